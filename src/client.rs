@@ -1,36 +1,200 @@
-use std::{collections::VecDeque, net::SocketAddr};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, Hasher},
+    io::ErrorKind,
+    marker::PhantomData,
+    net::{SocketAddr, ToSocketAddrs},
+    rc::Rc,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
 
-use jsonlrpc::ResponseObject;
-use mio::{event::Event, net::TcpStream, Interest, Poll, Token};
-use serde::Serialize;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
 
-use crate::connection::{Connection, ConnectionState};
+use jsonlrpc::{ErrorCode, ErrorObject, RequestId, RequestObject, RequestParams, ResponseObject};
+use mio::{event::Event, net::TcpStream, Interest, Poll, Token, Waker};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::{
+    connection::{
+        is_line_too_large_error, is_message_too_large_error, Connection, ConnectionState,
+        READ_CHUNK_SIZE,
+    },
+    error_codes::{CONNECTION_CLOSED, REQUEST_TIMEOUT},
+    http,
+    server::OkResponse,
+    socket::{self, SocketOptions, TcpKeepalive},
+    stream::Stream,
+    websocket::WebSocketSession,
+};
 
 /// RPC client.
+///
+/// `RESP` is the type of messages received from the server.
+/// It needs to be able to represent JSON-RPC error responses, as the client sometimes generates them locally
+/// (e.g., for timed out requests); such responses are discarded if they cannot be deserialized into `RESP`.
 #[derive(Debug)]
-pub struct RpcClient {
-    server_addr: SocketAddr,
+pub struct RpcClient<RESP = ResponseObject> {
+    server: ServerTarget,
+    resolved_addr: Option<SocketAddr>,
     token: Token,
     connection: Option<Connection>,
-    responses: VecDeque<ResponseObject>,
+    responses: VecDeque<ReceivedResponse<RESP>>,
+    pending_requests: HashSet<RequestId>,
+    request_deadlines: HashMap<RequestId, Instant>,
+    batch_responses: VecDeque<Vec<RESP>>,
+    pending_batches: Vec<Vec<RequestId>>,
+    next_request_id: i64,
+    server_requests: VecDeque<(u64, RequestObject)>,
+    next_seq: u64,
+    reconnect_backoff: Option<ReconnectBackoff>,
+    auto_reconnect: bool,
+    retain_unsent_requests: bool,
+    fail_pending_on_disconnect: bool,
+    max_queued_bytes: usize,
+    max_message_size: usize,
+    initial_buf_capacities: (usize, usize),
+    max_in_flight_requests: usize,
+    queue_excess_requests: bool,
+    queued_requests: VecDeque<RequestObject>,
+    connect_timeout: Option<Duration>,
+    bind_addr: Option<SocketAddr>,
+    socket_options: SocketOptions,
+    cancel_method: Option<String>,
+    transport: Transport,
+    websocket: Option<WebSocketSession>,
+    corked: bool,
+    connect_deadline: Option<Instant>,
+    keepalive_interval: Option<Duration>,
+    keepalive_method: String,
+    keepalive_max_missed: u32,
+    last_received_at: Instant,
+    last_ping_at: Option<Instant>,
+    missed_pings: u32,
+    ping_ids: HashSet<RequestId>,
+    unsent: Vec<u8>,
+    connected: bool,
+    ever_connected: bool,
+    failed_attempts: u32,
+    reconnect_at: Option<Instant>,
+    rng_state: u64,
+    events_enabled: bool,
+    events: VecDeque<ClientEvent>,
+    stats: ClientStats,
+    retries: HashMap<RequestId, PendingRetry>,
+    graceful_close: Option<GracefulClose>,
+    handle_sender: Option<mpsc::Sender<Box<RawValue>>>,
+    handle_receiver: Option<mpsc::Receiver<Box<RawValue>>>,
+    _response: PhantomData<RESP>,
 }
 
 impl RpcClient {
     /// Makes a new instance of [`RpcClient`].
     ///
-    /// If not already connected, this client will establish a connection to the specified server when [`RpcClient::send()`] is called.
+    /// If not already connected, this client will establish a connection to the specified server when [`RpcClient::send()`] (or [`RpcClient::connect()`]) is called.
     pub fn new(token: Token, server_addr: SocketAddr) -> Self {
-        Self {
-            server_addr,
-            token,
-            connection: None,
-            responses: VecDeque::new(),
-        }
+        Self::builder(token, server_addr).build()
+    }
+
+    /// Makes a new instance of [`RpcClient`] that connects to the server specified by a `hostname:port` string.
+    ///
+    /// See [`RpcClient::builder_host()`] for details.
+    pub fn new_host(token: Token, host: &str) -> Self {
+        Self::builder_host(token, host).build()
+    }
+
+    /// Makes a new instance of [`RpcClient`] that connects to a server listening on a Unix domain socket.
+    #[cfg(unix)]
+    pub fn new_unix<P: AsRef<Path>>(token: Token, path: P) -> Self {
+        Self::builder_unix(token, path).build()
+    }
+
+    /// Makes a new [`RpcClientBuilder`] instance.
+    ///
+    /// Note that the builder can also build clients with response types other than [`ResponseObject`].
+    pub fn builder(token: Token, server_addr: SocketAddr) -> RpcClientBuilder {
+        RpcClientBuilder::new(token, ServerTarget::Tcp(server_addr))
+    }
+
+    /// Makes a new [`RpcClientBuilder`] instance for a client that connects to the server specified by a `hostname:port` string.
+    ///
+    /// The hostname is resolved each time a connection attempt is made (including reconnects),
+    /// so changes in DNS records are followed.
+    /// If it resolves to multiple addresses, consecutive failed attempts rotate through them.
+    /// Note that the resolution is performed in a blocking manner.
+    pub fn builder_host(token: Token, host: &str) -> RpcClientBuilder {
+        RpcClientBuilder::new(token, ServerTarget::Host(host.to_owned()))
+    }
+
+    /// Makes a new [`RpcClientBuilder`] instance for a client that connects to a server listening on a Unix domain socket.
+    #[cfg(unix)]
+    pub fn builder_unix<P: AsRef<Path>>(token: Token, path: P) -> RpcClientBuilder {
+        RpcClientBuilder::new(token, ServerTarget::Unix(path.as_ref().to_path_buf()))
+    }
+
+    /// Takes the response with the specified ID from the receive queue and deserializes its result into `R`.
+    ///
+    /// The outer [`Result`] is a [`ClientError::Protocol`] error if the result cannot be deserialized into `R`,
+    /// and the inner one is the error response from the server.
+    pub fn try_take_result<R>(
+        &mut self,
+        id: &RequestId,
+    ) -> Option<Result<Result<R, ErrorObject>, ClientError>>
+    where
+        R: for<'de> Deserialize<'de>,
+    {
+        let response = self.try_take_response(id)?;
+        Some(match response.into_std_result() {
+            Ok(result) => serde_json::from_value(result)
+                .map(Ok)
+                .map_err(ClientError::Protocol),
+            Err(error) => Ok(Err(error)),
+        })
     }
+}
 
+impl<RESP> RpcClient<RESP>
+where
+    RESP: for<'de> Deserialize<'de>,
+{
     /// Returns the address of the RPC server to which this client sends requests.
+    ///
+    /// For clients made by [`RpcClient::builder_host()`], this is the address resolved by the latest connection attempt.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server is on a Unix domain socket, or if the hostname has not been resolved yet.
     pub fn server_addr(&self) -> SocketAddr {
-        self.server_addr
+        match &self.server {
+            ServerTarget::Tcp(addr) => *addr,
+            ServerTarget::Host(_) => self.resolved_addr.expect("hostname not resolved yet"),
+            #[cfg(unix)]
+            ServerTarget::Unix(_) => panic!("not a TCP client"),
+        }
+    }
+
+    /// Returns the `hostname:port` string of the RPC server to which this client sends requests.
+    ///
+    /// If the client was not made by [`RpcClient::builder_host()`], this method returns `None`.
+    pub fn server_host(&self) -> Option<&str> {
+        match &self.server {
+            ServerTarget::Host(host) => Some(host),
+            _ => None,
+        }
+    }
+
+    /// Returns the path of the Unix domain socket to which this client sends requests.
+    ///
+    /// If the server is not on a Unix domain socket, this method returns `None`.
+    #[cfg(unix)]
+    pub fn server_path(&self) -> Option<&Path> {
+        match &self.server {
+            ServerTarget::Unix(path) => Some(path),
+            _ => None,
+        }
     }
 
     /// Returns the `mio` token assigned to this client.
@@ -39,72 +203,1987 @@ impl RpcClient {
     }
 
     /// Sends a JSON-RPC request to the RPC server.
-    pub fn send<T: Serialize>(&mut self, poller: &mut Poll, request: &T) -> serde_json::Result<()> {
-        if self.connection.is_none() {
+    ///
+    /// If the number of queued bytes exceeds [`RpcClientBuilder::max_queued_bytes()`],
+    /// this method returns a [`std::io::ErrorKind::WouldBlock`] error without sending the request.
+    pub fn send<T: Serialize>(
+        &mut self,
+        poller: &mut Poll,
+        request: &T,
+    ) -> Result<(), ClientError> {
+        self.check_queue_capacity()?;
+        self.connect(poller)?;
+        self.write_message(poller, request)
+            .map_err(|e| self.handle_send_error(e))?;
+        self.stats.sent_messages += 1;
+        Ok(())
+    }
+
+    /// Sends a pre-serialized JSON-RPC message to the RPC server.
+    ///
+    /// `message` is validated to be a single JSON value, and then sent as is without being re-serialized
+    /// (this is useful for proxies or record/replay tools).
+    /// If `message` is not valid JSON, this method returns a [`ClientError::Serialize`] error,
+    /// and if it contains newlines, a [`std::io::ErrorKind::InvalidInput`] error.
+    ///
+    /// Note that the client does not track the IDs of requests sent via this method, as with [`RpcClient::send()`].
+    pub fn send_raw(&mut self, poller: &mut Poll, message: &[u8]) -> Result<(), ClientError> {
+        let message: &RawValue = serde_json::from_slice(message).map_err(ClientError::Serialize)?;
+        if message.get().contains('\n') {
+            return Err(ClientError::Io(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Message must not contain newlines",
+            )));
+        }
+        self.send(poller, &message)
+    }
+
+    /// Sends a JSON-RPC success response to a request initiated by the server.
+    pub fn reply_ok<T: Serialize>(
+        &mut self,
+        poller: &mut Poll,
+        id: RequestId,
+        result: T,
+    ) -> Result<(), ClientError> {
+        let response = OkResponse {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            id: &id,
+            result: &result,
+        };
+        self.send(poller, &response)
+    }
+
+    /// Sends a JSON-RPC error response to a request initiated by the server.
+    pub fn reply_err(
+        &mut self,
+        poller: &mut Poll,
+        id: RequestId,
+        code: ErrorCode,
+        message: &str,
+    ) -> Result<(), ClientError> {
+        let response = ResponseObject::Err {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            id: Some(id),
+            error: ErrorObject {
+                code,
+                message: message.to_owned(),
+                data: None,
+            },
+        };
+        self.send(poller, &response)
+    }
+
+    /// Sends a JSON-RPC request to the RPC server and returns the ID assigned to the request.
+    ///
+    /// IDs are assigned as monotonically increasing numbers,
+    /// so they should not be mixed with numeric IDs chosen by the caller for [`RpcClient::send()`].
+    /// See [`RpcClientBuilder::max_in_flight_requests()`] for the limit on outstanding requests.
+    ///
+    /// The response can be taken via [`RpcClient::try_take_response()`] (or [`RpcClient::try_recv()`]).
+    ///
+    /// If writing the request fails but it is retained for the next connection
+    /// (see [`RpcClientBuilder::retain_unsent_requests()`]), the ID is returned as if the request had been sent.
+    pub fn call(
+        &mut self,
+        poller: &mut Poll,
+        method: &str,
+        params: Option<RequestParams>,
+    ) -> Result<RequestId, ClientError> {
+        let id = RequestId::Number(self.next_request_id);
+        self.next_request_id += 1;
+        let request = RequestObject {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            id: Some(id.clone()),
+            method: method.to_owned(),
+            params,
+        };
+        if !self.queued_requests.is_empty() {
+            self.connect(poller)?;
+            self.send_queued_requests(poller)?;
+        }
+        if self.in_flight_requests() >= self.max_in_flight_requests
+            || !self.queued_requests.is_empty()
+        {
+            if !self.queue_excess_requests {
+                return Err(ClientError::Io(std::io::Error::new(
+                    ErrorKind::WouldBlock,
+                    "Too many in-flight requests",
+                )));
+            }
+            self.queued_requests.push_back(request);
+            return Ok(id);
+        }
+        if let Err(e) = self.send(poller, &request) {
+            if !unsent_request_ids(&self.unsent).contains(&id) {
+                return Err(e);
+            }
+        }
+        self.pending_requests.insert(id.clone());
+        Ok(id)
+    }
+
+    /// Sends a JSON-RPC request with params serialized from `params` in the same way as [`RpcClient::call()`].
+    ///
+    /// `params` must be serialized into a JSON array or object.
+    /// The typed result can be taken via [`RpcClient::try_take_result()`].
+    pub fn call_typed<P: Serialize>(
+        &mut self,
+        poller: &mut Poll,
+        method: &str,
+        params: &P,
+    ) -> Result<RequestId, ClientError> {
+        let params = serde_json::to_value(params)
+            .and_then(serde_json::from_value)
+            .map_err(ClientError::Serialize)?;
+        self.call(poller, method, Some(params))
+    }
+
+    /// Sends a JSON-RPC request in the same way as [`RpcClient::call()`], retrying it according to `policy`.
+    ///
+    /// The request is re-sent (with the same ID) if the connection is lost before the response arrives,
+    /// or if the server replies with one of the error codes specified by [`RetryPolicy::retry_on_error_code()`].
+    /// Only the final outcome is put into the receive queue.
+    /// Retries are performed by [`RpcClient::handle_timeout()`], so it needs to be called according to [`RpcClient::next_timeout()`].
+    ///
+    /// Note that this should only be used for idempotent requests, as the server may process a request more than once.
+    pub fn call_with_retry(
+        &mut self,
+        poller: &mut Poll,
+        method: &str,
+        params: Option<RequestParams>,
+        policy: RetryPolicy,
+    ) -> Result<RequestId, ClientError> {
+        let id = self.call(poller, method, params.clone())?;
+        let request = RequestObject {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            id: Some(id.clone()),
+            method: method.to_owned(),
+            params,
+        };
+        let retry = PendingRetry {
+            request,
+            policy,
+            attempts: 1,
+            retry_at: None,
+        };
+        self.retries.insert(id.clone(), retry);
+        Ok(id)
+    }
+
+    fn resend(&mut self, poller: &mut Poll, id: &RequestId) -> Result<(), ClientError> {
+        let request = self.retries[id].request.clone();
+        let result = self.send(poller, &request);
+        let waiting_for_reconnect = self.reconnect_at.filter(|_| self.connection.is_none());
+        let retry = self.retries.get_mut(id).expect("unreachable");
+        match result {
+            Ok(()) => {
+                retry.attempts += 1;
+                retry.retry_at = None;
+                self.pending_requests.insert(id.clone());
+                Ok(())
+            }
+            Err(ClientError::Connect(_)) if waiting_for_reconnect.is_some() => {
+                retry.retry_at = waiting_for_reconnect;
+                Ok(())
+            }
+            Err(e) => {
+                retry.attempts += 1;
+                retry.retry_at = Some(Instant::now() + retry.policy.delay(retry.attempts));
+                if retry.attempts >= retry.policy.max_attempts {
+                    self.retries.remove(id);
+                    self.push_error_response(id.clone(), CONNECTION_CLOSED, "Connection closed");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends a JSON-RPC notification to the RPC server.
+    ///
+    /// The server does not reply to notifications.
+    pub fn send_notification(
+        &mut self,
+        poller: &mut Poll,
+        method: &str,
+        params: Option<RequestParams>,
+    ) -> Result<(), ClientError> {
+        let notification = RequestObject {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            id: None,
+            method: method.to_owned(),
+            params,
+        };
+        self.send(poller, &notification)
+    }
+
+    /// Sends a JSON-RPC batch request to the RPC server.
+    ///
+    /// The corresponding batch response can be taken via [`RpcClient::try_recv_batch()`].
+    /// Note that the server does not reply if all of `requests` are notifications.
+    pub fn send_batch(
+        &mut self,
+        poller: &mut Poll,
+        requests: &[RequestObject],
+    ) -> Result<(), ClientError> {
+        if requests.is_empty() {
+            return Err(ClientError::Io(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Empty batch request",
+            )));
+        }
+
+        self.check_queue_capacity()?;
+        self.connect(poller)?;
+        self.write_message(poller, &requests)
+            .map_err(|e| self.handle_send_error(e))?;
+        self.stats.sent_messages += requests.len() as u64;
+
+        let ids = requests
+            .iter()
+            .filter_map(|r| r.id.clone())
+            .collect::<Vec<_>>();
+        if !ids.is_empty() {
+            self.pending_batches.push(ids);
+        }
+        Ok(())
+    }
+
+    /// Splits this client into a send-only half and a receive half.
+    ///
+    /// Both halves share this client, so they still need to be driven by the same poll loop
+    /// (i.e., events and timeouts are handled via [`RpcClientReceiver`]).
+    /// The client can be restored via [`RpcClientReceiver::reunite()`].
+    pub fn split(self) -> (RpcClientSender<RESP>, RpcClientReceiver<RESP>) {
+        let inner = Rc::new(RefCell::new(self));
+        let sender = RpcClientSender {
+            inner: inner.clone(),
+        };
+        (sender, RpcClientReceiver { inner })
+    }
+
+    /// Makes subsequent sends only queue messages until [`RpcClient::uncork()`] is called.
+    ///
+    /// This allows several messages sent in one poll iteration to be flushed with a single write.
+    /// The corked state is kept across reconnects.
+    pub fn cork(&mut self) {
+        self.corked = true;
+        if let Some(c) = &mut self.connection {
+            c.cork();
+        }
+    }
+
+    /// Flushes the messages queued since [`RpcClient::cork()`] was called at once.
+    pub fn uncork(&mut self, poller: &mut Poll) -> Result<(), ClientError> {
+        self.corked = false;
+        let Some(c) = &mut self.connection else {
+            return Ok(());
+        };
+        c.uncork(poller).map_err(|e| self.handle_send_error(e))
+    }
+
+    /// Attempts to write the queued messages immediately instead of waiting for the next writable event.
+    ///
+    /// Messages queued while the connection is being established are not affected.
+    pub fn flush(&mut self, poller: &mut Poll) -> Result<(), ClientError> {
+        let Some(c) = &mut self.connection else {
+            return Ok(());
+        };
+        c.flush(poller).map_err(|e| self.handle_send_error(e))
+    }
+
+    /// Makes a handle through which other threads can submit messages to this client.
+    ///
+    /// Submitting a message wakes up the poll loop via `waker`.
+    /// The messages are then sent when [`RpcClient::send_submitted()`] is called on the polling thread
+    /// (typically, upon receiving the `mio` event for `waker`).
+    ///
+    /// Note that the polling thread needs to keep `waker` alive,
+    /// as dropping the last reference to it deregisters it from the poller.
+    pub fn handle(&mut self, waker: Arc<Waker>) -> RpcClientHandle {
+        let sender = self.handle_sender.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            self.handle_receiver = Some(receiver);
+            sender
+        });
+        RpcClientHandle {
+            sender: sender.clone(),
+            waker,
+        }
+    }
+
+    /// Sends the messages submitted via [`RpcClientHandle`]s and returns the number of sent messages.
+    ///
+    /// If the send queue becomes full (see [`RpcClientBuilder::max_queued_bytes()`]),
+    /// this method returns an error and the remaining messages are kept for the next call.
+    pub fn send_submitted(&mut self, poller: &mut Poll) -> Result<usize, ClientError> {
+        let mut count = 0;
+        loop {
+            self.check_queue_capacity()?;
+            let Some(message) = self
+                .handle_receiver
+                .as_ref()
+                .and_then(|r| r.try_recv().ok())
+            else {
+                return Ok(count);
+            };
+            self.send(poller, &message)?;
+            count += 1;
+        }
+    }
+
+    /// Cancels the outstanding request with the specified ID.
+    ///
+    /// The request is no longer regarded as pending, so a late response to it is regarded as unsolicited
+    /// (see [`RpcClient::drain_unsolicited_responses()`]).
+    /// If [`RpcClientBuilder::cancel_method()`] is set, a notification with the params `{"id": id}`
+    /// is also sent to the server while connected (unless the request is still queued locally).
+    ///
+    /// Returns `Ok(false)` if there is no such outstanding request.
+    pub fn cancel(&mut self, poller: &mut Poll, id: &RequestId) -> Result<bool, ClientError> {
+        self.request_deadlines.remove(id);
+        if self.remove_queued_request(id) {
+            return Ok(true);
+        }
+        if !self.pending_requests.remove(id) {
+            return Ok(false);
+        }
+
+        if let (Some(method), Some(_)) = (&self.cancel_method, &self.connection) {
+            let mut params = serde_json::Map::new();
+            let id = serde_json::to_value(id).map_err(ClientError::Serialize)?;
+            params.insert("id".to_owned(), id);
+            let method = method.clone();
+            self.send_notification(poller, &method, Some(RequestParams::Object(params)))?;
+        }
+        Ok(true)
+    }
+
+    /// Starts establishing a connection to the RPC server if not already connected.
+    ///
+    /// As the connection is established in a non-blocking manner, failures to connect are reported by
+    /// subsequent [`RpcClient::handle_event()`] calls
+    /// (the connection is established once [`Connection::state()`] becomes [`ConnectionState::Connected`]).
+    pub fn connect(&mut self, poller: &mut Poll) -> Result<(), ClientError> {
+        if self.connection.is_some() {
+            return Ok(());
+        }
+        if self.reconnect_at.is_some_and(|t| Instant::now() < t) {
+            return Err(ClientError::Connect(std::io::Error::new(
+                ErrorKind::NotConnected,
+                "Waiting for the reconnect backoff to elapse",
+            )));
+        }
+        self.reconnect_at = None;
+
+        if !self.retain_unsent_requests && !self.fail_pending_on_disconnect {
             self.responses.clear();
+            self.pending_requests.clear();
+            self.request_deadlines.clear();
+            self.batch_responses.clear();
+            self.pending_batches.clear();
+        }
 
-            let mut stream = TcpStream::connect(self.server_addr).map_err(serde_json::Error::io)?;
+        if self.reconnect_backoff.is_some() && (self.ever_connected || self.failed_attempts > 0) {
+            let attempt = self.failed_attempts + 1;
+            self.push_event(ClientEvent::ReconnectAttempt { attempt });
+        }
+
+        let result = self.server.resolve(self.failed_attempts).and_then(|addr| {
+            let mut stream = self
+                .server
+                .connect(addr, self.bind_addr, &self.socket_options)?;
             poller
                 .registry()
-                .register(&mut stream, self.token, Interest::WRITABLE)
-                .map_err(serde_json::Error::io)?;
-            self.connection = Some(Connection::new(
-                self.token,
-                stream,
-                ConnectionState::Connecting,
-            ));
+                .register(&mut stream, self.token, Interest::WRITABLE)?;
+            Ok((stream, addr))
+        });
+        let (stream, addr) = result.map_err(|e| self.handle_error(e))?;
+        self.resolved_addr = addr;
+        self.connect_deadline = self.connect_timeout.map(|t| Instant::now() + t);
+        let mut connection = Connection::new(self.token, stream, ConnectionState::Connecting);
+        connection.set_max_line_size(self.max_message_size);
+        connection.set_max_outgoing_line_size(self.max_message_size);
+        let (read, write) = self.initial_buf_capacities;
+        connection.set_buf_capacities(read, write);
+        if self.corked {
+            connection.cork();
         }
-
-        self.connection
-            .as_mut()
-            .expect("unreachable")
-            .send(poller, request)
-            .map_err(|e| self.handle_error(e))
+        for line in std::mem::take(&mut self.unsent).split_inclusive(|&b| b == b'\n') {
+            connection.queue_bytes(line);
+        }
+        let seed = self.next_random();
+        if let Transport::WebSocket(path) = &self.transport {
+            let session = WebSocketSession::new(seed);
+            connection.queue_bytes(&session.handshake_request(path, &self.server.host()));
+            self.websocket = Some(session);
+        }
+        self.connection = Some(connection);
+        Ok(())
     }
 
     /// Returns the number of bytes enqueued by [`RpcClient::send()`] that have not yet been written to the TCP socket (e.g., as the send buffer is full).
+    ///
+    /// This includes the requests retained for the next connection if [`RpcClientBuilder::retain_unsent_requests()`] is enabled.
     pub fn queued_bytes_len(&self) -> usize {
-        self.connection.as_ref().map_or(0, |c| c.queued_bytes_len())
+        self.connection.as_ref().map_or(0, |c| c.queued_bytes_len()) + self.unsent.len()
+    }
+
+    /// Returns the number of messages (e.g., requests) that have not yet been completely written to the TCP socket.
+    ///
+    /// Like [`RpcClient::queued_bytes_len()`], this includes the requests retained for the next connection.
+    pub fn queued_messages_len(&self) -> usize {
+        let retained = self.unsent.iter().filter(|&&b| b == b'\n').count();
+        self.connection
+            .as_ref()
+            .map_or(0, |c| c.queued_messages_len())
+            + retained
     }
 
     /// Takes a JSON-RPC response from the receive queue.
-    pub fn try_recv(&mut self) -> Option<ResponseObject> {
-        self.responses.pop_front()
+    ///
+    /// Requests and notifications sent by the server are not returned by this method
+    /// (see [`RpcClient::try_recv_message()`]).
+    pub fn try_recv(&mut self) -> Option<RESP> {
+        self.responses.pop_front().map(|r| r.response)
+    }
+
+    /// Takes a message (a response, or a request or notification initiated by the server) from the receive queue.
+    ///
+    /// Messages are returned in the order they were received.
+    /// Requests from the server can be replied to via [`RpcClient::reply_ok()`] or [`RpcClient::reply_err()`].
+    pub fn try_recv_message(&mut self) -> Option<ClientMessage<RESP>> {
+        let request_first = match (self.server_requests.front(), self.responses.front()) {
+            (Some((seq, _)), Some(r)) => *seq < r.seq,
+            (request, _) => request.is_some(),
+        };
+        if !request_first {
+            return self.try_recv().map(ClientMessage::Response);
+        }
+        let (_, request) = self.server_requests.pop_front().expect("unreachable");
+        if request.id.is_some() {
+            Some(ClientMessage::Request(request))
+        } else {
+            Some(ClientMessage::Notification(request))
+        }
+    }
+
+    /// Takes the JSON-RPC response with the specified ID from the receive queue.
+    ///
+    /// Unlike [`RpcClient::try_recv()`], this method does not depend on the order in which the server replies.
+    pub fn try_take_response(&mut self, id: &RequestId) -> Option<RESP> {
+        let i = self
+            .responses
+            .iter()
+            .position(|r| r.id.as_ref() == Some(id))?;
+        self.responses.remove(i).map(|r| r.response)
+    }
+
+    /// Takes the unsolicited JSON-RPC responses from the receive queue.
+    ///
+    /// A response is considered unsolicited if its ID does not match any outstanding request sent via [`RpcClient::call()`]
+    /// (e.g., error responses without an ID, or duplicate responses).
+    /// Note that responses to requests sent via [`RpcClient::send()`] are also regarded as unsolicited,
+    /// as this client does not inspect the IDs of such requests.
+    pub fn drain_unsolicited_responses(&mut self) -> impl Iterator<Item = RESP> {
+        let (solicited, unsolicited): (VecDeque<_>, VecDeque<_>) =
+            self.responses.drain(..).partition(|r| r.solicited);
+        self.responses = solicited;
+        unsolicited.into_iter().map(|r| r.response)
+    }
+
+    /// Takes a JSON-RPC batch response from the receive queue.
+    ///
+    /// If the batch response corresponds to a batch request sent via [`RpcClient::send_batch()`],
+    /// its entries are ordered to match the ids of the requests
+    /// (responses without a matching id, such as invalid request errors, are placed at the end).
+    /// Batch responses to batch requests sent by other means (e.g., [`RpcClient::send()`] with an array)
+    /// are also put into this queue, with their entries in the received order.
+    pub fn try_recv_batch(&mut self) -> Option<Vec<RESP>> {
+        self.batch_responses.pop_front()
     }
 
     /// Handles an `mio` event.
-    pub fn handle_event(&mut self, poller: &mut Poll, event: &Event) -> serde_json::Result<()> {
+    pub fn handle_event(&mut self, poller: &mut Poll, event: &Event) -> Result<(), ClientError> {
         if event.token() != self.token {
             return Ok(());
         }
         let Some(c) = &mut self.connection else {
             return Ok(());
         };
-        c.handle_event(poller, event, |c, _poller| {
-            let response = c.stream_mut().read_value()?;
-            self.responses.push_back(response);
+        let result = c.handle_event(poller, event, |c, poller| {
+            let body;
+            let line = match &self.transport {
+                Transport::JsonLines => c.read_line()?,
+                Transport::Http(_) => {
+                    let max_body_size = self.max_message_size;
+                    body = c.read_prefix(|buf| http::parse_response(buf, max_body_size))?;
+                    if body.is_empty() {
+                        return Ok(());
+                    }
+                    &body[..]
+                }
+                Transport::WebSocket(_) => {
+                    let session = self.websocket.as_mut().expect("unreachable");
+                    let max_message_size = self.max_message_size;
+                    let Some(message) = session.read_message(c, poller, max_message_size)? else {
+                        return Ok(());
+                    };
+                    body = message;
+                    &body[..]
+                }
+            };
+            self.last_received_at = Instant::now();
+            self.missed_pings = 0;
+            if let Ok(request) = serde_json::from_slice::<RequestObject>(line) {
+                self.server_requests.push_back((self.next_seq, request));
+                self.next_seq += 1;
+                return Ok(());
+            }
+            match serde_json::from_slice::<RESP>(line) {
+                Ok(response) => {
+                    self.stats.received_responses += 1;
+                    let (id, error_code) = serde_json::from_slice::<IdProbe>(line)
+                        .map(|p| (p.id, p.error.map(|e| e.code)))
+                        .unwrap_or_default();
+                    if id.as_ref().is_some_and(|id| self.ping_ids.remove(id)) {
+                        return Ok(());
+                    }
+                    if let Some((id, retry)) = id
+                        .as_ref()
+                        .and_then(|id| Some((id, self.retries.get_mut(id)?)))
+                    {
+                        let retryable = error_code
+                            .is_some_and(|code| retry.policy.retry_on_error_codes.contains(&code));
+                        if retryable && retry.attempts < retry.policy.max_attempts {
+                            retry.retry_at =
+                                Some(Instant::now() + retry.policy.delay(retry.attempts));
+                            self.pending_requests.remove(id);
+                            return Ok(());
+                        }
+                        self.retries.remove(id);
+                    }
+                    let solicited = id.as_ref().is_some_and(|id| {
+                        self.request_deadlines.remove(id);
+                        self.pending_requests.remove(id)
+                    });
+                    self.responses.push_back(ReceivedResponse {
+                        response,
+                        id,
+                        solicited,
+                        seq: self.next_seq,
+                    });
+                    self.next_seq += 1;
+                }
+                Err(e) => {
+                    let Ok(responses) = serde_json::from_slice::<Vec<RESP>>(line) else {
+                        return Err(e);
+                    };
+                    self.stats.received_responses += responses.len() as u64;
+                    let ids = serde_json::from_slice::<Vec<IdProbe>>(line)
+                        .map(|probes| probes.into_iter().map(|p| p.id).collect())
+                        .unwrap_or_else(|_| vec![None; responses.len()]);
+                    let responses = ids.into_iter().zip(responses).collect();
+                    let batch = correlate_batch(&mut self.pending_batches, responses);
+                    self.batch_responses.push_back(batch);
+                }
+            }
             Ok(())
-        })
-        .map_err(|e| self.handle_error(e))
+        });
+        let established = c.is_established();
+        result.map_err(|e| {
+            if !e.is_io() {
+                // The connection has been closed due to the invalid message.
+                let error = std::io::Error::new(ErrorKind::InvalidData, e.to_string());
+                let _ = self.handle_error(error);
+                return ClientError::Protocol(e);
+            }
+            // Lines exceeding `max_message_size`.
+            let too_large = is_line_too_large_error(&e);
+            match self.handle_error(e.into()) {
+                ClientError::Io(e) if too_large => ClientError::Protocol(serde_json::Error::io(e)),
+                e => e,
+            }
+        })?;
+
+        if !self.connected && established {
+            self.connected = true;
+            self.connect_deadline = None;
+            self.last_received_at = Instant::now();
+            self.last_ping_at = None;
+            self.missed_pings = 0;
+            self.ping_ids.clear();
+            self.push_event(ClientEvent::Connected);
+            if self.reconnect_backoff.is_some() && (self.ever_connected || self.failed_attempts > 0)
+            {
+                let attempts = self.failed_attempts + 1;
+                self.push_event(ClientEvent::Reconnected { attempts });
+            }
+            if self.ever_connected {
+                self.stats.reconnects += 1;
+            }
+            self.ever_connected = true;
+            self.failed_attempts = 0;
+        }
+        self.send_queued_requests(poller)?;
+        self.finish_graceful_close(poller);
+        Ok(())
+    }
+
+    /// Sets a timeout for the outstanding request with the specified ID.
+    ///
+    /// If the response does not arrive within `timeout`, [`RpcClient::handle_timeout()`] puts
+    /// a synthetic error response with the code [`REQUEST_TIMEOUT`] into the receive queue instead.
+    /// A response arriving after the timeout is regarded as unsolicited
+    /// (see [`RpcClient::drain_unsolicited_responses()`]).
+    pub fn set_request_timeout(&mut self, id: &RequestId, timeout: Duration) {
+        if !self
+            .queued_requests
+            .iter()
+            .any(|r| r.id.as_ref() == Some(id))
+        {
+            self.pending_requests.insert(id.clone());
+        }
+        self.request_deadlines
+            .insert(id.clone(), Instant::now() + timeout);
+    }
+
+    /// Returns the duration until the next timeout to be handled by [`RpcClient::handle_timeout()`].
+    ///
+    /// Timeouts are set by [`RpcClient::set_request_timeout()`], [`RpcClientBuilder::auto_reconnect()`],
+    /// [`RpcClientBuilder::connect_timeout()`], [`RpcClientBuilder::keepalive()`], [`RpcClient::call_with_retry()`],
+    /// and [`RpcClient::flush_and_close()`].
+    pub fn next_timeout(&self) -> Option<Duration> {
+        let reconnect_at = self
+            .reconnect_at
+            .filter(|_| self.auto_reconnect && self.connection.is_none());
+        let request_deadline = self.request_deadlines.values().min().copied();
+        let connect_deadline = self.connect_deadline.filter(|_| !self.connected);
+        let at = reconnect_at
+            .into_iter()
+            .chain(request_deadline)
+            .chain(connect_deadline)
+            .chain(self.keepalive_deadline())
+            .chain(self.retries.values().filter_map(|r| r.retry_at))
+            .chain(self.graceful_close.as_ref().map(|g| g.deadline))
+            .min()?;
+        Some(at.saturating_duration_since(Instant::now()))
     }
 
-    /// Returns a reference to the internal TCP connection.
+    /// Handles timeouts.
+    ///
+    /// This method expires requests that have timed out, re-sends requests to be retried (see [`RpcClient::call_with_retry()`]),
+    /// and, if [`RpcClientBuilder::auto_reconnect()`] is enabled, reconnects to the server.
+    /// If a connection attempt exceeds [`RpcClientBuilder::connect_timeout()`] (or [`RpcClient::flush_and_close()`] does not
+    /// complete within its timeout), the connection is closed and a [`std::io::ErrorKind::TimedOut`] error is returned.
+    /// It should be called when the duration returned by [`RpcClient::next_timeout()`] has elapsed.
+    pub fn handle_timeout(&mut self, poller: &mut Poll) -> Result<(), ClientError> {
+        let now = Instant::now();
+        if self
+            .graceful_close
+            .as_ref()
+            .is_some_and(|g| g.deadline <= now)
+        {
+            self.close(poller);
+            return Err(ClientError::Io(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "Graceful close timed out",
+            )));
+        }
+
+        let connect_timed_out = self.connect_deadline.is_some_and(|t| t <= now);
+        if connect_timed_out && self.state() == ClientState::Connecting {
+            if let Some(c) = &mut self.connection {
+                c.close(poller);
+            }
+            let e = std::io::Error::new(ErrorKind::TimedOut, "Connection timed out");
+            return Err(self.handle_error(e));
+        }
+
+        let mut expired = self
+            .request_deadlines
+            .iter()
+            .filter(|(_, &deadline)| deadline <= now)
+            .map(|(id, &deadline)| (deadline, id.clone()))
+            .collect::<Vec<_>>();
+        expired.sort();
+        for (_, id) in expired {
+            self.request_deadlines.remove(&id);
+            self.pending_requests.remove(&id);
+            self.remove_queued_request(&id);
+            self.retries.remove(&id);
+            self.push_error_response(id, REQUEST_TIMEOUT, "Request timed out");
+        }
+        self.send_queued_requests(poller)?;
+
+        let mut due_retries = self
+            .retries
+            .iter()
+            .filter(|(_, r)| r.retry_at.is_some_and(|t| t <= now))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        due_retries.sort();
+        for id in due_retries {
+            self.resend(poller, &id)?;
+        }
+
+        if self.keepalive_deadline().is_some_and(|t| t <= now) {
+            if self.missed_pings >= self.keepalive_max_missed {
+                if let Some(c) = &mut self.connection {
+                    c.close(poller);
+                }
+                let e = std::io::Error::new(ErrorKind::TimedOut, "Keepalive timed out");
+                return Err(self.handle_error(e));
+            }
+            // Pings bypass `max_in_flight_requests`, as they are not regarded as pending requests.
+            let id = RequestId::Number(self.next_request_id);
+            self.next_request_id += 1;
+            let ping = RequestObject {
+                jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+                id: Some(id.clone()),
+                method: self.keepalive_method.clone(),
+                params: None,
+            };
+            self.send(poller, &ping)?;
+            self.ping_ids.insert(id);
+            self.missed_pings += 1;
+            self.last_ping_at = Some(now);
+        }
+
+        let reconnect_due = self.reconnect_at.is_some_and(|t| t <= now);
+        if self.auto_reconnect && self.connection.is_none() && reconnect_due {
+            self.connect(poller)?;
+        }
+        Ok(())
+    }
+
+    /// Takes a [`ClientEvent`] from the event queue.
+    ///
+    /// Events are recorded only if [`RpcClientBuilder::events()`] is enabled.
+    pub fn try_recv_event(&mut self) -> Option<ClientEvent> {
+        self.events.pop_front()
+    }
+
+    /// Returns the state of the connection to the server.
+    pub fn state(&self) -> ClientState {
+        match self.connection.as_ref().map(|c| c.state()) {
+            None | Some(ConnectionState::Closed) => ClientState::Disconnected,
+            Some(ConnectionState::Connecting) => ClientState::Connecting,
+            Some(ConnectionState::Connected) => ClientState::Connected,
+            Some(ConnectionState::ShuttingDown) => ClientState::ShuttingDown,
+        }
+    }
+
+    /// Returns a reference to the internal connection.
     pub fn connection(&self) -> Option<&Connection> {
         self.connection.as_ref()
     }
 
-    /// Closes the internal TCP connection if it has been established.
+    /// Closes the internal connection after flushing the data queued on it.
+    ///
+    /// The connection is closed once its write buffer becomes empty
+    /// (and, if `wait_for_responses` is `true`, all outstanding requests have been replied to).
+    /// Until then, [`RpcClient::handle_event()`] needs to be called as usual.
+    /// If this does not complete within `timeout`, [`RpcClient::handle_timeout()`] closes the connection
+    /// and returns a [`std::io::ErrorKind::TimedOut`] error.
+    ///
+    /// The client does not reconnect automatically after the connection is closed.
+    pub fn flush_and_close(
+        &mut self,
+        poller: &mut Poll,
+        timeout: Duration,
+        wait_for_responses: bool,
+    ) {
+        if self.connection.is_none() {
+            self.close(poller);
+            return;
+        }
+        self.graceful_close = Some(GracefulClose {
+            deadline: Instant::now() + timeout,
+            wait_for_responses,
+        });
+        if let Some(c) = &mut self.connection {
+            c.begin_shutdown();
+        }
+        self.finish_graceful_close(poller);
+    }
+
+    /// Closes the internal connection if it has been established.
     pub fn close(&mut self, poller: &mut Poll) {
+        self.graceful_close = None;
+        self.reconnect_at = None;
+        self.connect_deadline = None;
+        self.connected = false;
+        self.unsent.clear();
+        self.retries.clear();
+        self.queued_requests.clear();
         let Some(mut c) = self.connection.take() else {
             return;
         };
         c.close(poller);
+        self.stats.record_closed(&c);
+    }
+
+    /// Returns the statistics of this client.
+    pub fn stats(&self) -> ClientStats {
+        let mut stats = self.stats;
+        stats.in_flight_requests = self.in_flight_requests();
+        if let Some(c) = &self.connection {
+            stats.received_bytes += c.received_bytes();
+            stats.sent_bytes += c.sent_bytes();
+        }
+        stats
+    }
+
+    fn write_message<T: Serialize>(
+        &mut self,
+        poller: &mut Poll,
+        message: &T,
+    ) -> serde_json::Result<()> {
+        let c = self.connection.as_mut().expect("unreachable");
+        match &self.transport {
+            Transport::JsonLines => c.send(poller, message),
+            Transport::Http(path) => {
+                let body = serde_json::to_vec(message)?;
+                let request = http::build_request(path, &self.server.host(), &body);
+                c.send_bytes(poller, &request)
+            }
+            Transport::WebSocket(_) => {
+                let body = serde_json::to_vec(message)?;
+                let session = self.websocket.as_mut().expect("unreachable");
+                session.send_text(c, poller, &body)
+            }
+        }
     }
 
-    fn handle_error(&mut self, error: serde_json::Error) -> serde_json::Error {
+    fn handle_send_error(&mut self, error: serde_json::Error) -> ClientError {
+        if is_message_too_large_error(&error) {
+            return ClientError::Io(error.into());
+        }
         if error.is_io() {
-            self.connection = None;
+            self.handle_error(error.into())
+        } else {
+            ClientError::Serialize(error)
+        }
+    }
+
+    fn handle_error(&mut self, error: std::io::Error) -> ClientError {
+        if let Some(mut c) = self.connection.take() {
+            self.stats.record_closed(&c);
+            if self.retain_unsent_requests && self.transport == Transport::JsonLines {
+                self.unsent.extend(c.take_unsent_bytes());
+            }
+            self.schedule_retries_on_disconnect();
+            if self.fail_pending_on_disconnect {
+                self.fail_pending_requests();
+            }
+        }
+        self.connect_deadline = None;
+        let established = std::mem::take(&mut self.connected);
+        if established {
+            let error_kind = error.kind();
+            self.push_event(ClientEvent::Disconnected { error_kind });
+        }
+        if self.graceful_close.take().is_some() {
+            self.retries.clear();
+            return ClientError::from_io(error, established);
+        }
+        let Some(backoff) = self.reconnect_backoff else {
+            return ClientError::from_io(error, established);
+        };
+        let now = Instant::now();
+        if established {
+            self.reconnect_at = self.auto_reconnect.then_some(now);
+        } else {
+            self.failed_attempts = self.failed_attempts.saturating_add(1);
+            let retry_after = self.backoff_delay(backoff);
+            self.reconnect_at = Some(now + retry_after);
+            self.push_event(ClientEvent::ReconnectFailed {
+                attempt: self.failed_attempts,
+                retry_after,
+            });
+        }
+        ClientError::from_io(error, established)
+    }
+
+    fn finish_graceful_close(&mut self, poller: &mut Poll) {
+        let Some(graceful_close) = &self.graceful_close else {
+            return;
+        };
+        let flushed = self
+            .connection
+            .as_ref()
+            .is_none_or(|c| c.queued_bytes_len() == 0);
+        let replied = self.pending_requests.is_empty()
+            && self.pending_batches.is_empty()
+            && self.queued_requests.is_empty()
+            && self.retries.is_empty();
+        if flushed && (replied || !graceful_close.wait_for_responses) {
+            self.close(poller);
+        }
+    }
+
+    fn backoff_delay(&mut self, backoff: ReconnectBackoff) -> Duration {
+        let exp = self.failed_attempts.saturating_sub(1).min(31);
+        let delay = backoff.initial.saturating_mul(1 << exp).min(backoff.max);
+        if !backoff.jitter {
+            return delay;
+        }
+
+        // "Equal jitter": half of the delay is fixed, and the other half is randomized.
+        let half = delay / 2;
+        let nanos = u64::try_from(half.as_nanos()).unwrap_or(u64::MAX);
+        half + Duration::from_nanos(self.next_random() % nanos.saturating_add(1))
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 7;
+        self.rng_state ^= self.rng_state << 17;
+        self.rng_state
+    }
+
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        let interval = self.keepalive_interval.filter(|_| self.connected)?;
+        let last = self
+            .last_ping_at
+            .map_or(self.last_received_at, |t| t.max(self.last_received_at));
+        Some(last + interval)
+    }
+
+    fn in_flight_requests(&self) -> usize {
+        self.pending_requests.len() + self.pending_batches.iter().map(|b| b.len()).sum::<usize>()
+    }
+
+    fn send_queued_requests(&mut self, poller: &mut Poll) -> Result<(), ClientError> {
+        while self.connection.is_some() && self.in_flight_requests() < self.max_in_flight_requests {
+            let Some(request) = self.queued_requests.pop_front() else {
+                break;
+            };
+            if let Err(e) = self.send(poller, &request) {
+                if self.connection.is_some() {
+                    self.queued_requests.push_front(request);
+                }
+                return Err(e);
+            }
+            let id = request.id.expect("unreachable");
+            self.pending_requests.insert(id);
+        }
+        Ok(())
+    }
+
+    fn remove_queued_request(&mut self, id: &RequestId) -> bool {
+        let Some(i) = self
+            .queued_requests
+            .iter()
+            .position(|r| r.id.as_ref() == Some(id))
+        else {
+            return false;
+        };
+        self.queued_requests.remove(i);
+        true
+    }
+
+    fn check_queue_capacity(&self) -> Result<(), ClientError> {
+        if self.queued_bytes_len() > self.max_queued_bytes {
+            return Err(ClientError::Io(std::io::Error::new(
+                ErrorKind::WouldBlock,
+                "Send queue is full",
+            )));
+        }
+        Ok(())
+    }
+
+    fn push_error_response(&mut self, id: RequestId, code: ErrorCode, message: &str) {
+        let Some(response) = error_response(&id, code, message) else {
+            return;
+        };
+        self.responses.push_back(ReceivedResponse {
+            response,
+            id: Some(id),
+            solicited: true,
+            seq: self.next_seq,
+        });
+        self.next_seq += 1;
+    }
+
+    fn schedule_retries_on_disconnect(&mut self) {
+        let retained = unsent_request_ids(&self.unsent);
+        let now = Instant::now();
+        self.retries.retain(|id, retry| {
+            let queued = self
+                .queued_requests
+                .iter()
+                .any(|r| r.id.as_ref() == Some(id));
+            if retry.retry_at.is_some() || retained.contains(id) || queued {
+                return true;
+            }
+            if !retry.policy.retry_on_disconnect || retry.attempts >= retry.policy.max_attempts {
+                return false;
+            }
+            retry.retry_at = Some(now + retry.policy.delay(retry.attempts));
+            self.pending_requests.remove(id);
+            true
+        });
+    }
+
+    fn fail_pending_requests(&mut self) {
+        let retained = unsent_request_ids(&self.unsent);
+
+        let mut ids = self
+            .pending_requests
+            .iter()
+            .filter(|id| !retained.contains(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        ids.sort();
+        for id in ids {
+            self.pending_requests.remove(&id);
+            self.request_deadlines.remove(&id);
+            self.push_error_response(id, CONNECTION_CLOSED, "Connection closed");
+        }
+
+        let (retained_batches, failed_batches) = std::mem::take(&mut self.pending_batches)
+            .into_iter()
+            .partition::<Vec<_>, _>(|ids| ids.iter().any(|id| retained.contains(id)));
+        self.pending_batches = retained_batches;
+        for ids in failed_batches {
+            let batch = ids
+                .iter()
+                .filter_map(|id| error_response(id, CONNECTION_CLOSED, "Connection closed"))
+                .collect();
+            self.batch_responses.push_back(batch);
+        }
+    }
+
+    fn push_event(&mut self, event: ClientEvent) {
+        if self.events_enabled {
+            self.events.push_back(event);
+        }
+    }
+}
+
+/// Builder for [`RpcClient`].
+#[derive(Debug, Clone)]
+pub struct RpcClientBuilder {
+    token: Token,
+    server: ServerTarget,
+    reconnect_backoff: Option<ReconnectBackoff>,
+    auto_reconnect: bool,
+    retain_unsent_requests: bool,
+    fail_pending_on_disconnect: bool,
+    max_queued_bytes: usize,
+    max_message_size: usize,
+    initial_buf_capacities: (usize, usize),
+    max_in_flight_requests: usize,
+    queue_excess_requests: bool,
+    connect_timeout: Option<Duration>,
+    bind_addr: Option<SocketAddr>,
+    socket_options: SocketOptions,
+    cancel_method: Option<String>,
+    transport: Transport,
+    keepalive_interval: Option<Duration>,
+    keepalive_method: String,
+    keepalive_max_missed: u32,
+    events: bool,
+}
+
+impl RpcClientBuilder {
+    fn new(token: Token, server: ServerTarget) -> Self {
+        Self {
+            token,
+            server,
+            reconnect_backoff: None,
+            auto_reconnect: false,
+            retain_unsent_requests: false,
+            fail_pending_on_disconnect: false,
+            max_queued_bytes: usize::MAX,
+            max_message_size: usize::MAX,
+            initial_buf_capacities: (READ_CHUNK_SIZE, 0),
+            max_in_flight_requests: usize::MAX,
+            queue_excess_requests: false,
+            connect_timeout: None,
+            bind_addr: None,
+            socket_options: SocketOptions::default(),
+            cancel_method: None,
+            transport: Transport::JsonLines,
+            keepalive_interval: None,
+            keepalive_method: "rpc.ping".to_owned(),
+            keepalive_max_missed: 2,
+            events: false,
+        }
+    }
+
+    /// Sets the backoff applied between failed connection attempts (the default is no backoff).
+    ///
+    /// After a connection attempt fails, the delay before the next attempt starts at `initial`
+    /// and doubles on each consecutive failure up to `max`.
+    /// [`RpcClient::send()`] fails with [`std::io::ErrorKind::NotConnected`] until the delay has elapsed.
+    pub fn reconnect_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        let jitter = self.reconnect_backoff.is_none_or(|b| b.jitter);
+        self.reconnect_backoff = Some(ReconnectBackoff {
+            initial,
+            max,
+            jitter,
+        });
+        self
+    }
+
+    /// Sets whether the reconnect backoff is randomized (the default is `true`).
+    ///
+    /// If enabled, each delay is chosen randomly between half and all of the computed backoff,
+    /// so that many clients don't reconnect to a recovering server at the same moment.
+    pub fn reconnect_jitter(mut self, enabled: bool) -> Self {
+        if let Some(backoff) = &mut self.reconnect_backoff {
+            backoff.jitter = enabled;
+        }
+        self
+    }
+
+    /// Sets whether the client reconnects proactively after losing its connection (the default is `false`).
+    ///
+    /// If enabled, the client reconnects in [`RpcClient::handle_timeout()`] according to [`RpcClient::next_timeout()`]
+    /// instead of waiting for the next [`RpcClient::send()`].
+    /// This takes effect only if [`RpcClientBuilder::reconnect_backoff()`] is set.
+    pub fn auto_reconnect(mut self, enabled: bool) -> Self {
+        self.auto_reconnect = enabled;
+        self
+    }
+
+    /// Sets whether requests that have not been sent when the connection is lost are re-sent after reconnecting (the default is `false`).
+    ///
+    /// Only requests that have not been written to the socket at all are retained
+    /// (a partially written request is re-sent in its entirety),
+    /// so requests that were written but not delivered to the server are still lost.
+    /// Note that [`RpcClient::send()`] may return an error for a request that has been retained.
+    ///
+    /// If enabled, the receive queue and the outstanding request IDs are also kept across reconnects.
+    pub fn retain_unsent_requests(mut self, enabled: bool) -> Self {
+        self.retain_unsent_requests = enabled;
+        self
+    }
+
+    /// Sets whether outstanding requests fail with synthetic error responses when the connection is lost (the default is `false`).
+    ///
+    /// If enabled, an error response with the code [`CONNECTION_CLOSED`] is put into the receive queue
+    /// for each request sent via [`RpcClient::call()`] or [`RpcClient::send_batch()`] that has not been answered yet
+    /// (requests retained by [`RpcClientBuilder::retain_unsent_requests()`] are excluded).
+    /// The receive queue is also kept across reconnects.
+    pub fn fail_pending_on_disconnect(mut self, enabled: bool) -> Self {
+        self.fail_pending_on_disconnect = enabled;
+        self
+    }
+
+    /// Sets the maximum number of queued bytes beyond which sending fails (the default is `usize::MAX`).
+    ///
+    /// While [`RpcClient::queued_bytes_len()`] exceeds this limit (e.g., against a slow or stuck server),
+    /// [`RpcClient::send()`] and the like return a [`std::io::ErrorKind::WouldBlock`] error.
+    /// Such errors don't affect the connection.
+    pub fn max_queued_bytes(mut self, max: usize) -> Self {
+        self.max_queued_bytes = max;
+        self
+    }
+
+    /// Sets the maximum number of requests sent via [`RpcClient::call()`] (and its variants) that may await responses at once
+    /// (the default is `usize::MAX`).
+    ///
+    /// Requests in batches sent via [`RpcClient::send_batch()`] also count toward this limit.
+    /// When the limit is reached, further calls return a [`std::io::ErrorKind::WouldBlock`] error
+    /// unless [`RpcClientBuilder::queue_excess_requests()`] is enabled.
+    pub fn max_in_flight_requests(mut self, max: usize) -> Self {
+        self.max_in_flight_requests = max;
+        self
+    }
+
+    /// Sets whether requests exceeding [`RpcClientBuilder::max_in_flight_requests()`] are queued locally (the default is `false`).
+    ///
+    /// If enabled, [`RpcClient::call()`] assigns an ID to such a request and keeps it until a slot frees up
+    /// (i.e., a response arrives, or the request times out or is cancelled).
+    /// Queued requests are sent, in order, by [`RpcClient::handle_event()`] and [`RpcClient::handle_timeout()`].
+    pub fn queue_excess_requests(mut self, enabled: bool) -> Self {
+        self.queue_excess_requests = enabled;
+        self
+    }
+
+    /// Sets the maximum size in bytes of a single message sent or received by the client (the default is unlimited).
+    ///
+    /// Sending a larger message fails with a [`std::io::ErrorKind::InvalidInput`] error without queueing it
+    /// (the connection is kept open), and receiving a larger line closes the connection with a [`ClientError::Protocol`] error.
+    /// With [`RpcClientBuilder::http()`] or [`RpcClientBuilder::websocket()`], the limit also applies to
+    /// response bodies and WebSocket messages (including fragmented ones), and a larger one closes
+    /// the connection with a [`std::io::ErrorKind::InvalidData`] error.
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Sets the initial capacities in bytes of the read and write buffers of the connection
+    /// (the defaults are 4096 bytes and 0 bytes respectively).
+    ///
+    /// See [`Connection::set_buf_capacities()`] for details.
+    pub fn initial_buf_capacities(mut self, read: usize, write: usize) -> Self {
+        self.initial_buf_capacities = (read, write);
+        self
+    }
+
+    /// Sets the maximum time to wait for a connection to be established (the default is `None`, i.e., no limit).
+    ///
+    /// This requires [`RpcClient::handle_timeout()`] to be called according to [`RpcClient::next_timeout()`].
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets the local address that the client socket binds to before connecting (the default is `None`, i.e., chosen by the OS).
+    ///
+    /// This is useful on multi-homed hosts (e.g., to satisfy source-IP-based firewall rules).
+    /// It is ignored for clients connecting to Unix domain sockets.
+    pub fn bind_addr(mut self, addr: Option<SocketAddr>) -> Self {
+        self.bind_addr = addr;
+        self
+    }
+
+    /// Sets whether `TCP_NODELAY` is enabled on the client socket (the default is `true`).
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.socket_options.nodelay = nodelay;
+        self
+    }
+
+    /// Enables TCP keepalive on the client socket (the default is disabled).
+    ///
+    /// See [`RpcServerBuilder::tcp_keepalive()`](crate::RpcServerBuilder::tcp_keepalive) for the meaning of the parameters.
+    /// Note that [`RpcClientBuilder::keepalive()`] is an application-level alternative that also detects unresponsive servers.
+    pub fn tcp_keepalive(mut self, idle: Duration, interval: Duration, count: u32) -> Self {
+        self.socket_options.keepalive = Some(TcpKeepalive {
+            idle,
+            interval,
+            count,
+        });
+        self
+    }
+
+    /// Sets the size of the kernel send buffer (`SO_SNDBUF`) of the client socket (the default is chosen by the OS).
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.socket_options.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the kernel receive buffer (`SO_RCVBUF`) of the client socket (the default is chosen by the OS).
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.socket_options.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the method name of the notification sent by [`RpcClient::cancel()`] (the default is `None`, i.e., no notification is sent).
+    ///
+    /// For example, LSP-style servers expect `"$/cancelRequest"`.
+    pub fn cancel_method(mut self, method: Option<&str>) -> Self {
+        self.cancel_method = method.map(|m| m.to_owned());
+        self
+    }
+
+    /// Sets the interval of keepalive pings (the default is `None`, i.e., keepalive is disabled).
+    ///
+    /// If enabled, the client sends a request with [`RpcClientBuilder::keepalive_method()`]
+    /// when nothing has been received from the server for `interval`.
+    /// Any response to the request (including an error response) counts as a pong and is not put into the receive queue.
+    /// If [`RpcClientBuilder::keepalive_max_missed()`] consecutive pings remain unanswered,
+    /// the connection is closed and [`RpcClient::handle_timeout()`] returns a [`std::io::ErrorKind::TimedOut`] error.
+    ///
+    /// This requires [`RpcClient::handle_timeout()`] to be called according to [`RpcClient::next_timeout()`].
+    pub fn keepalive(mut self, interval: Option<Duration>) -> Self {
+        self.keepalive_interval = interval;
+        self
+    }
+
+    /// Sets the method name of keepalive pings (the default is `"rpc.ping"`).
+    pub fn keepalive_method(mut self, method: &str) -> Self {
+        self.keepalive_method = method.to_owned();
+        self
+    }
+
+    /// Sets the number of unanswered keepalive pings after which the connection is regarded as dead (the default is `2`).
+    pub fn keepalive_max_missed(mut self, max: u32) -> Self {
+        self.keepalive_max_missed = max;
+        self
+    }
+
+    /// Makes the client send each message as an HTTP/1.1 POST request to `path` (the default is `None`, i.e., JSON Lines).
+    ///
+    /// In this mode, messages are sent over a persistent (keep-alive) connection,
+    /// and responses are parsed from the bodies of the HTTP responses, which must arrive in the order of the requests.
+    /// Responses with empty bodies (e.g., `204 No Content` for notifications) are ignored.
+    ///
+    /// Note that [`RpcClientBuilder::retain_unsent_requests()`] has no effect in this mode.
+    pub fn http(mut self, path: Option<&str>) -> Self {
+        self.transport = path.map_or(Transport::JsonLines, |p| Transport::Http(p.to_owned()));
+        self
+    }
+
+    /// Makes the client exchange messages as WebSocket text frames with the endpoint at `path`
+    /// (the default is `None`, i.e., JSON Lines).
+    ///
+    /// The opening handshake is performed on each connection, and messages sent before it completes are held until then.
+    /// Pings from the server are answered automatically, and a close frame is reported as
+    /// a [`std::io::ErrorKind::ConnectionAborted`] error.
+    ///
+    /// Note that [`RpcClientBuilder::retain_unsent_requests()`] has no effect in this mode,
+    /// and this overrides [`RpcClientBuilder::http()`] (and vice versa).
+    pub fn websocket(mut self, path: Option<&str>) -> Self {
+        self.transport = path.map_or(Transport::JsonLines, |p| Transport::WebSocket(p.to_owned()));
+        self
+    }
+
+    /// Sets whether the client records [`ClientEvent`]s (the default is `false`).
+    ///
+    /// If enabled, the recorded events need to be taken via [`RpcClient::try_recv_event()`].
+    pub fn events(mut self, enabled: bool) -> Self {
+        self.events = enabled;
+        self
+    }
+
+    /// Builds an [`RpcClient`] with the configured options.
+    pub fn build<RESP>(&self) -> RpcClient<RESP>
+    where
+        RESP: for<'de> Deserialize<'de>,
+    {
+        RpcClient {
+            server: self.server.clone(),
+            resolved_addr: None,
+            token: self.token,
+            connection: None,
+            responses: VecDeque::new(),
+            pending_requests: HashSet::new(),
+            request_deadlines: HashMap::new(),
+            batch_responses: VecDeque::new(),
+            pending_batches: Vec::new(),
+            next_request_id: 0,
+            server_requests: VecDeque::new(),
+            next_seq: 0,
+            reconnect_backoff: self.reconnect_backoff,
+            auto_reconnect: self.auto_reconnect,
+            retain_unsent_requests: self.retain_unsent_requests,
+            fail_pending_on_disconnect: self.fail_pending_on_disconnect,
+            max_queued_bytes: self.max_queued_bytes,
+            max_message_size: self.max_message_size,
+            initial_buf_capacities: self.initial_buf_capacities,
+            max_in_flight_requests: self.max_in_flight_requests,
+            queue_excess_requests: self.queue_excess_requests,
+            queued_requests: VecDeque::new(),
+            connect_timeout: self.connect_timeout,
+            bind_addr: self.bind_addr,
+            socket_options: self.socket_options,
+            cancel_method: self.cancel_method.clone(),
+            transport: self.transport.clone(),
+            websocket: None,
+            corked: false,
+            connect_deadline: None,
+            keepalive_interval: self.keepalive_interval,
+            keepalive_method: self.keepalive_method.clone(),
+            keepalive_max_missed: self.keepalive_max_missed,
+            last_received_at: Instant::now(),
+            last_ping_at: None,
+            missed_pings: 0,
+            ping_ids: HashSet::new(),
+            unsent: Vec::new(),
+            connected: false,
+            ever_connected: false,
+            failed_attempts: 0,
+            reconnect_at: None,
+            rng_state: RandomState::new().build_hasher().finish() | 1,
+            events_enabled: self.events,
+            events: VecDeque::new(),
+            stats: ClientStats::default(),
+            retries: HashMap::new(),
+            graceful_close: None,
+            handle_sender: None,
+            handle_receiver: None,
+            _response: PhantomData,
+        }
+    }
+}
+
+/// Error returned by [`RpcClient`].
+#[derive(Debug)]
+pub enum ClientError {
+    /// Failed to establish a connection to the server (including timeouts and waiting for the reconnect backoff).
+    Connect(std::io::Error),
+
+    /// I/O error other than connection failures (e.g., the connection was reset, or the send queue is full).
+    Io(std::io::Error),
+
+    /// Failed to serialize a message to be sent (or a pre-serialized message is not valid JSON).
+    Serialize(serde_json::Error),
+
+    /// The server sent a message that violates the protocol (e.g., a line that is not a valid JSON-RPC message).
+    Protocol(serde_json::Error),
+}
+
+impl ClientError {
+    fn from_io(error: std::io::Error, established: bool) -> Self {
+        if established {
+            Self::Io(error)
+        } else {
+            Self::Connect(error)
+        }
+    }
+
+    /// Returns the kind of the underlying I/O error (`None` if this is not an I/O error).
+    pub fn io_error_kind(&self) -> Option<ErrorKind> {
+        match self {
+            Self::Connect(e) | Self::Io(e) => Some(e.kind()),
+            Self::Serialize(_) | Self::Protocol(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connect(e) => write!(f, "Failed to connect: {e}"),
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::Serialize(e) => write!(f, "Failed to serialize a message: {e}"),
+            Self::Protocol(e) => write!(f, "Protocol violation: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Connect(e) | Self::Io(e) => Some(e),
+            Self::Serialize(e) | Self::Protocol(e) => Some(e),
+        }
+    }
+}
+
+impl From<ClientError> for std::io::Error {
+    fn from(error: ClientError) -> Self {
+        match error {
+            ClientError::Connect(e) | ClientError::Io(e) => e,
+            ClientError::Serialize(e) => std::io::Error::new(ErrorKind::InvalidInput, e),
+            ClientError::Protocol(e) => std::io::Error::new(ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// Message received by [`RpcClient`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClientMessage<RESP = ResponseObject> {
+    /// Response to a request sent by the client.
+    Response(RESP),
+
+    /// Request initiated by the server that expects a response.
+    Request(RequestObject),
+
+    /// Notification sent by the server.
+    Notification(RequestObject),
+}
+
+/// Handle to submit messages to an [`RpcClient`] from other threads.
+///
+/// See [`RpcClient::handle()`] for details.
+#[derive(Debug, Clone)]
+pub struct RpcClientHandle {
+    sender: mpsc::Sender<Box<RawValue>>,
+    waker: Arc<Waker>,
+}
+
+impl RpcClientHandle {
+    /// Submits a JSON-RPC message (e.g., a request) to be sent to the server.
+    ///
+    /// The message is serialized on the calling thread.
+    /// If the client has been dropped, this method returns a [`std::io::ErrorKind::BrokenPipe`] error.
+    pub fn send<T: Serialize>(&self, message: &T) -> Result<(), ClientError> {
+        let message = serde_json::value::to_raw_value(message).map_err(ClientError::Serialize)?;
+        self.sender.send(message).map_err(|_| {
+            ClientError::Io(std::io::Error::new(
+                ErrorKind::BrokenPipe,
+                "Client has been dropped",
+            ))
+        })?;
+        self.waker.wake().map_err(ClientError::Io)
+    }
+
+    /// Submits a JSON-RPC notification to be sent to the server.
+    pub fn send_notification(
+        &self,
+        method: &str,
+        params: Option<RequestParams>,
+    ) -> Result<(), ClientError> {
+        let notification = RequestObject {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            id: None,
+            method: method.to_owned(),
+            params,
+        };
+        self.send(&notification)
+    }
+}
+
+/// Send-only half of an [`RpcClient`].
+///
+/// See [`RpcClient::split()`] for details.
+#[derive(Debug)]
+pub struct RpcClientSender<RESP = ResponseObject> {
+    inner: Rc<RefCell<RpcClient<RESP>>>,
+}
+
+impl<RESP> RpcClientSender<RESP>
+where
+    RESP: for<'de> Deserialize<'de>,
+{
+    /// Same as [`RpcClient::send()`].
+    pub fn send<T: Serialize>(&self, poller: &mut Poll, request: &T) -> Result<(), ClientError> {
+        self.inner.borrow_mut().send(poller, request)
+    }
+
+    /// Same as [`RpcClient::call()`].
+    pub fn call(
+        &self,
+        poller: &mut Poll,
+        method: &str,
+        params: Option<RequestParams>,
+    ) -> Result<RequestId, ClientError> {
+        self.inner.borrow_mut().call(poller, method, params)
+    }
+
+    /// Same as [`RpcClient::call_typed()`].
+    pub fn call_typed<P: Serialize>(
+        &self,
+        poller: &mut Poll,
+        method: &str,
+        params: &P,
+    ) -> Result<RequestId, ClientError> {
+        self.inner.borrow_mut().call_typed(poller, method, params)
+    }
+
+    /// Same as [`RpcClient::send_notification()`].
+    pub fn send_notification(
+        &self,
+        poller: &mut Poll,
+        method: &str,
+        params: Option<RequestParams>,
+    ) -> Result<(), ClientError> {
+        self.inner
+            .borrow_mut()
+            .send_notification(poller, method, params)
+    }
+
+    /// Same as [`RpcClient::send_batch()`].
+    pub fn send_batch(
+        &self,
+        poller: &mut Poll,
+        requests: &[RequestObject],
+    ) -> Result<(), ClientError> {
+        self.inner.borrow_mut().send_batch(poller, requests)
+    }
+
+    /// Same as [`RpcClient::cancel()`].
+    pub fn cancel(&self, poller: &mut Poll, id: &RequestId) -> Result<bool, ClientError> {
+        self.inner.borrow_mut().cancel(poller, id)
+    }
+
+    /// Same as [`RpcClient::flush()`].
+    pub fn flush(&self, poller: &mut Poll) -> Result<(), ClientError> {
+        self.inner.borrow_mut().flush(poller)
+    }
+
+    /// Same as [`RpcClient::state()`].
+    pub fn state(&self) -> ClientState {
+        self.inner.borrow().state()
+    }
+}
+
+/// Receive half of an [`RpcClient`].
+///
+/// See [`RpcClient::split()`] for details.
+#[derive(Debug)]
+pub struct RpcClientReceiver<RESP = ResponseObject> {
+    inner: Rc<RefCell<RpcClient<RESP>>>,
+}
+
+impl<RESP> RpcClientReceiver<RESP>
+where
+    RESP: for<'de> Deserialize<'de>,
+{
+    /// Same as [`RpcClient::handle_event()`].
+    pub fn handle_event(&self, poller: &mut Poll, event: &Event) -> Result<(), ClientError> {
+        self.inner.borrow_mut().handle_event(poller, event)
+    }
+
+    /// Same as [`RpcClient::next_timeout()`].
+    pub fn next_timeout(&self) -> Option<Duration> {
+        self.inner.borrow().next_timeout()
+    }
+
+    /// Same as [`RpcClient::handle_timeout()`].
+    pub fn handle_timeout(&self, poller: &mut Poll) -> Result<(), ClientError> {
+        self.inner.borrow_mut().handle_timeout(poller)
+    }
+
+    /// Same as [`RpcClient::try_recv()`].
+    pub fn try_recv(&self) -> Option<RESP> {
+        self.inner.borrow_mut().try_recv()
+    }
+
+    /// Same as [`RpcClient::try_recv_message()`].
+    pub fn try_recv_message(&self) -> Option<ClientMessage<RESP>> {
+        self.inner.borrow_mut().try_recv_message()
+    }
+
+    /// Same as [`RpcClient::try_take_response()`].
+    pub fn try_take_response(&self, id: &RequestId) -> Option<RESP> {
+        self.inner.borrow_mut().try_take_response(id)
+    }
+
+    /// Same as [`RpcClient::try_recv_batch()`].
+    pub fn try_recv_batch(&self) -> Option<Vec<RESP>> {
+        self.inner.borrow_mut().try_recv_batch()
+    }
+
+    /// Same as [`RpcClient::try_recv_event()`].
+    pub fn try_recv_event(&self) -> Option<ClientEvent> {
+        self.inner.borrow_mut().try_recv_event()
+    }
+
+    /// Same as [`RpcClient::reply_ok()`].
+    pub fn reply_ok<T: Serialize>(
+        &self,
+        poller: &mut Poll,
+        id: RequestId,
+        result: T,
+    ) -> Result<(), ClientError> {
+        self.inner.borrow_mut().reply_ok(poller, id, result)
+    }
+
+    /// Same as [`RpcClient::reply_err()`].
+    pub fn reply_err(
+        &self,
+        poller: &mut Poll,
+        id: RequestId,
+        code: ErrorCode,
+        message: &str,
+    ) -> Result<(), ClientError> {
+        self.inner.borrow_mut().reply_err(poller, id, code, message)
+    }
+
+    /// Same as [`RpcClient::stats()`].
+    pub fn stats(&self) -> ClientStats {
+        self.inner.borrow().stats()
+    }
+
+    /// Same as [`RpcClient::close()`].
+    pub fn close(&self, poller: &mut Poll) {
+        self.inner.borrow_mut().close(poller)
+    }
+
+    /// Restores the [`RpcClient`] from this half and `sender`.
+    ///
+    /// If `sender` was split from a different client, both halves are returned as is.
+    pub fn reunite(
+        self,
+        sender: RpcClientSender<RESP>,
+    ) -> Result<RpcClient<RESP>, (RpcClientSender<RESP>, Self)> {
+        if !Rc::ptr_eq(&self.inner, &sender.inner) {
+            return Err((sender, self));
+        }
+        drop(sender);
+        let inner = Rc::into_inner(self.inner).expect("unreachable");
+        Ok(inner.into_inner())
+    }
+}
+
+/// Statistics of [`RpcClient`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientStats {
+    /// Total number of messages (requests, notifications, and responses to server-initiated requests) sent to the server.
+    ///
+    /// Each request in a batch request is counted separately.
+    pub sent_messages: u64,
+
+    /// Total number of responses received from the server.
+    pub received_responses: u64,
+
+    /// Total number of times the connection was re-established after having been established before.
+    pub reconnects: u64,
+
+    /// Total number of bytes received from the server.
+    pub received_bytes: u64,
+
+    /// Total number of bytes sent to the server.
+    pub sent_bytes: u64,
+
+    /// Number of requests awaiting responses.
+    pub in_flight_requests: usize,
+}
+
+impl ClientStats {
+    fn record_closed(&mut self, connection: &Connection) {
+        self.received_bytes += connection.received_bytes();
+        self.sent_bytes += connection.sent_bytes();
+    }
+}
+
+/// State of the connection of an [`RpcClient`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ClientState {
+    /// There is no connection.
+    Disconnected,
+
+    /// A connection to the server is being established.
+    Connecting,
+
+    /// The client is connected to the server.
+    Connected,
+
+    /// The connection is being closed by [`RpcClient::flush_and_close()`].
+    ShuttingDown,
+}
+
+/// Event reported by [`RpcClient`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// A connection to the server was established.
+    Connected,
+
+    /// The established connection to the server was lost due to an error
+    /// (including the server closing the connection).
+    ///
+    /// This event is not reported for [`RpcClient::close()`].
+    Disconnected {
+        /// Kind of the error that caused the disconnection.
+        error_kind: ErrorKind,
+    },
+
+    /// The client started an attempt to reconnect to the server.
+    ReconnectAttempt {
+        /// Number of this attempt since the connection was lost (starting from 1).
+        attempt: u32,
+    },
+
+    /// The client reconnected to the server.
+    Reconnected {
+        /// Number of attempts it took to reconnect.
+        attempts: u32,
+    },
+
+    /// An attempt to (re)connect to the server failed.
+    ReconnectFailed {
+        /// Number of consecutive failed attempts.
+        attempt: u32,
+
+        /// Delay until the next attempt is allowed.
+        retry_after: Duration,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum ServerTarget {
+    Tcp(SocketAddr),
+    Host(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ServerTarget {
+    fn host(&self) -> String {
+        match self {
+            Self::Tcp(addr) => addr.to_string(),
+            Self::Host(host) => host.clone(),
+            #[cfg(unix)]
+            Self::Unix(_) => "localhost".to_owned(),
+        }
+    }
+
+    /// Returns the TCP address to connect to (`None` for Unix domain sockets).
+    fn resolve(&self, attempt: u32) -> std::io::Result<Option<SocketAddr>> {
+        match self {
+            Self::Tcp(addr) => Ok(Some(*addr)),
+            Self::Host(host) => {
+                let addrs = host.to_socket_addrs()?.collect::<Vec<_>>();
+                if addrs.is_empty() {
+                    return Err(std::io::Error::new(
+                        ErrorKind::NotFound,
+                        "No addresses resolved for the hostname",
+                    ));
+                }
+                Ok(Some(addrs[attempt as usize % addrs.len()]))
+            }
+            #[cfg(unix)]
+            Self::Unix(_) => Ok(None),
+        }
+    }
+
+    fn connect(
+        &self,
+        addr: Option<SocketAddr>,
+        bind_addr: Option<SocketAddr>,
+        socket_options: &SocketOptions,
+    ) -> std::io::Result<Stream> {
+        #[cfg(unix)]
+        if let Self::Unix(path) = self {
+            return Ok(Stream::Unix(mio::net::UnixStream::connect(path)?));
+        }
+
+        let addr = addr.expect("unreachable");
+        let stream = match bind_addr {
+            Some(bind_addr) => socket::connect_from(bind_addr, addr)?,
+            None => TcpStream::connect(addr)?,
+        };
+        // Socket options are applied on a best-effort basis.
+        let _ = socket::apply_socket_options(&stream, socket_options);
+        Ok(Stream::Tcp(stream))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Transport {
+    JsonLines,
+    Http(String),
+    WebSocket(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ReconnectBackoff {
+    initial: Duration,
+    max: Duration,
+    jitter: bool,
+}
+
+/// Retry policy for [`RpcClient::call_with_retry()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retry_on_disconnect: bool,
+    retry_on_error_codes: Vec<ErrorCode>,
+}
+
+impl RetryPolicy {
+    /// Makes a new [`RetryPolicy`] instance that sends a request at most `max_attempts` times (including the first attempt).
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            retry_on_disconnect: true,
+            retry_on_error_codes: Vec::new(),
+        }
+    }
+
+    /// Sets the delay before each retry (the default is no delay).
+    ///
+    /// The delay starts at `initial` and doubles on each retry, up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Sets whether to retry requests whose connection is lost before the response arrives (the default is `true`).
+    pub fn retry_on_disconnect(mut self, enabled: bool) -> Self {
+        self.retry_on_disconnect = enabled;
+        self
+    }
+
+    /// Adds an error code for which requests are retried (the default is none).
+    pub fn retry_on_error_code(mut self, code: ErrorCode) -> Self {
+        self.retry_on_error_codes.push(code);
+        self
+    }
+
+    fn delay(&self, attempts: u32) -> Duration {
+        let exp = attempts.saturating_sub(1).min(31);
+        self.initial_backoff
+            .saturating_mul(1 << exp)
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Debug)]
+struct GracefulClose {
+    deadline: Instant,
+    wait_for_responses: bool,
+}
+
+#[derive(Debug)]
+struct PendingRetry {
+    request: RequestObject,
+    policy: RetryPolicy,
+    attempts: u32,
+    retry_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct ReceivedResponse<RESP> {
+    response: RESP,
+    id: Option<RequestId>,
+    solicited: bool,
+    seq: u64,
+}
+
+fn error_response<RESP>(id: &RequestId, code: ErrorCode, message: &str) -> Option<RESP>
+where
+    RESP: for<'de> Deserialize<'de>,
+{
+    let response = ResponseObject::Err {
+        jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+        error: ErrorObject {
+            code,
+            message: message.to_owned(),
+            data: None,
+        },
+        id: Some(id.clone()),
+    };
+    let value = serde_json::to_value(&response).expect("unreachable");
+    serde_json::from_value(value).ok()
+}
+
+fn unsent_request_ids(unsent: &[u8]) -> HashSet<RequestId> {
+    let mut ids = HashSet::new();
+    for line in unsent.split(|&b| b == b'\n') {
+        if let Ok(probe) = serde_json::from_slice::<IdProbe>(line) {
+            ids.extend(probe.id);
+        } else if let Ok(probes) = serde_json::from_slice::<Vec<IdProbe>>(line) {
+            ids.extend(probes.into_iter().filter_map(|p| p.id));
+        }
+    }
+    ids
+}
+
+#[derive(Debug, Deserialize)]
+struct IdProbe {
+    #[serde(default)]
+    id: Option<RequestId>,
+    #[serde(default)]
+    error: Option<ErrorCodeProbe>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorCodeProbe {
+    code: ErrorCode,
+}
+
+fn correlate_batch<RESP>(
+    pending_batches: &mut Vec<Vec<RequestId>>,
+    mut responses: Vec<(Option<RequestId>, RESP)>,
+) -> Vec<RESP> {
+    let Some(i) = pending_batches.iter().position(|ids| {
+        responses
+            .iter()
+            .any(|(id, _)| id.as_ref().is_some_and(|id| ids.contains(id)))
+    }) else {
+        return responses.into_iter().map(|(_, r)| r).collect();
+    };
+    let ids = pending_batches.swap_remove(i);
+
+    let mut ordered = Vec::with_capacity(responses.len());
+    for id in &ids {
+        if let Some(j) = responses.iter().position(|(x, _)| x.as_ref() == Some(id)) {
+            ordered.push(responses.remove(j).1);
         }
-        error
     }
+    ordered.extend(responses.into_iter().map(|(_, r)| r));
+    ordered
 }
@@ -1,34 +1,131 @@
-use std::{io::ErrorKind, net::Shutdown};
+use std::{
+    any::Any,
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr},
+    time::{Duration, Instant},
+};
 
-use jsonlrpc::JsonlStream;
-use mio::{event::Event, net::TcpStream, Interest, Poll, Token};
-use serde::Serialize;
+use mio::{event::Event, Interest, Poll, Token};
+use serde::{Deserialize, Serialize};
 
-/// TCP connection state.
+use crate::stream::Stream;
+
+/// Connection state.
 #[allow(missing_docs)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ConnectionState {
     Connecting,
     Connected,
+
+    /// The connection is about to be closed once the queued data has been flushed
+    /// (e.g., by [`RpcServer::disconnect_gracefully()`](crate::RpcServer::disconnect_gracefully)).
+    ShuttingDown,
+
     Closed,
 }
 
-/// TCP connection.
+/// Level of the write buffer of a [`Connection`] relative to its watermarks.
+///
+/// See [`Connection::set_write_watermarks()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WriteBufferLevel {
+    /// The queued data reached the high watermark.
+    High,
+
+    /// The queued data fell to the low watermark after reaching the high watermark.
+    Low,
+}
+
+/// Default number of bytes read from the stream at once.
+pub(crate) const READ_CHUNK_SIZE: usize = 4096;
+
+/// JSON Lines connection over a TCP or Unix domain socket stream.
 #[derive(Debug)]
 pub struct Connection {
     token: Token,
-    stream: JsonlStream<TcpStream>,
+    stream: Stream,
     state: ConnectionState,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    read_buf: Vec<u8>,
+    read_buf_offset: usize,
+    read_buf_scanned: usize,
+    read_chunk_size: usize,
+    write_buf: Vec<u8>,
+    write_buf_offset: usize,
+    write_msg_ends: VecDeque<usize>,
+    chunked: VecDeque<ChunkedMessage>,
+    write_stalled_since: Option<Instant>,
+    write_watermarks: Option<(usize, usize)>,
+    write_buf_high: bool,
+    watermark_observer: Option<WatermarkObserver>,
+    read_closed: bool,
+    max_line_size: usize,
+    max_outgoing_line_size: usize,
+    max_retained_buf_size: usize,
+    buf_shrink_delay: Duration,
+    read_buf_underused_since: Option<Instant>,
+    write_buf_underused_since: Option<Instant>,
+    corked: bool,
+    read_interest: bool,
+    reading_paused: bool,
+    registered: bool,
+    parked: bool,
+    shutdown_requested: bool,
+    received_bytes: u64,
+    sent_bytes: u64,
+    last_read_at: Instant,
+    last_write_at: Instant,
+    user_data: Option<Box<dyn Any + Send>>,
+    last_error: Option<std::io::Error>,
+    state_observer: Option<StateObserver>,
 }
 
 impl Connection {
-    pub(crate) fn new(token: Token, stream: TcpStream, state: ConnectionState) -> Self {
-        let _ = stream.set_nodelay(true);
-        Self {
+    pub(crate) fn new(token: Token, stream: impl Into<Stream>, state: ConnectionState) -> Self {
+        let now = Instant::now();
+        let mut this = Self {
             token,
-            stream: JsonlStream::new(stream),
+            stream: stream.into(),
             state,
-        }
+            peer_addr: None,
+            local_addr: None,
+            read_buf: Vec::new(),
+            read_buf_offset: 0,
+            read_buf_scanned: 0,
+            read_chunk_size: READ_CHUNK_SIZE,
+            write_buf: Vec::new(),
+            write_buf_offset: 0,
+            write_msg_ends: VecDeque::new(),
+            chunked: VecDeque::new(),
+            write_stalled_since: None,
+            write_watermarks: None,
+            write_buf_high: false,
+            watermark_observer: None,
+            read_closed: false,
+            max_line_size: usize::MAX,
+            max_outgoing_line_size: usize::MAX,
+            max_retained_buf_size: usize::MAX,
+            buf_shrink_delay: Duration::ZERO,
+            read_buf_underused_since: None,
+            write_buf_underused_since: None,
+            corked: false,
+            read_interest: true,
+            reading_paused: false,
+            registered: true,
+            parked: false,
+            shutdown_requested: false,
+            received_bytes: 0,
+            sent_bytes: 0,
+            last_read_at: now,
+            last_write_at: now,
+            user_data: None,
+            last_error: None,
+            state_observer: None,
+        };
+        this.cache_addrs();
+        this
     }
 
     /// Returns the `mio` token assigned to this connection.
@@ -41,9 +138,261 @@ impl Connection {
         self.state
     }
 
-    /// Returns a reference to the internal TCP stream.
-    pub fn stream(&self) -> &TcpStream {
-        self.stream.inner()
+    /// Returns the total number of bytes received on this connection.
+    pub fn received_bytes(&self) -> u64 {
+        self.received_bytes
+    }
+
+    /// Returns the total number of bytes sent on this connection.
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent_bytes
+    }
+
+    /// Returns the time when data was last received on this connection (or when the connection was created).
+    pub fn last_read_at(&self) -> Instant {
+        self.last_read_at
+    }
+
+    /// Returns the time when data was last written to the stream (or when the connection was created).
+    pub fn last_write_at(&self) -> Instant {
+        self.last_write_at
+    }
+
+    /// Returns the time elapsed since the last read or write on this connection.
+    pub fn idle_for(&self) -> Duration {
+        self.last_read_at.max(self.last_write_at).elapsed()
+    }
+
+    /// Returns the address of the peer.
+    ///
+    /// The address is cached when the connection is accepted or established, so it is available even after the connection is closed.
+    /// Returns `None` for Unix domain socket connections (or TCP connections that have not been established yet).
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Returns the local address of this connection.
+    ///
+    /// As with [`Connection::peer_addr()`], the address is cached and `None` is returned for Unix domain socket connections.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Returns how long queued data has been waiting to be written without any progress
+    /// (e.g., because the peer has stopped reading).
+    ///
+    /// Returns `None` if the write buffer is empty or the last write attempt made progress.
+    pub fn write_stalled_for(&self) -> Option<Duration> {
+        self.write_stalled_since.map(|t| t.elapsed())
+    }
+
+    pub(crate) fn write_stalled_since(&self) -> Option<Instant> {
+        self.write_stalled_since
+    }
+
+    /// Sets the low and high watermarks in bytes of the write buffer (the default is `None`).
+    ///
+    /// Once the queued data reaches `high`, [`Connection::is_write_buffer_high()`] becomes `true`
+    /// until the queued data falls to `low` or below. The crossings are reported to the observer set by
+    /// [`Connection::set_watermark_observer()`], which allows applications to stop producing data while the buffer is high.
+    pub fn set_write_watermarks(&mut self, watermarks: Option<(usize, usize)>) {
+        self.write_watermarks = watermarks;
+        self.update_write_buffer_level();
+    }
+
+    /// Sets a callback that is invoked whenever the write buffer crosses a watermark set by [`Connection::set_write_watermarks()`].
+    pub fn set_watermark_observer<F>(&mut self, observer: F)
+    where
+        F: 'static + Send + FnMut(WriteBufferLevel),
+    {
+        self.watermark_observer = Some(WatermarkObserver(Box::new(observer)));
+    }
+
+    /// Returns `true` if the write buffer has reached the high watermark and has not fallen to the low watermark since.
+    pub fn is_write_buffer_high(&self) -> bool {
+        self.write_buf_high
+    }
+
+    fn update_write_buffer_level(&mut self) {
+        let queued = self.queued_bytes_len();
+        let level = match self.write_watermarks {
+            Some((_, high)) if !self.write_buf_high && queued >= high => WriteBufferLevel::High,
+            Some((low, _)) if self.write_buf_high && queued <= low => WriteBufferLevel::Low,
+            None if self.write_buf_high => WriteBufferLevel::Low,
+            _ => return,
+        };
+        self.write_buf_high = level == WriteBufferLevel::High;
+        if let Some(observer) = &mut self.watermark_observer {
+            (observer.0)(level);
+        }
+    }
+
+    /// Returns `true` if the peer has closed its writing side of this connection (i.e., EOF has been received).
+    ///
+    /// Data can still be sent on such a half-closed connection.
+    pub fn is_read_closed(&self) -> bool {
+        self.read_closed
+    }
+
+    /// Returns a reference to the application data attached to this connection if it is of type `T`.
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        self.user_data.as_ref()?.downcast_ref()
+    }
+
+    /// Returns a mutable reference to the application data attached to this connection if it is of type `T`.
+    pub fn user_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.user_data.as_mut()?.downcast_mut()
+    }
+
+    /// Attaches application data (e.g., a session state) to this connection, replacing the existing one.
+    pub fn set_user_data<T: Any + Send>(&mut self, data: T) {
+        self.user_data = Some(Box::new(data));
+    }
+
+    /// Detaches the application data from this connection if it is of type `T`.
+    pub fn take_user_data<T: Any>(&mut self) -> Option<T> {
+        if !self.user_data.as_ref()?.is::<T>() {
+            return None;
+        }
+        let data = self.user_data.take()?.downcast().expect("unreachable");
+        Some(*data)
+    }
+
+    /// Sets a callback that is invoked with the old and new states whenever the state of this connection changes.
+    pub fn set_state_observer<F>(&mut self, observer: F)
+    where
+        F: 'static + Send + FnMut(ConnectionState, ConnectionState),
+    {
+        self.state_observer = Some(StateObserver(Box::new(observer)));
+    }
+
+    /// Returns the error that caused this connection to be closed.
+    ///
+    /// Errors other than I/O errors (e.g., a malformed message) are reported as [`ErrorKind::InvalidData`].
+    /// Returns `None` if the connection is open or was closed deliberately.
+    pub fn last_error(&self) -> Option<&std::io::Error> {
+        self.last_error.as_ref()
+    }
+
+    /// Returns a reference to the internal stream.
+    ///
+    /// Since Unix domain socket connections were supported, this returns a [`Stream`] instead of a TCP stream.
+    /// This is a breaking change: callers that used the TCP stream directly (e.g., `conn.stream().peer_addr()`)
+    /// need to use [`Stream::as_tcp()`] (e.g., `conn.stream().as_tcp().map(|s| s.peer_addr())`)
+    /// or [`Connection::peer_addr()`] and [`Connection::local_addr()`] instead.
+    pub fn stream(&self) -> &Stream {
+        &self.stream
+    }
+
+    /// Registers this connection to `poller` with the given token.
+    ///
+    /// This is used to resume a connection detached by [`RpcServer::take_connection()`](crate::RpcServer::take_connection).
+    pub fn register(&mut self, poller: &mut Poll, token: Token) -> std::io::Result<()> {
+        self.token = token;
+        self.registered = true;
+        self.parked = true;
+        let result = self.update_interests(poller, self.has_pending_writes());
+        if result.is_err() {
+            self.registered = false;
+            self.parked = false;
+        }
+        result
+    }
+
+    /// Sets whether this connection is registered with [`Interest::READABLE`] (the default is `true`).
+    ///
+    /// While disabled, no readable events are reported for this connection, and the internal
+    /// reregistration performed when sending data keeps the setting
+    /// (the connection is only registered with [`Interest::WRITABLE`] while data is queued).
+    pub fn set_read_interest(&mut self, poller: &mut Poll, enabled: bool) -> std::io::Result<()> {
+        if self.read_interest == enabled {
+            return Ok(());
+        }
+        self.read_interest = enabled;
+        if self.state == ConnectionState::Closed {
+            return Ok(());
+        }
+        self.update_interests(poller, self.has_pending_writes())
+    }
+
+    /// Stops reading from this connection without closing it (e.g., while the application's processing queue is full).
+    ///
+    /// In addition to dropping [`Interest::READABLE`], this makes [`Connection::handle_event()`] and
+    /// [`Connection::handle_read()`] stop calling `on_read`, even if complete lines have already been buffered.
+    /// This method can also be called from within `on_read`.
+    pub fn pause_reading(&mut self, poller: &mut Poll) -> std::io::Result<()> {
+        self.reading_paused = true;
+        self.set_read_interest(poller, false)
+    }
+
+    /// Resumes reading paused by [`Connection::pause_reading()`].
+    ///
+    /// As lines buffered before pausing do not cause new readable events,
+    /// [`Connection::handle_read()`] should be called afterwards to process them.
+    pub fn resume_reading(&mut self, poller: &mut Poll) -> std::io::Result<()> {
+        self.reading_paused = false;
+        self.set_read_interest(poller, true)
+    }
+
+    /// Returns `true` if reading has been paused by [`Connection::pause_reading()`].
+    pub fn is_reading_paused(&self) -> bool {
+        self.reading_paused
+    }
+
+    /// Returns `true` if this connection is registered with [`Interest::READABLE`] (see [`Connection::set_read_interest()`]).
+    pub fn read_interest(&self) -> bool {
+        self.read_interest
+    }
+
+    /// Deregisters this connection from `poller` and returns the underlying stream
+    /// along with the received bytes that have not been read yet.
+    ///
+    /// This can be used to switch to another protocol on the same stream (e.g., after an RPC handshake).
+    /// Note that queued data which has not been written to the stream yet is discarded
+    /// (call [`Connection::flush()`] beforehand and check [`Connection::queued_bytes_len()`] if needed).
+    pub fn into_inner(mut self, poller: &mut Poll) -> (Stream, Vec<u8>) {
+        self.deregister(poller);
+        let mut unread = self.read_buf;
+        unread.drain(..self.read_buf_offset);
+        (self.stream, unread)
+    }
+
+    pub(crate) fn deregister(&mut self, poller: &mut Poll) {
+        if !self.parked {
+            let _ = poller.registry().deregister(&mut self.stream);
+        }
+        self.registered = false;
+        self.parked = false;
+    }
+
+    fn update_interests(&mut self, poller: &mut Poll, writing: bool) -> std::io::Result<()> {
+        if !self.registered {
+            return Ok(());
+        }
+        let interests = match (self.read_interest, writing) {
+            (true, false) => Interest::READABLE,
+            (true, true) => Interest::READABLE | Interest::WRITABLE,
+            (false, true) => Interest::WRITABLE,
+            (false, false) => {
+                // `mio` does not allow registering without any interests.
+                if !self.parked {
+                    poller.registry().deregister(&mut self.stream)?;
+                    self.parked = true;
+                }
+                return Ok(());
+            }
+        };
+        if self.parked {
+            poller
+                .registry()
+                .register(&mut self.stream, self.token, interests)?;
+            self.parked = false;
+            Ok(())
+        } else {
+            poller
+                .registry()
+                .reregister(&mut self.stream, self.token, interests)
+        }
     }
 
     pub(crate) fn close(&mut self, poller: &mut Poll) {
@@ -51,16 +400,111 @@ impl Connection {
             return;
         }
 
-        let _ = poller.registry().deregister(self.stream.inner_mut());
-        let _ = self.stream.inner().shutdown(Shutdown::Both);
-        self.state = ConnectionState::Closed;
+        self.deregister(poller);
+        let _ = self.stream.shutdown(Shutdown::Both);
+        self.set_state(ConnectionState::Closed);
+    }
+
+    /// Closes this connection, recording `error` as [`Connection::last_error()`].
+    pub(crate) fn close_with_error(&mut self, poller: &mut Poll, error: &serde_json::Error) {
+        if self.state == ConnectionState::Closed {
+            return;
+        }
+        let kind = error.io_error_kind().unwrap_or(ErrorKind::InvalidData);
+        self.last_error = Some(std::io::Error::new(kind, error.to_string()));
+        self.close(poller);
+    }
+
+    /// Marks this connection as [`ConnectionState::ShuttingDown`] (once it has been established).
+    pub(crate) fn begin_shutdown(&mut self) {
+        self.shutdown_requested = true;
+        if self.state == ConnectionState::Connected {
+            self.set_state(ConnectionState::ShuttingDown);
+        }
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        let old = std::mem::replace(&mut self.state, state);
+        if let Some(observer) = &mut self.state_observer {
+            (observer.0)(old, state);
+        }
+    }
+
+    pub(crate) fn set_max_line_size(&mut self, size: usize) {
+        self.max_line_size = size;
+    }
+
+    /// Sets the maximum size of a line to be sent (excluding the newline).
+    ///
+    /// Sending a larger message fails with [`message_too_large_error()`] without queueing it or closing the connection.
+    pub(crate) fn set_max_outgoing_line_size(&mut self, size: usize) {
+        self.max_outgoing_line_size = size;
+    }
+
+    /// Sets the capacity beyond which the read and write buffers are shrunk once they are drained.
+    ///
+    /// A buffer is only shrunk after it has not needed more than `size` bytes for `delay`,
+    /// so that connections alternating between large and small messages do not reallocate on every cycle.
+    pub(crate) fn set_max_retained_buf_size(&mut self, size: usize, delay: Duration) {
+        self.max_retained_buf_size = size;
+        self.buf_shrink_delay = delay;
+    }
+
+    /// Sets the capacities of the read and write buffers (the defaults are 4096 bytes and 0 bytes respectively).
+    ///
+    /// `read` is also the number of bytes read from the stream at once, so the read buffer
+    /// only grows beyond it to hold lines that are larger.
+    /// Buffers that currently hold data are reallocated once they are drained.
+    pub fn set_buf_capacities(&mut self, read: usize, write: usize) {
+        self.read_chunk_size = read.max(1);
+        if self.read_buf_offset == self.read_buf.len() {
+            self.read_buf = Vec::with_capacity(read);
+            self.read_buf_offset = 0;
+            self.read_buf_scanned = 0;
+        }
+        if self.queued_bytes_len() == 0 {
+            self.write_buf = Vec::with_capacity(write);
+            self.write_buf_offset = 0;
+        }
+    }
+
+    /// Returns the total capacity in bytes allocated for the read and write buffers of this connection.
+    pub fn allocated_buf_size(&self) -> usize {
+        self.read_buf.capacity() + self.write_buf.capacity()
+    }
+
+    /// Returns the number of bytes queued to be written to the stream.
+    pub fn queued_bytes_len(&self) -> usize {
+        let following = self
+            .chunked
+            .iter()
+            .map(|m| m.following.len())
+            .sum::<usize>();
+        self.write_buf.len() - self.write_buf_offset + following
+    }
+
+    /// Returns the number of queued messages that have not been completely written to the stream.
+    ///
+    /// A message that has been partially written is included.
+    pub fn queued_messages_len(&self) -> usize {
+        let chunked = self
+            .chunked
+            .iter()
+            .map(|m| 1 + m.following_ends.len())
+            .sum::<usize>();
+        self.write_msg_ends.len() + chunked
     }
 
-    pub(crate) fn queued_bytes_len(&self) -> usize {
-        self.stream.write_buf().len()
+    /// Returns `true` if there is queued data or a chunked message that has not been completely written yet.
+    pub(crate) fn has_pending_writes(&self) -> bool {
+        self.queued_bytes_len() > 0 || !self.chunked.is_empty()
     }
 
-    pub(crate) fn handle_event<F>(
+    /// Handles a `mio` event for this connection.
+    ///
+    /// `on_read` is called repeatedly while the connection is readable (e.g., to call [`Connection::read_value()`])
+    /// until it returns an error. [`std::io::ErrorKind::WouldBlock`] errors are not propagated.
+    pub fn handle_event<F>(
         &mut self,
         poller: &mut Poll,
         event: &Event,
@@ -84,11 +528,31 @@ impl Connection {
         Ok(())
     }
 
-    fn handle_read<F>(&mut self, poller: &mut Poll, mut on_read: F) -> serde_json::Result<()>
+    /// Same as [`Connection::handle_event()`] but passes each received line to `on_line` as raw bytes
+    /// (excluding the newline) without parsing it.
+    ///
+    /// This is useful to handle lines that are not JSON-RPC messages (e.g., control lines of a custom protocol)
+    /// or to relay lines transparently. Returning an error from `on_line` closes the connection.
+    pub fn handle_event_raw<F>(
+        &mut self,
+        poller: &mut Poll,
+        event: &Event,
+        mut on_line: F,
+    ) -> serde_json::Result<()>
+    where
+        F: FnMut(&[u8]) -> serde_json::Result<()>,
+    {
+        self.handle_event(poller, event, |c, _| on_line(c.read_line()?))
+    }
+
+    /// Calls `on_read` repeatedly in the same way as [`Connection::handle_event()`] without waiting for a readable event.
+    ///
+    /// This is useful to process the lines that have already been received (e.g., after [`Connection::resume_reading()`]).
+    pub fn handle_read<F>(&mut self, poller: &mut Poll, mut on_read: F) -> serde_json::Result<()>
     where
         F: FnMut(&mut Self, &mut Poll) -> serde_json::Result<()>,
     {
-        while self.state != ConnectionState::Closed {
+        while self.state != ConnectionState::Closed && !self.reading_paused {
             if let Err(e) = on_read(self, poller) {
                 self.handle_error(poller, e)?;
                 break;
@@ -97,27 +561,412 @@ impl Connection {
         Ok(())
     }
 
-    pub(crate) fn send<T: Serialize>(
+    /// Sends a JSON value as a line.
+    pub fn send<T: Serialize>(&mut self, poller: &mut Poll, request: &T) -> serde_json::Result<()> {
+        self.send_all(poller, std::iter::once(request))
+    }
+
+    /// Queues all of `values` and then flushes them at once.
+    pub(crate) fn send_all<'a, T, I>(
         &mut self,
         poller: &mut Poll,
-        request: &T,
-    ) -> serde_json::Result<()> {
+        values: I,
+    ) -> serde_json::Result<()>
+    where
+        T: 'a + Serialize,
+        I: IntoIterator<Item = &'a T>,
+    {
         self.check_not_closed()?;
 
-        let start_writing = self.queued_bytes_len() == 0;
+        let start_writing = !self.has_pending_writes();
 
-        self.stream
-            .write_value_to_buf(request)
-            .or_else(|e| self.handle_error(poller, e))?;
-        if self.state == ConnectionState::Connecting {
+        for value in values {
+            self.write_value_to_buf(value)
+                .or_else(|e| self.handle_error(poller, e))?;
+        }
+        self.update_write_buffer_level();
+        if self.state == ConnectionState::Connecting || self.corked {
+            return Ok(());
+        }
+
+        self.handle_write(poller, start_writing)
+    }
+
+    /// Queues raw bytes (e.g., a message framed by a protocol other than JSON Lines) and then flushes them.
+    pub(crate) fn send_bytes(&mut self, poller: &mut Poll, bytes: &[u8]) -> serde_json::Result<()> {
+        self.check_not_closed()?;
+        if bytes.len() > self.max_outgoing_line_size {
+            return Err(message_too_large_error());
+        }
+
+        let start_writing = !self.has_pending_writes();
+        self.queue_bytes(bytes);
+        if self.state == ConnectionState::Connecting || self.corked {
+            return Ok(());
+        }
+
+        self.handle_write(poller, start_writing)
+    }
+
+    /// Writes a line produced by `write` directly into the write buffer and then flushes it.
+    ///
+    /// The outer error is from `write` (in which case nothing is queued), and the inner one is from sending.
+    pub(crate) fn send_with<F>(
+        &mut self,
+        poller: &mut Poll,
+        write: F,
+    ) -> std::io::Result<serde_json::Result<()>>
+    where
+        F: FnOnce(&mut dyn Write) -> std::io::Result<()>,
+    {
+        if let Err(e) = self.check_not_closed() {
+            return Ok(Err(e));
+        }
+
+        let start_writing = !self.has_pending_writes();
+
+        let max_size = self.max_outgoing_line_size;
+        let (buf, ends) = self.tail_buf();
+        let old_len = buf.len();
+        let result = write(buf).and_then(|()| {
+            if buf[old_len..].contains(&b'\n') {
+                Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "Message must not contain newlines",
+                ))
+            } else if buf.len() - old_len > max_size {
+                Err(message_too_large_error().into())
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = result {
+            buf.truncate(old_len);
+            return Err(e);
+        }
+        buf.push(b'\n');
+        ends.push_back(buf.len());
+        self.update_write_buffer_level();
+        if self.state == ConnectionState::Connecting || self.corked {
+            return Ok(Ok(()));
+        }
+
+        Ok(self.handle_write(poller, start_writing))
+    }
+
+    /// Reads a JSON value from the next line received on this connection.
+    ///
+    /// Returns a [`std::io::ErrorKind::WouldBlock`] error if no complete line has been received yet.
+    pub fn read_value<T>(&mut self) -> serde_json::Result<T>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let line = self.read_line()?;
+        serde_json::from_slice(line)
+    }
+
+    /// Reads the next line received on this connection as raw bytes (excluding the newline).
+    ///
+    /// Returns a [`std::io::ErrorKind::WouldBlock`] error if no complete line has been received yet.
+    pub fn read_line(&mut self) -> serde_json::Result<&[u8]> {
+        loop {
+            if let Some(i) = self.read_buf[self.read_buf_scanned..]
+                .iter()
+                .position(|b| *b == b'\n')
+            {
+                let start = self.read_buf_offset;
+                let end = self.read_buf_scanned + i;
+                self.read_buf_offset = end + 1;
+                self.read_buf_scanned = end + 1;
+                if end - start > self.max_line_size {
+                    return Err(line_too_large_error());
+                }
+                return Ok(&self.read_buf[start..end]);
+            }
+            self.read_buf_scanned = self.read_buf.len();
+            if self.read_buf.len() - self.read_buf_offset > self.max_line_size {
+                return Err(line_too_large_error());
+            }
+            self.fill_read_buf()?;
+        }
+    }
+
+    /// Reads and consumes a prefix of the stream (e.g., a protocol header) that precedes the JSON lines.
+    ///
+    /// `parse` returns `Ok(None)` if more data is needed, or the length of the prefix and the parsed value.
+    pub(crate) fn read_prefix<T, F>(&mut self, mut parse: F) -> serde_json::Result<T>
+    where
+        F: FnMut(&[u8]) -> std::io::Result<Option<(usize, T)>>,
+    {
+        loop {
+            let buf = &self.read_buf[self.read_buf_offset..];
+            if let Some((len, value)) = parse(buf).map_err(serde_json::Error::io)? {
+                self.read_buf_offset += len;
+                self.read_buf_scanned = self.read_buf_scanned.max(self.read_buf_offset);
+                return Ok(value);
+            }
+            self.fill_read_buf()?;
+        }
+    }
+
+    fn fill_read_buf(&mut self) -> serde_json::Result<()> {
+        let used = self.read_buf.len();
+        if self.read_buf_offset > 0 {
+            self.read_buf.drain(..self.read_buf_offset);
+            self.read_buf_scanned -= self.read_buf_offset;
+            self.read_buf_offset = 0;
+        }
+        if self.read_buf.is_empty() {
+            // Releases the memory retained after receiving a large message.
+            shrink_buf(
+                &mut self.read_buf,
+                used,
+                self.max_retained_buf_size,
+                self.buf_shrink_delay,
+                &mut self.read_buf_underused_since,
+            );
+        }
+        if self.read_closed {
+            return Err(serde_json::Error::io(ErrorKind::UnexpectedEof.into()));
+        }
+
+        let old_len = self.read_buf.len();
+        self.read_buf.resize(old_len + self.read_chunk_size, 0);
+        let result = self.stream.read(&mut self.read_buf[old_len..]);
+        let read_size = *result.as_ref().unwrap_or(&0);
+        self.read_buf.truncate(old_len + read_size);
+        self.received_bytes += read_size as u64;
+        if read_size > 0 {
+            self.last_read_at = Instant::now();
+        }
+        match result {
+            Err(e) => Err(serde_json::Error::io(e)),
+            Ok(0) => {
+                self.read_closed = true;
+                Err(serde_json::Error::io(ErrorKind::UnexpectedEof.into()))
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    fn write_value_to_buf<T: Serialize>(&mut self, value: &T) -> serde_json::Result<()> {
+        let max_size = self.max_outgoing_line_size;
+        let (buf, ends) = self.tail_buf();
+        let old_len = buf.len();
+        let writer = LimitedWriter {
+            buf: &mut *buf,
+            limit: old_len.saturating_add(max_size),
+        };
+        if let Err(e) = serde_json::to_writer(writer, value) {
+            buf.truncate(old_len);
+            if e.is_io() {
+                // Only `LimitedWriter` produces I/O errors here.
+                return Err(message_too_large_error());
+            }
+            return Err(e);
+        }
+        buf.push(b'\n');
+        ends.push_back(buf.len());
+        Ok(())
+    }
+
+    /// Returns the buffer that new messages are appended to, along with their end offsets.
+    ///
+    /// While a chunked message is being sent, subsequent messages are held until it completes.
+    fn tail_buf(&mut self) -> (&mut Vec<u8>, &mut VecDeque<usize>) {
+        match self.chunked.back_mut() {
+            Some(m) => (&mut m.following, &mut m.following_ends),
+            None => (&mut self.write_buf, &mut self.write_msg_ends),
+        }
+    }
+
+    /// Takes the queued lines that have not been written to the stream at all.
+    ///
+    /// A line that has been partially written is included in its entirety.
+    pub(crate) fn take_unsent_bytes(&mut self) -> Vec<u8> {
+        let start = self.write_buf[..self.write_buf_offset]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let unsent = self.write_buf.split_off(start);
+        self.write_buf.clear();
+        self.write_buf_offset = 0;
+        self.write_msg_ends.clear();
+        self.write_stalled_since = None;
+        self.update_write_buffer_level();
+        unsent
+    }
+
+    /// Makes subsequent sends only queue data until [`Connection::uncork()`] is called.
+    pub(crate) fn cork(&mut self) {
+        self.corked = true;
+    }
+
+    /// Flushes the data queued while corked at once.
+    pub(crate) fn uncork(&mut self, poller: &mut Poll) -> serde_json::Result<()> {
+        self.corked = false;
+        if !self.is_established() || !self.has_pending_writes() {
+            return Ok(());
+        }
+        self.handle_write(poller, true)
+    }
+
+    /// Queues `bytes` as a single message without flushing it.
+    pub(crate) fn queue_bytes(&mut self, bytes: &[u8]) {
+        let (buf, ends) = self.tail_buf();
+        buf.extend_from_slice(bytes);
+        ends.push_back(buf.len());
+        self.update_write_buffer_level();
+    }
+
+    /// Sends a line whose content is produced chunk by chunk by `produce`.
+    ///
+    /// `produce` is called to append the next chunk each time the previously produced data has been written,
+    /// at most once per write attempt, and returns `Ok(true)` once the line is complete.
+    /// Messages sent in the meantime are queued after the line.
+    /// As part of the line may already have been written, errors from `produce` (including
+    /// newlines in chunks and exceeding the size limit) close the connection.
+    pub(crate) fn send_chunked<F>(
+        &mut self,
+        poller: &mut Poll,
+        produce: F,
+    ) -> serde_json::Result<()>
+    where
+        F: 'static + Send + FnMut(&mut dyn Write) -> std::io::Result<bool>,
+    {
+        self.check_not_closed()?;
+
+        let start_writing = !self.has_pending_writes();
+        self.chunked.push_back(ChunkedMessage {
+            produce: Box::new(produce),
+            size: 0,
+            following: Vec::new(),
+            following_ends: VecDeque::new(),
+        });
+        if self.state == ConnectionState::Connecting || self.corked {
             return Ok(());
         }
 
         self.handle_write(poller, start_writing)
     }
 
-    pub(crate) fn stream_mut(&mut self) -> &mut JsonlStream<TcpStream> {
-        &mut self.stream
+    /// Appends the next chunk of the current chunked message to the write buffer.
+    fn produce_chunk(&mut self) -> serde_json::Result<()> {
+        let Some(message) = self.chunked.front_mut() else {
+            return Ok(());
+        };
+        let old_len = self.write_buf.len();
+        let done = (message.produce)(&mut self.write_buf)
+            .map_err(|e| serde_json::Error::io(std::io::Error::other(e)))?;
+        message.size += self.write_buf.len() - old_len;
+        if self.write_buf[old_len..].contains(&b'\n') {
+            return Err(serde_json::Error::io(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Chunked message must not contain newlines",
+            )));
+        }
+        if message.size > self.max_outgoing_line_size {
+            return Err(serde_json::Error::io(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Chunked message size limit exceeded",
+            )));
+        }
+        if done {
+            let message = self.chunked.pop_front().expect("unreachable");
+            self.write_buf.push(b'\n');
+            self.write_msg_ends.push_back(self.write_buf.len());
+            let base = self.write_buf.len();
+            self.write_buf.extend_from_slice(&message.following);
+            self.write_msg_ends
+                .extend(message.following_ends.into_iter().map(|end| base + end));
+        }
+        Ok(())
+    }
+
+    /// Attempts to write the queued data to the stream immediately (even if the connection is corked).
+    ///
+    /// Data that cannot be written without blocking remains queued and is sent when the stream becomes writable.
+    pub fn flush(&mut self, poller: &mut Poll) -> serde_json::Result<()> {
+        self.check_not_closed()?;
+        if self.state == ConnectionState::Connecting || !self.has_pending_writes() {
+            return Ok(());
+        }
+        self.handle_write(poller, true)
+    }
+
+    pub(crate) fn flush_buf(&mut self) -> serde_json::Result<()> {
+        let result = self.write_queued_data();
+        self.update_write_buffer_level();
+        result
+    }
+
+    fn write_queued_data(&mut self) -> serde_json::Result<()> {
+        let mut produced = false;
+        loop {
+            self.write_buf_data()?;
+            if produced || self.chunked.is_empty() {
+                return Ok(());
+            }
+            // Produces at most one chunk per call so that a huge message does not block the event loop.
+            self.produce_chunk()?;
+            produced = true;
+        }
+    }
+
+    fn write_buf_data(&mut self) -> serde_json::Result<()> {
+        while self.write_buf_offset < self.write_buf.len() {
+            let result = self.stream.write(&self.write_buf[self.write_buf_offset..]);
+            if result
+                .as_ref()
+                .is_err_and(|e| e.kind() == ErrorKind::WouldBlock)
+            {
+                self.write_stalled_since.get_or_insert_with(Instant::now);
+            }
+            let written_size = result.map_err(serde_json::Error::io)?;
+            if written_size == 0 {
+                return Err(serde_json::Error::io(ErrorKind::WriteZero.into()));
+            }
+            self.write_buf_offset += written_size;
+            while self
+                .write_msg_ends
+                .front()
+                .is_some_and(|&end| end <= self.write_buf_offset)
+            {
+                self.write_msg_ends.pop_front();
+            }
+            self.sent_bytes += written_size as u64;
+            self.last_write_at = Instant::now();
+            self.write_stalled_since = None;
+        }
+
+        let used = self.write_buf.len();
+        self.write_buf.clear();
+        self.write_buf_offset = 0;
+        self.write_msg_ends.clear();
+        shrink_buf(
+            &mut self.write_buf,
+            used,
+            self.max_retained_buf_size,
+            self.buf_shrink_delay,
+            &mut self.write_buf_underused_since,
+        );
+
+        Ok(())
+    }
+
+    fn cache_addrs(&mut self) {
+        let Some(stream) = self.stream.as_tcp() else {
+            return;
+        };
+        self.peer_addr = stream.peer_addr().ok().or(self.peer_addr);
+        self.local_addr = stream.local_addr().ok().or(self.local_addr);
+    }
+
+    pub(crate) fn is_established(&self) -> bool {
+        matches!(
+            self.state,
+            ConnectionState::Connected | ConnectionState::ShuttingDown
+        )
     }
 
     fn check_not_closed(&mut self) -> serde_json::Result<()> {
@@ -130,41 +979,42 @@ impl Connection {
 
     fn handle_connect(&mut self, poller: &mut Poll) -> serde_json::Result<()> {
         // See: https://docs.rs/mio/1.0.2/mio/net/struct.TcpStream.html#method.connect
-        self.stream
-            .inner()
-            .take_error()
-            .map_err(serde_json::Error::io)?;
-        match self.stream.inner().peer_addr() {
-            Err(e) if e.kind() == ErrorKind::NotConnected => return Ok(()),
+        self.stream.take_error().map_err(serde_json::Error::io)?;
+        match self.stream.is_connected() {
             Err(e) => return self.handle_error(poller, serde_json::Error::io(e)),
-            Ok(_) => {}
+            Ok(false) => return Ok(()),
+            Ok(true) => {}
         }
 
-        self.state = ConnectionState::Connected;
+        self.set_state(ConnectionState::Connected);
+        if self.shutdown_requested {
+            self.set_state(ConnectionState::ShuttingDown);
+        }
+        self.cache_addrs();
         self.handle_write(poller, false)?;
 
         Ok(())
     }
 
     fn handle_write(&mut self, poller: &mut Poll, start_writing: bool) -> serde_json::Result<()> {
-        let result = match self.stream.flush() {
+        let result = match self.flush_buf() {
             Err(e) if e.io_error_kind() == Some(ErrorKind::WouldBlock) => {
                 if start_writing {
-                    let interests = Interest::READABLE | Interest::WRITABLE;
-                    poller
-                        .registry()
-                        .reregister(self.stream.inner_mut(), self.token, interests)
+                    self.update_interests(poller, true)
                         .map_err(serde_json::Error::io)
                 } else {
                     Ok(())
                 }
             }
             Err(e) => Err(e),
+            Ok(_) if !self.chunked.is_empty() => {
+                // Re-arms the writable event to produce the next chunk in the next poll iteration.
+                self.update_interests(poller, true)
+                    .map_err(serde_json::Error::io)
+            }
             Ok(_) => {
                 if self.queued_bytes_len() == 0 && !start_writing {
-                    poller
-                        .registry()
-                        .reregister(self.stream.inner_mut(), self.token, Interest::READABLE)
+                    self.update_interests(poller, false)
                         .map_err(serde_json::Error::io)
                 } else {
                     Ok(())
@@ -182,7 +1032,153 @@ impl Connection {
         if error.io_error_kind() == Some(ErrorKind::WouldBlock) {
             return Ok(());
         }
-        self.close(poller);
+        if is_message_too_large_error(&error) {
+            return Err(error);
+        }
+        self.close_with_error(poller, &error);
         Err(error)
     }
 }
+
+type ChunkProducer = Box<dyn Send + FnMut(&mut dyn Write) -> std::io::Result<bool>>;
+
+struct ChunkedMessage {
+    produce: ChunkProducer,
+    size: usize,
+    following: Vec<u8>,
+    following_ends: VecDeque<usize>,
+}
+
+impl std::fmt::Debug for ChunkedMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChunkedMessage")
+            .field("size", &self.size)
+            .field("following", &self.following.len())
+            .finish_non_exhaustive()
+    }
+}
+
+struct WatermarkObserver(Box<dyn Send + FnMut(WriteBufferLevel)>);
+
+impl std::fmt::Debug for WatermarkObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("WatermarkObserver").finish_non_exhaustive()
+    }
+}
+
+struct StateObserver(Box<dyn Send + FnMut(ConnectionState, ConnectionState)>);
+
+impl std::fmt::Debug for StateObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("StateObserver").finish_non_exhaustive()
+    }
+}
+
+/// Writer that fails once the buffer would exceed `limit` bytes.
+struct LimitedWriter<'a> {
+    buf: &'a mut Vec<u8>,
+    limit: usize,
+}
+
+impl Write for LimitedWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if self.buf.len() + data.len() > self.limit {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Shrinks a drained buffer to `max_capacity` once it has not needed more than that for `delay`.
+///
+/// `used` is the number of bytes the buffer held before it was drained and
+/// `underused_since` tracks when the buffer last needed more than `max_capacity` bytes.
+fn shrink_buf(
+    buf: &mut Vec<u8>,
+    used: usize,
+    max_capacity: usize,
+    delay: Duration,
+    underused_since: &mut Option<Instant>,
+) {
+    if buf.capacity() <= max_capacity {
+        *underused_since = None;
+        return;
+    }
+
+    let now = Instant::now();
+    if used > max_capacity {
+        *underused_since = Some(now);
+    }
+    let since = *underused_since.get_or_insert(now);
+    if now.duration_since(since) >= delay {
+        buf.shrink_to(max_capacity);
+        *underused_since = None;
+    }
+}
+
+/// Returns the error that [`Connection::read_line()`] returns when a line exceeds the size limit.
+pub(crate) fn line_too_large_error() -> serde_json::Error {
+    size_limit_error(ErrorKind::InvalidData, SizeLimit::Line)
+}
+
+pub(crate) fn is_line_too_large_error(error: &serde_json::Error) -> bool {
+    exceeded_size_limit(error) == Some(SizeLimit::Line)
+}
+
+/// Returns the error that sending returns when a message exceeds the outgoing size limit.
+pub(crate) fn message_too_large_error() -> serde_json::Error {
+    size_limit_error(ErrorKind::InvalidInput, SizeLimit::Message)
+}
+
+pub(crate) fn is_message_too_large_error(error: &serde_json::Error) -> bool {
+    exceeded_size_limit(error) == Some(SizeLimit::Message)
+}
+
+fn size_limit_error(kind: ErrorKind, limit: SizeLimit) -> serde_json::Error {
+    serde_json::Error::io(std::io::Error::new(kind, SizeLimitExceeded(limit)))
+}
+
+fn exceeded_size_limit(error: &serde_json::Error) -> Option<SizeLimit> {
+    // `serde_json::Error::source()` returns the source of the I/O error's payload (i.e., `SizeLimit`),
+    // which distinguishes these errors from those of the underlying stream with the same kinds.
+    std::error::Error::source(error)?
+        .downcast_ref::<SizeLimit>()
+        .copied()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SizeLimit {
+    Line,
+    Message,
+}
+
+impl std::fmt::Display for SizeLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Line => write!(f, "Line size limit exceeded"),
+            Self::Message => write!(f, "Message size limit exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for SizeLimit {}
+
+#[derive(Debug)]
+struct SizeLimitExceeded(SizeLimit);
+
+impl std::fmt::Display for SizeLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for SizeLimitExceeded {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
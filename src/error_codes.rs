@@ -0,0 +1,18 @@
+//! Implementation-defined JSON-RPC error codes used by this crate.
+use jsonlrpc::ErrorCode;
+
+/// The server cannot accept more connections or requests at the moment.
+pub const SERVER_OVERLOADED: ErrorCode = ErrorCode::new(-32000);
+
+/// The message exceeds the maximum size accepted by the server.
+pub const PAYLOAD_TOO_LARGE: ErrorCode = ErrorCode::new(-32001);
+
+/// The response did not arrive within the timeout set on the client.
+///
+/// Responses with this code are generated locally by [`RpcClient`](crate::RpcClient).
+pub const REQUEST_TIMEOUT: ErrorCode = ErrorCode::new(-32002);
+
+/// The connection was lost before the response arrived.
+///
+/// Responses with this code are generated locally by [`RpcClient`](crate::RpcClient).
+pub const CONNECTION_CLOSED: ErrorCode = ErrorCode::new(-32003);
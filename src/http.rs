@@ -0,0 +1,146 @@
+use std::io::ErrorKind;
+
+const MAX_HEADER_LEN: usize = 16 * 1024;
+
+/// Builds an HTTP/1.1 POST request carrying `body` as a JSON payload.
+pub(crate) fn build_request(path: &str, host: &str, body: &[u8]) -> Vec<u8> {
+    let mut request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\nAccept: application/json\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+    request
+}
+
+/// Parses an HTTP/1.1 response at the beginning of `buf`.
+///
+/// Returns `Ok(None)` if more data is needed.
+/// Otherwise, returns the length of the response and its body
+/// (empty for informational responses and responses without content).
+/// Bodies larger than `max_body_size` are rejected before they are completely received.
+pub(crate) fn parse_response(
+    buf: &[u8],
+    max_body_size: usize,
+) -> std::io::Result<Option<(usize, Vec<u8>)>> {
+    let Some(header_end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        if buf.len() > MAX_HEADER_LEN {
+            return Err(invalid_response_error("Too large HTTP response header"));
+        }
+        return Ok(None);
+    };
+    let header = std::str::from_utf8(&buf[..header_end])
+        .map_err(|_| invalid_response_error("Non UTF-8 HTTP response header"))?;
+    let body_start = header_end + 4;
+
+    let mut lines = header.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    let status = match status_line.split(' ').collect::<Vec<_>>().as_slice() {
+        [version, status, ..] if version.starts_with("HTTP/1.") => status
+            .parse::<u16>()
+            .map_err(|_| invalid_response_error("Invalid HTTP status code"))?,
+        _ => return Err(invalid_response_error("Invalid HTTP status line")),
+    };
+    if (100..200).contains(&status) || status == 204 || status == 304 {
+        return Ok(Some((body_start, Vec::new())));
+    }
+
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(invalid_response_error("Invalid HTTP header"));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            let length = value
+                .parse::<usize>()
+                .map_err(|_| invalid_response_error("Invalid Content-Length header"))?;
+            content_length = Some(length);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+    }
+
+    if chunked {
+        return parse_chunked_body(buf, body_start, max_body_size);
+    }
+    let Some(length) = content_length else {
+        return Err(invalid_response_error("Missing Content-Length header"));
+    };
+    if length > max_body_size {
+        return Err(too_large_body_error());
+    }
+    let end = body_start
+        .checked_add(length)
+        .ok_or_else(too_large_body_error)?;
+    let Some(body) = buf.get(body_start..end) else {
+        return Ok(None);
+    };
+    Ok(Some((end, body.to_vec())))
+}
+
+fn parse_chunked_body(
+    buf: &[u8],
+    mut offset: usize,
+    max_body_size: usize,
+) -> std::io::Result<Option<(usize, Vec<u8>)>> {
+    let mut body = Vec::new();
+    loop {
+        let Some(line_len) = find_line(&buf[offset..])? else {
+            return Ok(None);
+        };
+        let line = std::str::from_utf8(&buf[offset..offset + line_len])
+            .map_err(|_| invalid_response_error("Invalid chunk size"))?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| invalid_response_error("Invalid chunk size"))?;
+        offset += line_len + 2;
+
+        if size == 0 {
+            // Skips trailer fields until the terminating empty line.
+            loop {
+                let Some(line_len) = find_line(&buf[offset..])? else {
+                    return Ok(None);
+                };
+                offset += line_len + 2;
+                if line_len == 0 {
+                    return Ok(Some((offset, body)));
+                }
+            }
+        }
+
+        if size > max_body_size - body.len() {
+            return Err(too_large_body_error());
+        }
+        let end = offset
+            .checked_add(size)
+            .and_then(|end| end.checked_add(2))
+            .ok_or_else(too_large_body_error)?;
+        let Some(chunk) = buf.get(offset..end) else {
+            return Ok(None);
+        };
+        if !chunk.ends_with(b"\r\n") {
+            return Err(invalid_response_error("Invalid chunk"));
+        }
+        body.extend_from_slice(&chunk[..size]);
+        offset += size + 2;
+    }
+}
+
+/// Returns the length of the line (excluding CRLF) at the beginning of `buf`, or `Ok(None)` if it is incomplete.
+fn find_line(buf: &[u8]) -> std::io::Result<Option<usize>> {
+    match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(len) => Ok(Some(len)),
+        None if buf.len() > MAX_HEADER_LEN => Err(invalid_response_error("Too long HTTP line")),
+        None => Ok(None),
+    }
+}
+
+fn too_large_body_error() -> std::io::Error {
+    invalid_response_error("Too large HTTP response body")
+}
+
+fn invalid_response_error(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
@@ -9,7 +9,7 @@
 //! use std::net::SocketAddr;
 //!
 //! use jsonlrpc::{RequestId, RequestObject, ResponseObject};
-//! use jsonlrpc_mio::{RpcClient, RpcServer};
+//! use jsonlrpc_mio::{Incoming, RpcClient, RpcServer};
 //! use mio::{Events, Poll, Token};
 //!
 //! # fn main() -> std::io::Result<()> {
@@ -36,7 +36,7 @@
 //!     poller.poll(&mut events, None)?;
 //!     for event in events.iter() {
 //!         server.handle_event(&mut poller, event)?;
-//!         if let Some((from, request)) = server.try_recv() {
+//!         if let Some((from, Incoming::Call(request))) = server.try_recv() {
 //!             assert_eq!(request.method, "ping");
 //!             let response = ResponseObject::Ok {
 //!                 jsonrpc: jsonlrpc::JsonRpcVersion::V2,
@@ -59,15 +59,40 @@
 #![warn(missing_docs)]
 mod client;
 mod connection;
+pub mod error_codes;
+mod http;
+mod pool;
+mod probe;
+mod proxy;
+mod raw;
+mod router;
 mod server;
+mod slab;
+mod socket;
+mod stream;
+mod websocket;
 
-pub use self::client::RpcClient;
-pub use self::connection::{Connection, ConnectionState};
-pub use self::server::{ClientId, RpcServer};
+pub use self::client::{
+    ClientError, ClientEvent, ClientMessage, ClientState, ClientStats, RetryPolicy, RpcClient,
+    RpcClientBuilder, RpcClientHandle, RpcClientReceiver, RpcClientSender,
+};
+pub use self::connection::{Connection, ConnectionState, WriteBufferLevel};
+pub use self::pool::RpcClientPool;
+pub use self::raw::RawRequest;
+pub use self::router::Router;
+pub use self::server::{
+    AcceptRejectReason, ClientId, DisconnectReason, Incoming, ParseErrorPolicy, ReplyStatus,
+    RequestContext, RpcServer, RpcServerBuilder, ServerEvent, ServerStats,
+};
+pub use self::stream::Stream;
 
 #[cfg(test)]
 mod tests {
-    use std::{net::SocketAddr, time::Duration};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
 
     use jsonlrpc::{ErrorCode, RequestId, RequestObject, ResponseObject};
     use mio::{Events, Poll, Token};
@@ -109,7 +134,7 @@ mod tests {
                 .or_fail()?;
             for event in events.iter() {
                 server.handle_event(&mut poller, event).or_fail()?;
-                if let Some((from, request)) = server.try_recv() {
+                if let Some((from, Incoming::Call(request))) = server.try_recv() {
                     assert_eq!(request.method, "ping");
                     let response = ResponseObject::Ok {
                         jsonrpc: jsonlrpc::JsonRpcVersion::V2,
@@ -161,7 +186,7 @@ mod tests {
             poller.poll(&mut events, None)?;
             for event in events.iter() {
                 server.handle_event(&mut poller, event)?;
-                if let Some((from, request)) = server.try_recv() {
+                if let Some((from, Incoming::Call(request))) = server.try_recv() {
                     assert_eq!(request.method, "ping");
                     let response = ResponseObject::Ok {
                         jsonrpc: jsonlrpc::JsonRpcVersion::V2,
@@ -222,4 +247,5532 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn batch_request() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let server_addr = server.listen_addr();
+        let client = std::thread::spawn(move || -> orfail::Result<serde_json::Value> {
+            let stream = std::net::TcpStream::connect(server_addr).or_fail()?;
+            let mut stream = jsonlrpc::JsonlStream::new(stream);
+            let batch = serde_json::json!([
+                {"jsonrpc": "2.0", "id": 1, "method": "ping"},
+                {"jsonrpc": "2.0", "method": "notify"},
+                {"jsonrpc": "2.0", "id": 2, "method": "ping"},
+                "invalid"
+            ]);
+            stream.write_value(&batch).or_fail()?;
+            stream.read_value().or_fail()
+        });
+
+        let mut requests = 0;
+        let mut notifications = 0;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                let received = server.drain_requests().collect::<Vec<_>>();
+                assert_eq!(server.queued_requests_len(), 0);
+                for (from, incoming) in received {
+                    requests += 1;
+                    let Incoming::Call(request) = incoming else {
+                        notifications += 1;
+                        continue;
+                    };
+                    let id = request.id.or_fail()?;
+                    if id == RequestId::Number(1) {
+                        server.reply_ok(&mut poller, from, id, "pong").or_fail()?;
+                    } else {
+                        let code = ErrorCode::INTERNAL_ERROR;
+                        server
+                            .reply_err(&mut poller, from, id, code, "failed")
+                            .or_fail()?;
+                    }
+                }
+            }
+            if client.is_finished() {
+                break;
+            }
+        }
+        assert_eq!(requests, 3);
+        assert_eq!(notifications, 1);
+
+        let response = client.join().expect("client thread panicked").or_fail()?;
+        let responses: Vec<ResponseObject> = serde_json::from_value(response).or_fail()?;
+        assert_eq!(responses.len(), 3);
+        assert!(responses
+            .iter()
+            .any(|r| r.id() == Some(&RequestId::Number(1))));
+        assert!(responses.iter().any(|r| matches!(
+            r,
+            ResponseObject::Err { id: Some(RequestId::Number(2)), error, .. }
+                if error.code == ErrorCode::INTERNAL_ERROR
+        )));
+        assert!(responses.iter().any(|r| matches!(
+            r,
+            ResponseObject::Err { error, .. } if error.code == ErrorCode::INVALID_REQUEST
+        )));
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch_request_duplicate_ids() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let server_addr = server.listen_addr();
+        let client = std::thread::spawn(move || -> orfail::Result<serde_json::Value> {
+            let stream = std::net::TcpStream::connect(server_addr).or_fail()?;
+            let mut stream = jsonlrpc::JsonlStream::new(stream);
+            let batch = serde_json::json!([
+                {"jsonrpc": "2.0", "id": 1, "method": "foo"},
+                {"jsonrpc": "2.0", "id": 1, "method": "bar"}
+            ]);
+            stream.write_value(&batch).or_fail()?;
+            stream.read_value().or_fail()
+        });
+
+        let mut requests = 0;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    requests += 1;
+                    let method = incoming.request().method.clone();
+                    let id = incoming.request().id.clone().or_fail()?;
+                    server.reply_ok(&mut poller, from, id, method).or_fail()?;
+                }
+            }
+            if client.is_finished() {
+                break;
+            }
+        }
+        assert_eq!(requests, 2);
+
+        // Both responses are delivered in a single batch.
+        let response = client.join().expect("client thread panicked").or_fail()?;
+        let responses: Vec<ResponseObject> = serde_json::from_value(response).or_fail()?;
+        let mut results = responses
+            .into_iter()
+            .map(|r| {
+                assert_eq!(r.id(), Some(&RequestId::Number(1)));
+                r.into_std_result().ok()
+            })
+            .collect::<Vec<_>>();
+        results.sort_by_key(|r| r.as_ref().map(|v| v.to_string()));
+        assert_eq!(
+            results,
+            [
+                Some(serde_json::json!("bar")),
+                Some(serde_json::json!("foo"))
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .backlog(16)
+            .nodelay(false)
+            .start(&mut poller)
+            .or_fail()?;
+        assert_ne!(server.listen_addr().port(), 0);
+        assert!(std::net::TcpStream::connect(server.listen_addr()).is_ok());
+        assert!(!server.is_shutdown());
+
+        server.shutdown(&mut poller);
+        assert!(server.is_shutdown());
+        assert_eq!(0, server.connections().count());
+        assert!(std::net::TcpStream::connect(server.listen_addr()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_events() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .events(true)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+            stream
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"hello\"}\n")
+                .or_fail()?;
+            streams.push(stream);
+        }
+
+        let mut clients = Vec::new();
+        for _ in 0..20 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            while let Some((from, _)) = server.try_recv() {
+                clients.push(from);
+            }
+            if clients.len() == 2 {
+                break;
+            }
+        }
+        assert_eq!(clients.len(), 2);
+
+        server.shutdown(&mut poller);
+        let mut disconnected = Vec::new();
+        while let Some(event) = server.try_recv_event() {
+            if let ServerEvent::Disconnected { client, reason } = event {
+                assert_eq!(reason, DisconnectReason::Shutdown);
+                disconnected.push(client);
+            }
+        }
+        clients.sort();
+        disconnected.sort();
+        assert_eq!(disconnected, clients);
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_connections() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+        server.set_max_connections(0);
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        client.send(&mut poller, &"ping").or_fail()?;
+
+        let mut success = false;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                let _ = client.handle_event(&mut poller, event);
+                if let Some(response) = client.try_recv() {
+                    let ResponseObject::Err { error, .. } = response else {
+                        panic!("{response:?}");
+                    };
+                    assert_eq!(error.code, error_codes::SERVER_OVERLOADED);
+                    success = true;
+                    break 'root;
+                }
+            }
+        }
+        assert!(success);
+        assert_eq!(0, server.connections().count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn server_notification() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let server_addr = server.listen_addr();
+        let client = std::thread::spawn(move || -> orfail::Result<RequestObject> {
+            let stream = std::net::TcpStream::connect(server_addr).or_fail()?;
+            let mut stream = jsonlrpc::JsonlStream::new(stream);
+            let subscribe = serde_json::json!({"jsonrpc": "2.0", "method": "subscribe"});
+            stream.write_value(&subscribe).or_fail()?;
+            stream.read_value().or_fail()
+        });
+
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, incoming)) = server.try_recv() {
+                    assert!(incoming.is_notification());
+                    let notified = server
+                        .notify(&mut poller, from, "updated", None)
+                        .or_fail()?;
+                    assert!(notified);
+                }
+            }
+            if client.is_finished() {
+                break;
+            }
+        }
+
+        let notification = client.join().expect("client thread panicked").or_fail()?;
+        assert_eq!(notification.method, "updated");
+        assert_eq!(notification.id, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn disconnect() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let server_addr = server.listen_addr();
+        let client = std::thread::spawn(move || -> orfail::Result<usize> {
+            let mut stream = std::net::TcpStream::connect(server_addr).or_fail()?;
+            stream
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"hello\"}\n")
+                .or_fail()?;
+            stream.read(&mut [0; 16]).or_fail()
+        });
+
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, _)) = server.try_recv() {
+                    assert!(server.disconnect(&mut poller, from));
+                    assert!(!server.disconnect(&mut poller, from));
+                }
+            }
+            if client.is_finished() {
+                break;
+            }
+        }
+
+        let read_size = client.join().expect("client thread panicked").or_fail()?;
+        assert_eq!(read_size, 0);
+        assert_eq!(0, server.connections().count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn stale_client_id() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            Token(0),
+            Token(1),
+        )
+        .or_fail()?;
+
+        let mut client_ids = Vec::new();
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+            stream
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"hello\"}\n")
+                .or_fail()?;
+
+            let mut client_id = None;
+            for _ in 0..10 {
+                poller
+                    .poll(&mut events, Some(Duration::from_millis(100)))
+                    .or_fail()?;
+                for event in events.iter() {
+                    server.handle_event(&mut poller, event).or_fail()?;
+                }
+                if let Some((from, _)) = server.try_recv() {
+                    client_id = Some(from);
+                    break;
+                }
+            }
+            let client_id = client_id.or_fail()?;
+            if client_ids.is_empty() {
+                assert!(server.disconnect(&mut poller, client_id));
+            }
+            client_ids.push(client_id);
+            streams.push(stream);
+        }
+
+        let (old, new) = (client_ids[0], client_ids[1]);
+        assert_eq!(old.token(), new.token());
+        assert_ne!(old, new);
+        assert!(!server.notify(&mut poller, old, "hello", None).or_fail()?);
+        assert!(!server.disconnect(&mut poller, old));
+        assert!(server.notify(&mut poller, new, "hello", None).or_fail()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn router() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+        let mut router = Router::new();
+        router.route("sum", |params: Vec<i64>| Ok(params.iter().sum::<i64>()));
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        for (id, method, params) in [(1, "sum", "[1, 2]"), (2, "sum", "{}"), (3, "mul", "[]")] {
+            let request = RequestObject {
+                jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+                method: method.to_owned(),
+                params: Some(params.parse::<jsonlrpc::RequestParams>().or_fail()?),
+                id: Some(RequestId::Number(id)),
+            };
+            client.send(&mut poller, &request).or_fail()?;
+        }
+
+        let mut responses = Vec::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                server.dispatch(&mut poller, &mut router).or_fail()?;
+
+                client.handle_event(&mut poller, event).or_fail()?;
+                while let Some(response) = client.try_recv() {
+                    responses.push(response.into_std_result().map_err(|e| e.code));
+                }
+            }
+            if responses.len() == 3 {
+                break;
+            }
+        }
+        assert_eq!(
+            responses,
+            [
+                Ok(serde_json::json!(3)),
+                Err(ErrorCode::INVALID_PARAMS),
+                Err(ErrorCode::METHOD_NOT_FOUND)
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn accept_filter() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+        let refused = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let refused_clone = refused.clone();
+        server.set_accept_filter(move |addr| {
+            assert!(addr.ip().is_loopback());
+            refused_clone.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            false
+        });
+
+        let _stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if refused.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+                break;
+            }
+        }
+        assert_eq!(refused.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(0, server.connections().count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_listeners() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .add_listen_addr(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .start(&mut poller)
+            .or_fail()?;
+        let listen_addrs = server.listen_addrs().collect::<Vec<_>>();
+        assert_eq!(listen_addrs.len(), 2);
+        assert_eq!(listen_addrs[0], server.listen_addr());
+
+        let mut streams = Vec::new();
+        for addr in listen_addrs {
+            let mut stream = std::net::TcpStream::connect(addr).or_fail()?;
+            stream
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"hello\"}\n")
+                .or_fail()?;
+            streams.push(stream);
+        }
+
+        let mut received = 0;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            received += server.drain_requests().count();
+            if received == 2 {
+                break;
+            }
+        }
+        assert_eq!(received, 2);
+        assert_eq!(2, server.connections().count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn pause_accepting() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+        server.pause_accepting(&mut poller).or_fail()?;
+        assert!(!server.is_accepting());
+
+        let _stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        for accepting in [false, true] {
+            if accepting {
+                server.resume_accepting(&mut poller).or_fail()?;
+                assert!(server.is_accepting());
+            }
+            for _ in 0..3 {
+                poller
+                    .poll(&mut events, Some(Duration::from_millis(50)))
+                    .or_fail()?;
+                for event in events.iter() {
+                    server.handle_event(&mut poller, event).or_fail()?;
+                }
+            }
+            assert_eq!(usize::from(accepting), server.connections().count());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn idle_timeout() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .idle_timeout(Some(Duration::from_millis(50)))
+            .events(true)
+            .start(&mut poller)
+            .or_fail()?;
+        assert_eq!(server.next_timeout(), None);
+
+        let _stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        let mut event = None;
+        for _ in 0..10 {
+            let timeout = server.next_timeout().unwrap_or(Duration::from_millis(100));
+            poller.poll(&mut events, Some(timeout)).or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            server.handle_timeout(&mut poller);
+            event = server.try_recv_event();
+            if event.is_some() {
+                break;
+            }
+        }
+        let Some(ServerEvent::Disconnected { reason, .. }) = event else {
+            panic!("{event:?}");
+        };
+        assert_eq!(reason, DisconnectReason::IdleTimeout);
+        assert_eq!(0, server.connections().count());
+        assert_eq!(server.next_timeout(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn server_initiated_request() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let server_addr = server.listen_addr();
+        let client = std::thread::spawn(move || -> orfail::Result<()> {
+            let stream = std::net::TcpStream::connect(server_addr).or_fail()?;
+            let mut stream = jsonlrpc::JsonlStream::new(stream);
+            let hello = serde_json::json!({"jsonrpc": "2.0", "method": "hello"});
+            stream.write_value(&hello).or_fail()?;
+
+            let request: RequestObject = stream.read_value().or_fail()?;
+            assert_eq!(request.method, "whoami");
+            let response = ResponseObject::Ok {
+                jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+                result: serde_json::json! { "client" },
+                id: request.id.or_fail()?,
+            };
+            stream.write_value(&response).or_fail()?;
+            Ok(())
+        });
+
+        let mut request_id = None;
+        let mut response = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, _)) = server.try_recv() {
+                    request_id = server.call(&mut poller, from, "whoami", None).or_fail()?;
+                }
+            }
+            response = server.try_recv_response();
+            if response.is_some() {
+                break;
+            }
+        }
+        client.join().expect("client thread panicked").or_fail()?;
+
+        let (_, response) = response.or_fail()?;
+        assert_eq!(response.id(), request_id.as_ref());
+        assert_eq!(
+            response.into_std_result().ok(),
+            Some(serde_json::json! { "client" })
+        );
+        assert_eq!(None, server.try_recv());
+
+        Ok(())
+    }
+
+    #[test]
+    fn slow_consumer() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .max_write_buf_size(1024)
+            .events(true)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"hello\"}\n")
+            .or_fail()?;
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            from = server.try_recv().map(|(from, _)| from);
+            if from.is_some() {
+                break;
+            }
+        }
+        let from = from.or_fail()?;
+
+        let params = jsonlrpc::RequestParams::Array(vec![serde_json::json!("a".repeat(64 * 1024))]);
+        let mut sent = 0;
+        while server
+            .notify(&mut poller, from, "data", Some(params.clone()))
+            .or_fail()?
+        {
+            sent += 1;
+            assert!(sent < 10000);
+        }
+        assert_eq!(
+            server.try_recv_event(),
+            Some(ServerEvent::Disconnected {
+                client: from,
+                reason: DisconnectReason::WriteBufferFull
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_method() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .methods(["ping"])
+            .start(&mut poller)
+            .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let request = RequestObject {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            method: "pong".to_owned(),
+            params: None,
+            id: Some(RequestId::Number(1)),
+        };
+        client.send(&mut poller, &request).or_fail()?;
+
+        let mut success = false;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                assert_eq!(None, server.try_recv());
+
+                client.handle_event(&mut poller, event).or_fail()?;
+                if let Some(response) = client.try_recv() {
+                    assert_eq!(response.id(), Some(&RequestId::Number(1)));
+                    let ResponseObject::Err { error, .. } = response else {
+                        panic!("{response:?}");
+                    };
+                    assert_eq!(error.code, ErrorCode::METHOD_NOT_FOUND);
+                    success = true;
+                    break 'root;
+                }
+            }
+        }
+        assert!(success);
+
+        Ok(())
+    }
+
+    #[test]
+    fn close_on_parse_error() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .parse_error_policy(ParseErrorPolicy::Close)
+            .events(true)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let server_addr = server.listen_addr();
+        let client = std::thread::spawn(move || -> orfail::Result<Vec<u8>> {
+            let mut stream = std::net::TcpStream::connect(server_addr).or_fail()?;
+            stream.write_all(b"not json\n").or_fail()?;
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).or_fail()?;
+            Ok(buf)
+        });
+
+        let mut event = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            event = server.try_recv_event();
+            if event.is_some() {
+                break;
+            }
+        }
+        let Some(ServerEvent::Disconnected { reason, .. }) = event else {
+            panic!("{event:?}");
+        };
+        assert_eq!(reason, DisconnectReason::InvalidMessage);
+
+        let buf = client.join().expect("client thread panicked").or_fail()?;
+        let response: ResponseObject = serde_json::from_slice(&buf).or_fail()?;
+        let ResponseObject::Err { error, .. } = response else {
+            panic!("{response:?}");
+        };
+        assert_eq!(error.code, ErrorCode::PARSE_ERROR);
+
+        Ok(())
+    }
+
+    #[test]
+    fn too_large_request() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .max_request_size(16)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let server_addr = server.listen_addr();
+        let client = std::thread::spawn(move || -> orfail::Result<Vec<u8>> {
+            let mut stream = std::net::TcpStream::connect(server_addr).or_fail()?;
+            stream
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"hello\"}\n")
+                .or_fail()?;
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).or_fail()?;
+            Ok(buf)
+        });
+
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if client.is_finished() {
+                break;
+            }
+        }
+        assert_eq!(None, server.try_recv());
+        assert_eq!(0, server.connections().count());
+
+        let buf = client.join().expect("client thread panicked").or_fail()?;
+        let response: ResponseObject = serde_json::from_slice(&buf).or_fail()?;
+        let ResponseObject::Err { error, .. } = response else {
+            panic!("{response:?}");
+        };
+        assert_eq!(error.code, error_codes::PAYLOAD_TOO_LARGE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stats() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+        assert_eq!(server.stats(), ServerStats::default());
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let request = RequestObject {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            method: "ping".to_owned(),
+            params: None,
+            id: Some(RequestId::Number(1)),
+        };
+        client.send(&mut poller, &request).or_fail()?;
+
+        let mut success = false;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, Incoming::Call(request))) = server.try_recv() {
+                    let id = request.id.or_fail()?;
+                    server.reply_ok(&mut poller, from, id, "pong").or_fail()?;
+                }
+
+                client.handle_event(&mut poller, event).or_fail()?;
+                if client.try_recv().is_some() {
+                    success = true;
+                    break 'root;
+                }
+            }
+        }
+        assert!(success);
+
+        let stats = server.stats();
+        assert_eq!(stats.active_connections, 1);
+        assert_eq!(stats.accepted_connections, 1);
+        assert_eq!(stats.closed_connections, 0);
+        assert_eq!(stats.received_requests, 1);
+        assert_eq!(stats.sent_responses, 1);
+        assert!(stats.received_bytes > 0);
+        assert!(stats.sent_bytes > 0);
+
+        let before = stats;
+        server.shutdown(&mut poller);
+        let stats = server.stats();
+        assert_eq!(stats.active_connections, 0);
+        assert_eq!(stats.closed_connections, 1);
+        assert_eq!(stats.received_bytes, before.received_bytes);
+        assert_eq!(stats.sent_bytes, before.sent_bytes);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reuse_port() -> orfail::Result<()> {
+        let mut poller0 = Poll::new().or_fail()?;
+        let mut poller1 = Poll::new().or_fail()?;
+
+        let server0: RpcServer = RpcServer::start_reuseport(
+            &mut poller0,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+        let server1: RpcServer = RpcServer::start_reuseport(
+            &mut poller1,
+            server0.listen_addr(),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+        assert_eq!(server0.listen_addr(), server1.listen_addr());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn unix_domain_socket() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let path = std::env::temp_dir().join(format!("jsonlrpc_mio-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut server: RpcServer = RpcServer::builder_unix(&path)
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .start(&mut poller)
+            .or_fail()?;
+        assert_eq!(server.unix_listen_paths().collect::<Vec<_>>(), [&path]);
+        assert_eq!(server.listen_addrs().count(), 0);
+
+        let client_path = path.clone();
+        let client = std::thread::spawn(move || -> orfail::Result<Vec<u8>> {
+            let mut stream = std::os::unix::net::UnixStream::connect(client_path).or_fail()?;
+            stream
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"ping\",\"id\":1}\n")
+                .or_fail()?;
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).or_fail()?;
+            Ok(buf)
+        });
+
+        let mut replied = false;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some((from, Incoming::Call(request))) = server.try_recv() {
+                assert_eq!(server.peer_addr(from), None);
+                let id = request.id.or_fail()?;
+                server.reply_ok(&mut poller, from, id, "pong").or_fail()?;
+                server.disconnect(&mut poller, from);
+                replied = true;
+                break;
+            }
+        }
+        assert!(replied);
+        server.shutdown(&mut poller);
+        let _ = std::fs::remove_file(&path);
+
+        let buf = client.join().expect("client thread panicked").or_fail()?;
+        let response: ResponseObject = serde_json::from_slice(&buf).or_fail()?;
+        assert_eq!(response.into_std_result(), Ok(serde_json::json!("pong")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn rebind() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+        let old_addr = server.listen_addr();
+
+        let mut old_client = RpcClient::new(CLIENT_TOKEN, old_addr);
+        old_client.send(&mut poller, &"ping").or_fail()?;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                old_client.handle_event(&mut poller, event).or_fail()?;
+            }
+            if server.connections().count() == 1 {
+                break;
+            }
+        }
+        assert_eq!(server.connections().count(), 1);
+
+        let new_addr = server
+            .rebind(&mut poller, SocketAddr::from(([127, 0, 0, 1], 0)))
+            .or_fail()?;
+        assert_ne!(new_addr, old_addr);
+        assert_eq!(server.listen_addr(), new_addr);
+        assert!(std::net::TcpStream::connect(old_addr).is_err());
+
+        let _new_client = std::net::TcpStream::connect(new_addr).or_fail()?;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if server.connections().count() == 2 {
+                break;
+            }
+        }
+        assert_eq!(server.connections().count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn set_token_range() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            Token(0),
+            Token(1),
+        )
+        .or_fail()?;
+
+        let mut clients = Vec::new();
+        let mut connect = |server: &mut RpcServer, poller: &mut Poll| -> orfail::Result<usize> {
+            clients.push(std::net::TcpStream::connect(server.listen_addr()).or_fail()?);
+            for _ in 0..5 {
+                poller
+                    .poll(&mut events, Some(Duration::from_millis(100)))
+                    .or_fail()?;
+                for event in events.iter() {
+                    server.handle_event(poller, event).or_fail()?;
+                }
+            }
+            Ok(server.connections().count())
+        };
+        assert_eq!(connect(&mut server, &mut poller)?, 1);
+        assert_eq!(connect(&mut server, &mut poller)?, 1);
+
+        assert!(server.set_token_range(Token(1), Token(2)).is_err());
+        let e = server
+            .set_token_range(Token(0), Token(usize::MAX))
+            .expect_err("overflow");
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(server.set_token_range(Token(0), Token(0)).is_ok());
+        server.set_token_range(Token(0), Token(2)).or_fail()?;
+        assert_eq!(connect(&mut server, &mut poller)?, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn request_context() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        let start = std::time::Instant::now();
+        stream
+            .write_all(
+                concat!(
+                    r#"{"jsonrpc":"2.0","method":"a","id":0}"#,
+                    "\n",
+                    r#"[{"jsonrpc":"2.0","method":"b","id":1},{"jsonrpc":"2.0","method":"c"}]"#,
+                    "\n"
+                )
+                .as_bytes(),
+            )
+            .or_fail()?;
+
+        let mut contexts = Vec::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            while let Some((context, incoming)) = server.try_recv_with_context() {
+                contexts.push((context, incoming.into_request().method));
+            }
+            if contexts.len() == 3 {
+                break;
+            }
+        }
+        assert_eq!(contexts.len(), 3);
+        for (i, (context, method)) in contexts.iter().enumerate() {
+            assert_eq!(context.sequence, i as u64);
+            assert_eq!(context.peer_addr, Some(stream.local_addr().or_fail()?));
+            assert_eq!(context.client, contexts[0].0.client);
+            assert!(context.received_at >= start);
+            assert_eq!(method, ["a", "b", "c"][i]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn reply_batch() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        for i in 0..3 {
+            let request = RequestObject {
+                jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+                method: "ping".to_owned(),
+                params: None,
+                id: Some(RequestId::Number(i)),
+            };
+            client.send(&mut poller, &request).or_fail()?;
+        }
+
+        let mut received = Vec::new();
+        let mut responses = Vec::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            received.extend(server.drain_requests());
+            if received.len() == 3 {
+                let from = received[0].0;
+                let replies = received
+                    .drain(..)
+                    .map(|(_, incoming)| ResponseObject::Ok {
+                        jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+                        result: serde_json::json!("pong"),
+                        id: incoming.into_request().id.expect("call"),
+                    })
+                    .collect::<Vec<_>>();
+                let status = server.reply_batch(&mut poller, from, &replies).or_fail()?;
+                assert_eq!(status, ReplyStatus::Sent);
+            }
+            while let Some(response) = client.try_recv() {
+                responses.push(response);
+            }
+            if responses.len() == 3 {
+                break;
+            }
+        }
+        let ids = responses
+            .iter()
+            .map(|r| r.id().cloned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            (0..3)
+                .map(|i| Some(RequestId::Number(i)))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(server.stats().sent_responses, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_in_flight_requests() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .max_in_flight_requests(1)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        for i in 0..3 {
+            let request = RequestObject {
+                jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+                method: "ping".to_owned(),
+                params: None,
+                id: Some(RequestId::Number(i)),
+            };
+            client.send(&mut poller, &request).or_fail()?;
+        }
+
+        let mut responses = 0;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            while let Some((from, Incoming::Call(request))) = server.try_recv() {
+                assert_eq!(server.queued_requests_len(), 0);
+                let id = request.id.or_fail()?;
+                server.reply_ok(&mut poller, from, id, "pong").or_fail()?;
+            }
+            while client.try_recv().is_some() {
+                responses += 1;
+            }
+            if responses == 3 {
+                break;
+            }
+        }
+        assert_eq!(responses, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reply_status() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .events(true)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let server_addr = server.listen_addr();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let client = std::thread::spawn(move || -> orfail::Result<usize> {
+            let mut stream = std::net::TcpStream::connect(server_addr).or_fail()?;
+            stream
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"a\",\"id\":0}\n")
+                .or_fail()?;
+            rx.recv().or_fail()?;
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).or_fail()?;
+            Ok(buf.len())
+        });
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some((client, _)) = server.try_recv() {
+                from = Some(client);
+                break;
+            }
+        }
+        let from = from.or_fail()?;
+
+        let large = "x".repeat(1024 * 1024);
+        let mut status = ReplyStatus::Sent;
+        for _ in 0..64 {
+            status = server
+                .reply_ok(&mut poller, from, RequestId::Number(0), &large)
+                .or_fail()?;
+            if status == ReplyStatus::Queued {
+                break;
+            }
+        }
+        assert_eq!(status, ReplyStatus::Queued);
+        tx.send(()).or_fail()?;
+
+        let mut flushed = false;
+        for _ in 0..50 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some(event) = server.try_recv_event() {
+                assert_eq!(event, ServerEvent::Flushed { client: from });
+                flushed = true;
+                break;
+            }
+        }
+        assert!(flushed);
+
+        server.disconnect(&mut poller, from);
+        let status = server
+            .reply_ok(&mut poller, from, RequestId::Number(0), "late")
+            .or_fail()?;
+        assert!(status.is_dropped());
+
+        let received = client.join().expect("client thread panicked").or_fail()?;
+        assert!(received > large.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_accepts_per_event() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .max_accepts_per_event(1)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let _clients = (0..3)
+            .map(|_| std::net::TcpStream::connect(server.listen_addr()))
+            .collect::<Result<Vec<_>, _>>()
+            .or_fail()?;
+
+        let mut counts = Vec::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                counts.push(server.connections().count());
+            }
+            if server.connections().count() == 3 {
+                break;
+            }
+        }
+        assert_eq!(counts.first(), Some(&1));
+        assert_eq!(server.connections().count(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn disconnect_gracefully() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .events(true)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let server_addr = server.listen_addr();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let reader = std::thread::spawn(move || -> orfail::Result<usize> {
+            let mut stream = std::net::TcpStream::connect(server_addr).or_fail()?;
+            stream
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"a\",\"id\":0}\n")
+                .or_fail()?;
+            rx.recv().or_fail()?;
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).or_fail()?;
+            Ok(buf.iter().filter(|b| **b == b'\n').count())
+        });
+        let mut stalled = std::net::TcpStream::connect(server_addr).or_fail()?;
+        stalled
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"b\",\"id\":0}\n")
+            .or_fail()?;
+
+        let mut clients = std::collections::HashMap::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            while let Some((from, incoming)) = server.try_recv() {
+                clients.insert(incoming.into_request().method, from);
+            }
+            if clients.len() == 2 {
+                break;
+            }
+        }
+        let (reader_id, stalled_id) = (clients["a"], clients["b"]);
+
+        let large = "x".repeat(1024 * 1024);
+        let mut replies = 0;
+        for client in [reader_id, stalled_id] {
+            for i in 0..64 {
+                let status = server
+                    .reply_ok(&mut poller, client, RequestId::Number(0), &large)
+                    .or_fail()?;
+                if client == reader_id {
+                    replies = i + 1;
+                }
+                if status == ReplyStatus::Queued {
+                    break;
+                }
+            }
+        }
+
+        assert!(server.disconnect_gracefully(&mut poller, reader_id, Duration::from_secs(10)));
+        assert!(server.disconnect_gracefully(&mut poller, stalled_id, Duration::from_millis(200)));
+        assert_eq!(server.connections().count(), 2);
+        assert_eq!(
+            server.connection(reader_id).or_fail()?.state(),
+            ConnectionState::ShuttingDown
+        );
+        for client in [reader_id, stalled_id] {
+            assert_eq!(
+                server.try_recv_event(),
+                Some(ServerEvent::ShuttingDown { client })
+            );
+        }
+        tx.send(()).or_fail()?;
+
+        let mut reasons = std::collections::HashMap::new();
+        for _ in 0..100 {
+            poller.poll(&mut events, server.next_timeout()).or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            server.handle_timeout(&mut poller);
+            while let Some(ServerEvent::Disconnected { client, reason }) = server.try_recv_event() {
+                reasons.insert(client, reason);
+            }
+            if reasons.len() == 2 {
+                break;
+            }
+        }
+        assert_eq!(reasons[&reader_id], DisconnectReason::Requested);
+        assert_eq!(reasons[&stalled_id], DisconnectReason::DrainTimeout);
+
+        let received = reader.join().expect("reader thread panicked").or_fail()?;
+        assert_eq!(received, replies);
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_request() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer<RawRequest> = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let request = RequestObject {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            method: "sum".to_owned(),
+            params: Some("[1, 2, 3]".parse::<jsonlrpc::RequestParams>().or_fail()?),
+            id: Some(RequestId::Number(1)),
+        };
+        client.send(&mut poller, &request).or_fail()?;
+
+        let mut success = false;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, Incoming::Call(request))) = server.try_recv() {
+                    assert_eq!(request.method, "sum");
+                    let params: Vec<i64> = request.parse_params().or_fail()?;
+                    let id = request.id.or_fail()?;
+                    let sum = params.iter().sum::<i64>();
+                    server.reply_ok(&mut poller, from, id, sum).or_fail()?;
+                }
+
+                client.handle_event(&mut poller, event).or_fail()?;
+                if let Some(response) = client.try_recv() {
+                    assert_eq!(response.into_std_result(), Ok(serde_json::json!(6)));
+                    success = true;
+                    break 'root;
+                }
+            }
+        }
+        assert!(success);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dual_stack() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer =
+            RpcServer::start_dual_stack(&mut poller, 0, SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+                .or_fail()?;
+        let addrs = server.listen_addrs().collect::<Vec<_>>();
+        assert_eq!(addrs.len(), 2);
+        assert!(addrs[0].is_ipv4());
+        assert!(addrs[1].is_ipv6());
+        assert_eq!(addrs[0].port(), addrs[1].port());
+
+        let port = addrs[0].port();
+        let _clients = [
+            std::net::TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port))).or_fail()?,
+            std::net::TcpStream::connect(SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port)))
+                .or_fail()?,
+        ];
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if server.connections().count() == 2 {
+                break;
+            }
+        }
+        assert_eq!(server.connections().count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_tokens() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(Token(0), Token(1))
+            .events(true)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let _client0 = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        let mut client1 = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+
+        let mut event = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            event = server.try_recv_event();
+            if event.is_some() {
+                break;
+            }
+        }
+        let Some(ServerEvent::AcceptRejected { peer_addr, reason }) = event else {
+            panic!("{event:?}");
+        };
+        assert_eq!(reason, AcceptRejectReason::NoTokens);
+        assert_eq!(peer_addr, Some(client1.local_addr().or_fail()?));
+        assert_eq!(server.connections().count(), 1);
+        assert_eq!(server.stats().rejected_connections, 1);
+
+        let mut buf = Vec::new();
+        client1.read_to_end(&mut buf).or_fail()?;
+        let response: ResponseObject = serde_json::from_slice(&buf).or_fail()?;
+        let ResponseObject::Err { error, .. } = response else {
+            panic!("{response:?}");
+        };
+        assert_eq!(error.code, error_codes::SERVER_OVERLOADED);
+
+        Ok(())
+    }
+
+    #[test]
+    fn proxy_protocol() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .proxy_protocol(true)
+            .events(true)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let request = b"{\"jsonrpc\":\"2.0\",\"method\":\"ping\",\"id\":1}\n";
+        let mut v1 = b"PROXY TCP4 192.0.2.1 192.0.2.2 12345 80\r\n".to_vec();
+        v1.extend_from_slice(request);
+        let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        v2.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+        v2.extend_from_slice(&std::net::Ipv6Addr::UNSPECIFIED.octets());
+        v2.extend_from_slice(&[0x30, 0x39, 0x00, 0x50]);
+        v2.extend_from_slice(request);
+
+        let mut streams = Vec::new();
+        for bytes in [v1, v2, b"GET / HTTP/1.1\r\n\r\n".to_vec()] {
+            let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+            stream.write_all(&bytes).or_fail()?;
+            streams.push(stream);
+        }
+
+        let mut peer_addrs = Vec::new();
+        let mut reasons = Vec::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            while let Some((from, _)) = server.try_recv() {
+                peer_addrs.push(server.peer_addr(from).or_fail()?);
+            }
+            while let Some(ServerEvent::Disconnected { reason, .. }) = server.try_recv_event() {
+                reasons.push(reason);
+            }
+            if peer_addrs.len() == 2 && reasons.len() == 1 {
+                break;
+            }
+        }
+        peer_addrs.sort();
+        assert_eq!(
+            peer_addrs,
+            [
+                SocketAddr::from(([192, 0, 2, 1], 12345)),
+                SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, 12345)),
+            ]
+        );
+        assert_eq!(reasons, [DisconnectReason::InvalidProxyHeader]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_connections_per_ip() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .max_connections_per_ip(1)
+            .events(true)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let mut clients = Vec::new();
+        let mut connect = |server: &mut RpcServer, poller: &mut Poll| -> orfail::Result<_> {
+            clients.push(std::net::TcpStream::connect(server.listen_addr()).or_fail()?);
+            for _ in 0..10 {
+                poller
+                    .poll(&mut events, Some(Duration::from_millis(100)))
+                    .or_fail()?;
+                for event in events.iter() {
+                    server.handle_event(poller, event).or_fail()?;
+                }
+                if let Some(event) = server.try_recv_event() {
+                    return Ok(Some(event));
+                }
+                if server.connections().count() == 1 {
+                    break;
+                }
+            }
+            Ok(None)
+        };
+
+        assert_eq!(connect(&mut server, &mut poller)?, None);
+        let Some(ServerEvent::AcceptRejected { reason, .. }) = connect(&mut server, &mut poller)?
+        else {
+            panic!();
+        };
+        assert_eq!(reason, AcceptRejectReason::MaxConnectionsPerIp);
+
+        let client = server.connections().next().or_fail()?.token();
+        let client = ClientId::from((client.0, 0));
+        assert!(server.disconnect(&mut poller, client));
+        assert!(matches!(
+            server.try_recv_event(),
+            Some(ServerEvent::Disconnected { .. })
+        ));
+        assert_eq!(connect(&mut server, &mut poller)?, None);
+        assert_eq!(server.connections().count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn strict_ordering() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .strict_ordering(true)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        for (i, method) in ["a", "b", "c"].into_iter().enumerate() {
+            writeln!(
+                stream,
+                r#"{{"jsonrpc":"2.0","method":"{method}","id":{i}}}"#
+            )
+            .or_fail()?;
+        }
+
+        let mut methods = Vec::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            assert!(server.queued_requests_len() <= 1);
+            while let Some((from, Incoming::Call(request))) = server.try_recv() {
+                methods.push(request.method);
+                let id = request.id.or_fail()?;
+                server.reply_ok(&mut poller, from, id, ()).or_fail()?;
+                assert!(server.queued_requests_len() <= 1);
+            }
+            if methods.len() == 3 {
+                break;
+            }
+        }
+        assert_eq!(methods, ["a", "b", "c"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reply_with() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let request = RequestObject {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            method: "range".to_owned(),
+            params: None,
+            id: Some(RequestId::Number(1)),
+        };
+        client.send(&mut poller, &request).or_fail()?;
+
+        let mut success = false;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, _)) = server.try_recv() {
+                    let error = server
+                        .reply_with(&mut poller, from, |w| w.write_all(b"{\n}"))
+                        .expect_err("newlines are not allowed");
+                    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+                    server
+                        .reply_with(&mut poller, from, |w| {
+                            write!(w, r#"{{"jsonrpc":"2.0","id":1,"result":["#)?;
+                            for i in 0..1000 {
+                                if i > 0 {
+                                    write!(w, ",")?;
+                                }
+                                write!(w, "{i}")?;
+                            }
+                            write!(w, "]}}")
+                        })
+                        .or_fail()?;
+                }
+
+                client.handle_event(&mut poller, event).or_fail()?;
+                if let Some(response) = client.try_recv() {
+                    let result = response.into_std_result().ok().or_fail()?;
+                    let values: Vec<u32> = serde_json::from_value(result).or_fail()?;
+                    assert_eq!(values, (0..1000).collect::<Vec<_>>());
+                    success = true;
+                    break 'root;
+                }
+            }
+        }
+        assert!(success);
+
+        Ok(())
+    }
+
+    #[test]
+    fn take_connection() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .events(true)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let request = RequestObject {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            method: "upgrade".to_owned(),
+            params: None,
+            id: Some(RequestId::Number(1)),
+        };
+        client.send(&mut poller, &request).or_fail()?;
+
+        let detached_token = Token(200);
+        let mut detached = None;
+        let mut received = None;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                if event.token() == detached_token {
+                    let connection: &mut Connection = detached.as_mut().or_fail()?;
+                    connection
+                        .handle_event(&mut poller, event, |c, _| {
+                            let value: serde_json::Value = c.read_value()?;
+                            received = Some(value);
+                            Ok(())
+                        })
+                        .or_fail()?;
+                    if received.is_some() {
+                        break 'root;
+                    }
+                    continue;
+                }
+
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, _)) = server.try_recv() {
+                    let mut connection = server.take_connection(&mut poller, from).or_fail()?;
+                    assert!(server.connection(from).is_none());
+                    assert!(server.take_connection(&mut poller, from).is_none());
+                    assert!(server
+                        .reply_ok(&mut poller, from, RequestId::Number(1), ())
+                        .or_fail()?
+                        .is_dropped());
+
+                    connection.register(&mut poller, detached_token).or_fail()?;
+                    let response = ResponseObject::Ok {
+                        jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+                        id: RequestId::Number(1),
+                        result: serde_json::json!("upgraded"),
+                    };
+                    connection.send(&mut poller, &response).or_fail()?;
+                    detached = Some(connection);
+                }
+
+                client.handle_event(&mut poller, event).or_fail()?;
+                if let Some(response) = client.try_recv() {
+                    let result = response.into_std_result().ok().or_fail()?;
+                    assert_eq!(result, serde_json::json!("upgraded"));
+                    client
+                        .send(&mut poller, &serde_json::json!({"raw": true}))
+                        .or_fail()?;
+                }
+            }
+        }
+        assert_eq!(received, Some(serde_json::json!({"raw": true})));
+
+        let mut reasons = Vec::new();
+        while let Some(event) = server.try_recv_event() {
+            if let ServerEvent::Disconnected { reason, .. } = event {
+                reasons.push(reason);
+            }
+        }
+        assert_eq!(reasons, [DisconnectReason::Detached]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_batch() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let request = |method: &str, id: Option<i64>| RequestObject {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            method: method.to_owned(),
+            params: None,
+            id: id.map(RequestId::Number),
+        };
+        assert!(client.send_batch(&mut poller, &[]).is_err());
+        client
+            .send_batch(
+                &mut poller,
+                &[
+                    request("foo", Some(1)),
+                    request("bar", None),
+                    request("baz", Some(2)),
+                ],
+            )
+            .or_fail()?;
+
+        let mut received = Vec::new();
+        let mut batch = None;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                received.extend(server.drain_requests());
+                if received.len() == 3 {
+                    // Reply in the reverse order.
+                    for (from, incoming) in received.drain(..).rev() {
+                        if incoming.is_notification() {
+                            continue;
+                        }
+                        let id = incoming.request().id.clone().or_fail()?;
+                        let method = incoming.request().method.clone();
+                        server.reply_ok(&mut poller, from, id, method).or_fail()?;
+                    }
+                }
+
+                client.handle_event(&mut poller, event).or_fail()?;
+                if let Some(responses) = client.try_recv_batch() {
+                    batch = Some(responses);
+                    break 'root;
+                }
+            }
+        }
+
+        let batch = batch.or_fail()?;
+        let ids = batch.iter().map(|r| r.id().cloned()).collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [Some(RequestId::Number(1)), Some(RequestId::Number(2))]
+        );
+        assert!(client.try_recv().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_send_notification() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let params = jsonlrpc::RequestParams::Array(vec![serde_json::json!(1)]);
+        client
+            .send_notification(&mut poller, "log", Some(params.clone()))
+            .or_fail()?;
+
+        let mut received = None;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                client.handle_event(&mut poller, event).or_fail()?;
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((_, incoming)) = server.try_recv() {
+                    received = Some(incoming);
+                    break 'root;
+                }
+            }
+        }
+
+        let incoming = received.or_fail()?;
+        assert!(incoming.is_notification());
+        assert_eq!(incoming.request().method, "log");
+        assert_eq!(incoming.request().params, Some(params));
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_call() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let id0 = client.call(&mut poller, "foo", None).or_fail()?;
+        let id1 = client.call(&mut poller, "bar", None).or_fail()?;
+        assert_eq!(id0, RequestId::Number(0));
+        assert_eq!(id1, RequestId::Number(1));
+
+        let mut responses = Vec::new();
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    let id = incoming.request().id.clone().or_fail()?;
+                    let method = incoming.request().method.clone();
+                    server.reply_ok(&mut poller, from, id, method).or_fail()?;
+                }
+
+                client.handle_event(&mut poller, event).or_fail()?;
+                while let Some(response) = client.try_recv() {
+                    responses.push(response);
+                }
+                if responses.len() == 2 {
+                    break 'root;
+                }
+            }
+        }
+
+        let results = responses
+            .into_iter()
+            .map(|r| (r.id().cloned(), r.into_std_result().ok()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                (Some(id0), Some(serde_json::json!("foo"))),
+                (Some(id1), Some(serde_json::json!("bar")))
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_response_correlation() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let id0 = client.call(&mut poller, "foo", None).or_fail()?;
+        let id1 = client.call(&mut poller, "bar", None).or_fail()?;
+
+        let mut received = Vec::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                received.extend(server.drain_requests());
+                if received.len() == 2 {
+                    // Reply in the reverse order, followed by an unsolicited response.
+                    let from = received[0].0;
+                    for (from, incoming) in received.drain(..).rev() {
+                        let id = incoming.request().id.clone().or_fail()?;
+                        let method = incoming.request().method.clone();
+                        server.reply_ok(&mut poller, from, id, method).or_fail()?;
+                    }
+                    server
+                        .reply_ok(&mut poller, from, RequestId::Number(100), ())
+                        .or_fail()?;
+                }
+
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+        }
+
+        let response = client.try_take_response(&id0).or_fail()?;
+        assert_eq!(
+            response.into_std_result().ok(),
+            Some(serde_json::json!("foo"))
+        );
+        assert!(client.try_take_response(&id0).is_none());
+
+        let unsolicited = client.drain_unsolicited_responses().collect::<Vec<_>>();
+        assert_eq!(unsolicited.len(), 1);
+        assert_eq!(unsolicited[0].id(), Some(&RequestId::Number(100)));
+
+        let response = client.try_recv().or_fail()?;
+        assert_eq!(response.id(), Some(&id1));
+        assert!(client.try_recv().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_reconnect() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        // Connection failures are retried with backoff.
+        let unused_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .or_fail()?
+            .local_addr()
+            .or_fail()?;
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, unused_addr)
+            .reconnect_backoff(Duration::from_secs(10), Duration::from_secs(60))
+            .events(true)
+            .build();
+        client.call(&mut poller, "foo", None).or_fail()?;
+        let mut failed = None;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                if client.handle_event(&mut poller, event).is_err() {
+                    failed = client.try_recv_event();
+                    break 'root;
+                }
+            }
+        }
+        let Some(ClientEvent::ReconnectFailed {
+            attempt,
+            retry_after,
+        }) = failed
+        else {
+            return Err(orfail::Failure::new(format!(
+                "unexpected event: {failed:?}"
+            )));
+        };
+        assert_eq!(attempt, 1);
+        assert!(Duration::from_secs(5) <= retry_after && retry_after <= Duration::from_secs(10));
+        let e = client.call(&mut poller, "foo", None).expect_err("backoff");
+        assert_eq!(e.io_error_kind(), Some(std::io::ErrorKind::NotConnected));
+        assert_eq!(client.next_timeout(), None);
+
+        // Lost connections are re-established proactively.
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server.listen_addr())
+            .reconnect_backoff(Duration::from_millis(10), Duration::from_secs(1))
+            .auto_reconnect(true)
+            .events(true)
+            .build();
+        client.call(&mut poller, "foo", None).or_fail()?;
+
+        let mut client_events = Vec::new();
+        'root: for _ in 0..20 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, _)) = server.try_recv() {
+                    server.disconnect(&mut poller, from);
+                }
+
+                let _ = client.handle_event(&mut poller, event);
+                if client.next_timeout() == Some(Duration::ZERO) {
+                    client.handle_timeout(&mut poller).or_fail()?;
+                }
+                client_events.extend(std::iter::from_fn(|| client.try_recv_event()));
+                if client_events.len() == 5 {
+                    break 'root;
+                }
+            }
+        }
+        assert_eq!(client_events.len(), 5);
+        assert_eq!(client_events[0], ClientEvent::Connected);
+        assert!(matches!(client_events[1], ClientEvent::Disconnected { .. }));
+        assert_eq!(
+            client_events[2..],
+            [
+                ClientEvent::ReconnectAttempt { attempt: 1 },
+                ClientEvent::Connected,
+                ClientEvent::Reconnected { attempts: 1 }
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_request_timeout() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        assert_eq!(client.next_timeout(), None);
+        let id = client.call(&mut poller, "foo", None).or_fail()?;
+        client.set_request_timeout(&id, Duration::from_millis(50));
+        assert!(client.next_timeout().is_some());
+
+        let mut request = None;
+        let mut response = None;
+        'root: for _ in 0..10 {
+            poller.poll(&mut events, client.next_timeout()).or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some(r) = server.try_recv() {
+                    // Hold the request without replying.
+                    request = Some(r);
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            client.handle_timeout(&mut poller).or_fail()?;
+            if let Some(r) = client.try_recv() {
+                response = Some(r);
+                break 'root;
+            }
+        }
+
+        let response = response.or_fail()?;
+        assert_eq!(response.id(), Some(&id));
+        let error = response.into_std_result().err().or_fail()?;
+        assert_eq!(error.code, error_codes::REQUEST_TIMEOUT);
+        assert_eq!(client.next_timeout(), None);
+
+        // A late response is regarded as unsolicited.
+        let (from, _) = request.or_fail()?;
+        server
+            .reply_ok(&mut poller, from, id.clone(), ())
+            .or_fail()?;
+        let mut late = Vec::new();
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                client.handle_event(&mut poller, event).or_fail()?;
+                late.extend(client.drain_unsolicited_responses());
+                if !late.is_empty() {
+                    break 'root;
+                }
+            }
+        }
+        assert_eq!(late.len(), 1);
+        assert_eq!(late[0].id(), Some(&id));
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_custom_response_type() -> orfail::Result<()> {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        #[serde(untagged)]
+        enum Message {
+            Event { event: String },
+            Response(ResponseObject),
+        }
+
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client: RpcClient<Message> =
+            RpcClient::builder(CLIENT_TOKEN, server.listen_addr()).build();
+        let id = client.call(&mut poller, "subscribe", None).or_fail()?;
+        client.set_request_timeout(&id, Duration::ZERO);
+        client.handle_timeout(&mut poller).or_fail()?;
+        let Some(Message::Response(response)) = client.try_take_response(&id) else {
+            return Err(orfail::Failure::new("no timeout response"));
+        };
+        assert_eq!(
+            response.into_std_result().err().map(|e| e.code),
+            Some(error_codes::REQUEST_TIMEOUT)
+        );
+
+        let mut received = Vec::new();
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, _)) = server.try_recv() {
+                    let message = serde_json::json!({"event": "started"});
+                    server.reply(&mut poller, from, &message).or_fail()?;
+                }
+
+                client.handle_event(&mut poller, event).or_fail()?;
+                if let Some(message) = client.try_recv() {
+                    received.push(message);
+                    break 'root;
+                }
+            }
+        }
+        assert_eq!(
+            received,
+            [Message::Event {
+                event: "started".to_owned()
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_pool() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut servers = Vec::new();
+        for i in 0..2 {
+            let server: RpcServer = RpcServer::start(
+                &mut poller,
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                Token(i * 10),
+                Token(i * 10 + 9),
+            )
+            .or_fail()?;
+            servers.push(server);
+        }
+        let addrs = servers.iter().map(|s| s.listen_addr()).collect::<Vec<_>>();
+
+        let mut pool = RpcClientPool::new(CLIENT_TOKEN, Token(CLIENT_TOKEN.0 + 1));
+        for &addr in &addrs {
+            pool.call(&mut poller, addr, "whoami", None).or_fail()?;
+        }
+        pool.call(&mut poller, addrs[0], "whoami", None).or_fail()?;
+        assert_eq!(pool.clients().count(), 2);
+        let unknown_addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        assert!(pool
+            .call(&mut poller, unknown_addr, "whoami", None)
+            .is_err());
+
+        let mut received = Vec::new();
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                for server in &mut servers {
+                    server.handle_event(&mut poller, event).or_fail()?;
+                    while let Some((from, incoming)) = server.try_recv() {
+                        let id = incoming.request().id.clone().or_fail()?;
+                        let addr = server.listen_addr().to_string();
+                        server.reply_ok(&mut poller, from, id, addr).or_fail()?;
+                    }
+                }
+
+                pool.handle_event(&mut poller, event).or_fail()?;
+                while let Some((addr, response)) = pool.try_recv() {
+                    let result = response.into_std_result().ok().or_fail()?;
+                    assert_eq!(result, serde_json::json!(addr.to_string()));
+                    received.push(addr);
+                }
+                if received.len() == 3 {
+                    break 'root;
+                }
+            }
+        }
+        received.sort();
+        let mut expected = vec![addrs[0], addrs[0], addrs[1]];
+        expected.sort();
+        assert_eq!(received, expected);
+
+        assert!(pool.remove_client(&mut poller, addrs[1]));
+        assert!(pool.client(addrs[1]).is_none());
+        pool.call(&mut poller, unknown_addr, "whoami", None)
+            .or_fail()?;
+
+        // The whole token space can be assigned to a pool.
+        let mut pool = RpcClientPool::new(Token(usize::MAX - 1), Token(usize::MAX));
+        for &addr in &addrs {
+            pool.call(&mut poller, addr, "whoami", None).or_fail()?;
+        }
+        assert_eq!(pool.clients().count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_connect() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        assert!(client.connection().is_none());
+        client.connect(&mut poller).or_fail()?;
+        let token = client.connection().map(|c| c.token());
+        client.connect(&mut poller).or_fail()?;
+        assert_eq!(client.connection().map(|c| c.token()), token);
+
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            let state = client.connection().map(|c| c.state());
+            if state == Some(ConnectionState::Connected) && server.connections().count() == 1 {
+                break;
+            }
+        }
+        assert_eq!(
+            client.connection().map(|c| c.state()),
+            Some(ConnectionState::Connected)
+        );
+        assert_eq!(server.connections().count(), 1);
+
+        // Unreachable servers are detected without sending requests.
+        let unused_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .or_fail()?
+            .local_addr()
+            .or_fail()?;
+        let mut client = RpcClient::new(CLIENT_TOKEN, unused_addr);
+        client.connect(&mut poller).or_fail()?;
+        let mut failed = false;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                if client.handle_event(&mut poller, event).is_err() {
+                    failed = true;
+                    break 'root;
+                }
+            }
+        }
+        assert!(failed);
+        assert!(client.connection().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_state() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server.listen_addr())
+            .events(true)
+            .build();
+        assert_eq!(client.state(), ClientState::Disconnected);
+        client
+            .send_notification(&mut poller, "hello", None)
+            .or_fail()?;
+        assert_eq!(client.state(), ClientState::Connecting);
+
+        let mut client_events = Vec::new();
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, _)) = server.try_recv() {
+                    server.disconnect(&mut poller, from);
+                }
+
+                let _ = client.handle_event(&mut poller, event);
+                client_events.extend(std::iter::from_fn(|| client.try_recv_event()));
+                if client_events.len() == 2 {
+                    break 'root;
+                }
+            }
+        }
+        assert_eq!(client_events.len(), 2);
+        assert_eq!(client_events[0], ClientEvent::Connected);
+        assert!(matches!(client_events[1], ClientEvent::Disconnected { .. }));
+        assert_eq!(client.state(), ClientState::Disconnected);
+
+        client.connect(&mut poller).or_fail()?;
+        client.close(&mut poller);
+        assert_eq!(client.state(), ClientState::Disconnected);
+        assert_eq!(client.try_recv_event(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_retain_unsent_requests() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .or_fail()?
+            .local_addr()
+            .or_fail()?;
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, addr)
+            .retain_unsent_requests(true)
+            .build();
+        let id = client.call(&mut poller, "foo", None).or_fail()?;
+        let queued = client.queued_bytes_len();
+        assert!(queued > 0);
+        assert_eq!(client.queued_messages_len(), 1);
+
+        // The connection attempt fails as the server is not running.
+        let mut failed = false;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                if client.handle_event(&mut poller, event).is_err() {
+                    failed = true;
+                    break 'root;
+                }
+            }
+        }
+        assert!(failed);
+        assert_eq!(client.state(), ClientState::Disconnected);
+        assert_eq!(client.queued_bytes_len(), queued);
+        assert_eq!(client.queued_messages_len(), 1);
+
+        // The retained request is sent after reconnecting.
+        let mut server: RpcServer =
+            RpcServer::start(&mut poller, addr, SERVER_TOKEN_MIN, SERVER_TOKEN_MAX).or_fail()?;
+        client.connect(&mut poller).or_fail()?;
+
+        let mut response = None;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, incoming)) = server.try_recv() {
+                    let id = incoming.request().id.clone().or_fail()?;
+                    server.reply_ok(&mut poller, from, id, "bar").or_fail()?;
+                }
+
+                client.handle_event(&mut poller, event).or_fail()?;
+                if let Some(r) = client.try_take_response(&id) {
+                    response = Some(r);
+                    break 'root;
+                }
+            }
+        }
+        let response = response.or_fail()?;
+        assert_eq!(
+            response.into_std_result().ok(),
+            Some(serde_json::json!("bar"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_call_retained_after_write_failure() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").or_fail()?;
+        let mut client: RpcClient =
+            RpcClient::builder(CLIENT_TOKEN, listener.local_addr().or_fail()?)
+                .retain_unsent_requests(true)
+                .build();
+        client.connect(&mut poller).or_fail()?;
+        let (stream, _) = listener.accept().or_fail()?;
+        for _ in 0..10 {
+            if client.state() == ClientState::Connected {
+                break;
+            }
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+        }
+        assert_eq!(client.state(), ClientState::Connected);
+
+        // Closing the peer with unread data resets the connection, so that the next write fails.
+        client.send(&mut poller, &"unread").or_fail()?;
+        std::thread::sleep(Duration::from_millis(50));
+        drop(stream);
+        std::thread::sleep(Duration::from_millis(50));
+        let id0 = client.call(&mut poller, "foo", None).or_fail()?;
+        assert_eq!(client.state(), ClientState::Disconnected);
+        assert_eq!(client.queued_messages_len(), 1);
+
+        // The retained request is sent together with the next one after reconnecting.
+        let id1 = client.call(&mut poller, "bar", None).or_fail()?;
+        assert_ne!(id0, id1);
+
+        let (stream, _) = listener.accept().or_fail()?;
+        stream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .or_fail()?;
+        let mut reader = BufReader::new(stream.try_clone().or_fail()?);
+        let mut writer = stream;
+        let mut ids = Vec::new();
+        let mut line = String::new();
+        let (mut r0, mut r1) = (None, None);
+        for _ in 0..20 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(50)))
+                .or_fail()?;
+            for event in events.iter() {
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            r0 = r0.or_else(|| client.try_take_response(&id0));
+            r1 = r1.or_else(|| client.try_take_response(&id1));
+            if r0.is_some() && r1.is_some() {
+                break;
+            }
+            if reader.read_line(&mut line).is_ok() && line.ends_with('\n') {
+                let request: RequestObject = serde_json::from_str(&line).or_fail()?;
+                let id = request.id.or_fail()?;
+                let response = format!(
+                    "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{:?}}}\n",
+                    serde_json::to_string(&id).or_fail()?,
+                    request.method
+                );
+                writer.write_all(response.as_bytes()).or_fail()?;
+                ids.push(id);
+                line.clear();
+            }
+        }
+        assert_eq!(ids, [id0, id1]);
+
+        let (r0, r1) = (r0.or_fail()?, r1.or_fail()?);
+        assert_eq!(r0.into_std_result().ok(), Some(serde_json::json!("foo")));
+        assert_eq!(r1.into_std_result().ok(), Some(serde_json::json!("bar")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_fail_pending_on_disconnect() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server.listen_addr())
+            .fail_pending_on_disconnect(true)
+            .build();
+        let id0 = client.call(&mut poller, "foo", None).or_fail()?;
+        let id1 = client.call(&mut poller, "bar", None).or_fail()?;
+
+        let mut responses = Vec::new();
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                let requests = server.drain_requests().collect::<Vec<_>>();
+                for (from, _) in requests {
+                    // Close the connection without replying.
+                    server.disconnect(&mut poller, from);
+                }
+
+                let _ = client.handle_event(&mut poller, event);
+                responses.extend(std::iter::from_fn(|| client.try_recv()));
+                if responses.len() == 2 {
+                    break 'root;
+                }
+            }
+        }
+
+        let errors = responses
+            .into_iter()
+            .map(|r| (r.id().cloned(), r.into_std_result().err().map(|e| e.code)))
+            .collect::<Vec<_>>();
+        let expected_code = Some(error_codes::CONNECTION_CLOSED);
+        assert_eq!(
+            errors,
+            [(Some(id0), expected_code), (Some(id1), expected_code)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_max_queued_bytes() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server.listen_addr())
+            .max_queued_bytes(0)
+            .build();
+
+        // Requests are queued until the connection is established.
+        client.call(&mut poller, "foo", None).or_fail()?;
+        let e = client
+            .call(&mut poller, "bar", None)
+            .expect_err("queue full");
+        assert_eq!(e.io_error_kind(), Some(std::io::ErrorKind::WouldBlock));
+        assert_eq!(client.state(), ClientState::Connecting);
+
+        let mut received = 0;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                received += server.drain_requests().count();
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            if received == 1 {
+                break;
+            }
+        }
+        assert_eq!(received, 1);
+        assert_eq!(client.queued_bytes_len(), 0);
+        client.call(&mut poller, "bar", None).or_fail()?;
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn client_unix_domain_socket() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let path =
+            std::env::temp_dir().join(format!("jsonlrpc_mio-client-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut server: RpcServer = RpcServer::builder_unix(&path)
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let mut client = RpcClient::new_unix(CLIENT_TOKEN, &path);
+        assert_eq!(client.server_path(), Some(path.as_path()));
+        client.call(&mut poller, "ping", None).or_fail()?;
+
+        let mut response = None;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, incoming)) = server.try_recv() {
+                    let id = incoming.request().id.clone().or_fail()?;
+                    server.reply_ok(&mut poller, from, id, "pong").or_fail()?;
+                }
+
+                client.handle_event(&mut poller, event).or_fail()?;
+                if let Some(r) = client.try_recv() {
+                    response = Some(r);
+                    break 'root;
+                }
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+
+        let response = response.or_fail()?;
+        assert_eq!(
+            response.into_std_result().ok(),
+            Some(serde_json::json!("pong"))
+        );
+        assert!(client.connection().or_fail()?.stream().as_unix().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_connect_timeout() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+
+        let server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server.listen_addr())
+            .connect_timeout(Some(Duration::ZERO))
+            .build();
+        assert_eq!(client.next_timeout(), None);
+        client.connect(&mut poller).or_fail()?;
+        assert_eq!(client.next_timeout(), Some(Duration::ZERO));
+
+        // The connection is still connecting as no events have been handled.
+        let e = client.handle_timeout(&mut poller).expect_err("timeout");
+        assert_eq!(e.io_error_kind(), Some(std::io::ErrorKind::TimedOut));
+        assert_eq!(client.state(), ClientState::Disconnected);
+        assert_eq!(client.next_timeout(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_keepalive() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server.listen_addr())
+            .keepalive(Some(Duration::from_millis(20)))
+            .keepalive_method("ping")
+            .keepalive_max_missed(1)
+            .build();
+        client.connect(&mut poller).or_fail()?;
+
+        // Pings are answered by the server.
+        let mut pings = 0;
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(200) {
+            poller.poll(&mut events, client.next_timeout()).or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    assert_eq!(incoming.request().method, "ping");
+                    let id = incoming.request().id.clone().or_fail()?;
+                    server.reply_ok(&mut poller, from, id, "pong").or_fail()?;
+                    pings += 1;
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            client.handle_timeout(&mut poller).or_fail()?;
+        }
+        assert!(pings > 0);
+        assert!(client.try_recv().is_none());
+        assert_eq!(client.state(), ClientState::Connected);
+
+        // Pings are no longer answered.
+        let mut timed_out = false;
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            poller.poll(&mut events, client.next_timeout()).or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                server.drain_requests().for_each(drop);
+                let _ = client.handle_event(&mut poller, event);
+            }
+            if let Err(e) = client.handle_timeout(&mut poller) {
+                assert_eq!(e.io_error_kind(), Some(std::io::ErrorKind::TimedOut));
+                timed_out = true;
+                break;
+            }
+        }
+        assert!(timed_out);
+        assert_eq!(client.state(), ClientState::Disconnected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_receives_server_initiated_messages() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        client
+            .send_notification(&mut poller, "hello", None)
+            .or_fail()?;
+
+        let mut messages = Vec::new();
+        let mut response = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, _)) = server.try_recv() {
+                    server
+                        .notify(&mut poller, from, "progress", None)
+                        .or_fail()?;
+                    server.call(&mut poller, from, "whoami", None).or_fail()?;
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+                while let Some(message) = client.try_recv_message() {
+                    if let ClientMessage::Request(request) = &message {
+                        let id = request.id.clone().or_fail()?;
+                        client.reply_ok(&mut poller, id, "client").or_fail()?;
+                    }
+                    messages.push(message);
+                }
+            }
+            response = server.try_recv_response();
+            if response.is_some() {
+                break;
+            }
+        }
+
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(&messages[0], ClientMessage::Notification(r) if r.method == "progress"));
+        assert!(matches!(&messages[1], ClientMessage::Request(r) if r.method == "whoami"));
+        let (_, response) = response.or_fail()?;
+        assert!(
+            matches!(response, ResponseObject::Ok { result, .. } if result == serde_json::json!("client"))
+        );
+        assert!(client.try_recv().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_bind_addr() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let bind_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .or_fail()?
+            .local_addr()
+            .or_fail()?;
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server.listen_addr())
+            .bind_addr(Some(bind_addr))
+            .build();
+        client
+            .send_notification(&mut poller, "hello", None)
+            .or_fail()?;
+
+        let mut peer_addr = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, _)) = server.try_recv() {
+                    peer_addr = server.peer_addr(from);
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            if peer_addr.is_some() {
+                break;
+            }
+        }
+        assert_eq!(peer_addr, Some(bind_addr));
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_cancel() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server.listen_addr())
+            .cancel_method(Some("$/cancelRequest"))
+            .build();
+        let call_id = client.call(&mut poller, "slow", None).or_fail()?;
+        let mut cancelled = false;
+        let mut cancel_params = None;
+        let mut pending = None;
+        let mut late = 0;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    let request = incoming.into_request();
+                    if request.method == "slow" {
+                        pending = Some((from, request.id.or_fail()?));
+                    } else {
+                        assert_eq!(request.method, "$/cancelRequest");
+                        cancel_params = request.params;
+                        let (from, id) = pending.take().or_fail()?;
+                        server.reply_ok(&mut poller, from, id, "late").or_fail()?;
+                    }
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            if pending.is_some() && !cancelled {
+                assert!(client.cancel(&mut poller, &call_id).or_fail()?);
+                assert!(!client.cancel(&mut poller, &call_id).or_fail()?);
+                cancelled = true;
+            }
+            late += client.drain_unsolicited_responses().count();
+            if late > 0 {
+                break;
+            }
+        }
+
+        let expected = serde_json::json!({"id": 0});
+        assert_eq!(
+            serde_json::to_value(cancel_params.or_fail()?).or_fail()?,
+            expected
+        );
+        assert_eq!(late, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_stats() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        client.call(&mut poller, "foo", None).or_fail()?;
+        client.call(&mut poller, "bar", None).or_fail()?;
+        assert_eq!(client.stats().sent_messages, 2);
+        assert_eq!(client.stats().in_flight_requests, 2);
+
+        let mut responses = 0;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    let id = incoming.into_request().id.or_fail()?;
+                    server.reply_ok(&mut poller, from, id, ()).or_fail()?;
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            while client.try_recv().is_some() {
+                responses += 1;
+            }
+            if responses == 2 {
+                break;
+            }
+        }
+        assert_eq!(responses, 2);
+
+        let stats = client.stats();
+        assert_eq!(stats.received_responses, 2);
+        assert_eq!(stats.in_flight_requests, 0);
+        assert_eq!(stats.reconnects, 0);
+        assert!(stats.sent_bytes > 0);
+        assert!(stats.received_bytes > 0);
+
+        client.close(&mut poller);
+        assert_eq!(client.stats(), stats);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_handle() -> orfail::Result<()> {
+        const WAKER_TOKEN: Token = Token(101);
+
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let waker = std::sync::Arc::new(mio::Waker::new(poller.registry(), WAKER_TOKEN).or_fail()?);
+        let handle = client.handle(waker.clone());
+        let sender = std::thread::spawn(move || -> orfail::Result<()> {
+            for i in 0..3 {
+                handle
+                    .send_notification(&format!("foo{i}"), None)
+                    .or_fail()?;
+            }
+            Ok(())
+        });
+
+        let mut methods = Vec::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                if event.token() == WAKER_TOKEN {
+                    client.send_submitted(&mut poller).or_fail()?;
+                }
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((_, incoming)) = server.try_recv() {
+                    methods.push(incoming.into_request().method);
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            if methods.len() == 3 {
+                break;
+            }
+        }
+        sender.join().expect("thread panicked")?;
+        assert_eq!(methods, ["foo0", "foo1", "foo2"]);
+
+        drop(client);
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let handle = client.handle(waker);
+        drop(client);
+        let e = handle.send_notification("foo", None).err().or_fail()?;
+        assert_eq!(e.io_error_kind(), Some(std::io::ErrorKind::BrokenPipe));
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_call_with_retry() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let policy = RetryPolicy::new(3)
+            .backoff(Duration::from_millis(10), Duration::from_millis(100))
+            .retry_on_error_code(error_codes::SERVER_OVERLOADED);
+        let flaky_id = client
+            .call_with_retry(&mut poller, "flaky", None, policy.clone())
+            .or_fail()?;
+        let broken_id = client
+            .call_with_retry(&mut poller, "broken", None, policy)
+            .or_fail()?;
+
+        let mut received = Vec::new();
+        let mut responses = Vec::new();
+        let start = std::time::Instant::now();
+        while start.elapsed() < Duration::from_secs(2) && responses.len() < 2 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(10)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    let request = incoming.into_request();
+                    let id = request.id.or_fail()?;
+                    let attempt = received.iter().filter(|m| **m == request.method).count();
+                    received.push(request.method.clone());
+                    if request.method == "flaky" && attempt > 0 {
+                        server.reply_ok(&mut poller, from, id, "ok").or_fail()?;
+                    } else {
+                        let code = error_codes::SERVER_OVERLOADED;
+                        server
+                            .reply_err(&mut poller, from, id, code, "busy")
+                            .or_fail()?;
+                    }
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            client.handle_timeout(&mut poller).or_fail()?;
+            responses.extend(std::iter::from_fn(|| client.try_recv()));
+        }
+
+        received.sort();
+        assert_eq!(received, ["broken", "broken", "broken", "flaky", "flaky"]);
+        assert_eq!(responses.len(), 2);
+        for response in responses {
+            match response {
+                ResponseObject::Ok { id, .. } => assert_eq!(id, flaky_id),
+                ResponseObject::Err { id, error, .. } => {
+                    assert_eq!(id, Some(broken_id.clone()));
+                    assert_eq!(error.code, error_codes::SERVER_OVERLOADED);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_call_typed() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let add_id = client.call_typed(&mut poller, "add", &(1, 2)).or_fail()?;
+        let fail_id = client
+            .call_typed(&mut poller, "fail", &serde_json::json!({}))
+            .or_fail()?;
+        assert!(client.call_typed(&mut poller, "add", &1).is_err());
+
+        let mut sum = None;
+        let mut error = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    let request = incoming.into_request();
+                    let id = request.id.or_fail()?;
+                    if request.method == "add" {
+                        let Some(jsonlrpc::RequestParams::Array(params)) = request.params else {
+                            return Err(orfail::Failure::new("unexpected params"));
+                        };
+                        let sum = params.iter().filter_map(|p| p.as_i64()).sum::<i64>();
+                        server.reply_ok(&mut poller, from, id, sum).or_fail()?;
+                    } else {
+                        let code = ErrorCode::METHOD_NOT_FOUND;
+                        server
+                            .reply_err(&mut poller, from, id, code, "no")
+                            .or_fail()?;
+                    }
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            sum = sum.or_else(|| client.try_take_result::<u32>(&add_id));
+            error = error.or_else(|| client.try_take_result::<u32>(&fail_id));
+            if sum.is_some() && error.is_some() {
+                break;
+            }
+        }
+
+        assert_eq!(sum.or_fail()?.or_fail()?.ok(), Some(3));
+        let error = error.or_fail()?.or_fail()?.err().or_fail()?;
+        assert_eq!(error.code, ErrorCode::METHOD_NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_flush_and_close() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        // The connection is closed after the response arrives.
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let id = client.call(&mut poller, "foo", None).or_fail()?;
+        client.flush_and_close(&mut poller, Duration::from_secs(1), true);
+        assert_ne!(client.state(), ClientState::Disconnected);
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    let id = incoming.into_request().id.or_fail()?;
+                    server.reply_ok(&mut poller, from, id, ()).or_fail()?;
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            if client.state() == ClientState::Disconnected {
+                break;
+            }
+        }
+        assert_eq!(client.state(), ClientState::Disconnected);
+        assert!(client.try_take_response(&id).is_some());
+
+        // The connection is closed when the timeout expires.
+        client.call(&mut poller, "foo", None).or_fail()?;
+        client.flush_and_close(&mut poller, Duration::from_millis(50), true);
+        let mut result = Ok(());
+        for _ in 0..10 {
+            poller.poll(&mut events, client.next_timeout()).or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            result = client.handle_timeout(&mut poller);
+            if result.is_err() {
+                break;
+            }
+        }
+        let e = result.err().or_fail()?;
+        assert_eq!(e.io_error_kind(), Some(std::io::ErrorKind::TimedOut));
+        assert_eq!(client.state(), ClientState::Disconnected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_error_variants() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let unused_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .or_fail()?
+            .local_addr()
+            .or_fail()?;
+        let mut client = RpcClient::new(CLIENT_TOKEN, unused_addr);
+
+        let e = client.call_typed(&mut poller, "foo", &1).err().or_fail()?;
+        assert!(matches!(e, ClientError::Serialize(_)));
+        assert_eq!(e.io_error_kind(), None);
+
+        client
+            .send_notification(&mut poller, "foo", None)
+            .or_fail()?;
+        let mut error = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                if let Err(e) = client.handle_event(&mut poller, event) {
+                    error = Some(e);
+                }
+            }
+            if error.is_some() {
+                break;
+            }
+        }
+        let e = error.or_fail()?;
+        assert!(matches!(e, ClientError::Connect(_)), "{e}");
+        assert!(e.io_error_kind().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_http_transport() -> orfail::Result<()> {
+        use std::io::BufRead;
+
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").or_fail()?;
+        let server_addr = listener.local_addr().or_fail()?;
+        let server = std::thread::spawn(move || -> orfail::Result<Vec<String>> {
+            let (stream, _) = listener.accept().or_fail()?;
+            let mut reader = std::io::BufReader::new(stream.try_clone().or_fail()?);
+            let mut writer = stream;
+            let mut request_lines = Vec::new();
+            for i in 0..3 {
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).or_fail()?;
+                request_lines.push(request_line.trim_end().to_owned());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).or_fail()?;
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(v) = line.strip_prefix("Content-Length: ") {
+                        content_length = v.trim().parse::<usize>().or_fail()?;
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).or_fail()?;
+                let request: RequestObject = serde_json::from_slice(&body).or_fail()?;
+                let Some(id) = request.id else {
+                    writer
+                        .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                        .or_fail()?;
+                    continue;
+                };
+                let response = ResponseObject::Ok {
+                    jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+                    result: serde_json::json!(request.method),
+                    id,
+                };
+                let body = serde_json::to_string_pretty(&response).or_fail()?;
+                if i == 0 {
+                    write!(
+                        writer,
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+                        body.len()
+                    )
+                    .or_fail()?;
+                } else {
+                    let (a, b) = body.split_at(body.len() / 2);
+                    write!(
+                        writer,
+                        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{a}\r\n{:x}\r\n{b}\r\n0\r\n\r\n",
+                        a.len(),
+                        b.len()
+                    )
+                    .or_fail()?;
+                }
+            }
+
+            // Waits for the client to close the connection.
+            reader.read_line(&mut String::new()).or_fail()?;
+            Ok(request_lines)
+        });
+
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server_addr)
+            .http(Some("/rpc"))
+            .build();
+        client.call(&mut poller, "foo", None).or_fail()?;
+        client
+            .send_notification(&mut poller, "bar", None)
+            .or_fail()?;
+        client.call(&mut poller, "baz", None).or_fail()?;
+
+        let mut results = Vec::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            while let Some(response) = client.try_recv() {
+                results.push(response.into_std_result().ok().or_fail()?);
+            }
+            if results.len() == 2 {
+                break;
+            }
+        }
+        assert_eq!(
+            results,
+            [serde_json::json!("foo"), serde_json::json!("baz")]
+        );
+        client.close(&mut poller);
+
+        let request_lines = server.join().expect("thread panicked")?;
+        assert!(request_lines.iter().all(|l| l == "POST /rpc HTTP/1.1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_websocket_transport() -> orfail::Result<()> {
+        use std::io::BufRead;
+
+        // Example from RFC 6455.
+        assert_eq!(
+            websocket::accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        fn read_frame(reader: &mut impl Read) -> orfail::Result<(u8, Vec<u8>)> {
+            let mut header = [0; 2];
+            reader.read_exact(&mut header).or_fail()?;
+            (header[1] & 0x80 != 0).or_fail_with(|()| "unmasked frame".to_owned())?;
+            let len = usize::from(header[1] & 0x7F);
+            (len < 126).or_fail()?;
+            let mut mask = [0; 4];
+            reader.read_exact(&mut mask).or_fail()?;
+            let mut payload = vec![0; len];
+            reader.read_exact(&mut payload).or_fail()?;
+            for (b, m) in payload.iter_mut().zip(mask.iter().cycle()) {
+                *b ^= m;
+            }
+            Ok((header[0] & 0x0F, payload))
+        }
+
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").or_fail()?;
+        let server_addr = listener.local_addr().or_fail()?;
+        let server = std::thread::spawn(move || -> orfail::Result<Vec<u8>> {
+            let (stream, _) = listener.accept().or_fail()?;
+            let mut reader = std::io::BufReader::new(stream.try_clone().or_fail()?);
+            let mut writer = stream;
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).or_fail()?;
+            assert_eq!(request_line, "GET /ws HTTP/1.1\r\n");
+            let mut key = None;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).or_fail()?;
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(v) = line.strip_prefix("Sec-WebSocket-Key: ") {
+                    key = Some(v.trim().to_owned());
+                }
+            }
+            let accept = websocket::accept_key(&key.or_fail()?);
+            write!(
+                writer,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+            )
+            .or_fail()?;
+
+            let (opcode, payload) = read_frame(&mut reader)?;
+            assert_eq!(opcode, 0x1);
+            let request: RequestObject = serde_json::from_slice(&payload).or_fail()?;
+
+            // Ping.
+            writer.write_all(&[0x89, 2, b'h', b'i']).or_fail()?;
+            let (opcode, pong) = read_frame(&mut reader)?;
+            assert_eq!(opcode, 0xA);
+
+            // Fragmented response.
+            let response = ResponseObject::Ok {
+                jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+                result: serde_json::json!(request.method),
+                id: request.id.or_fail()?,
+            };
+            let body = serde_json::to_vec(&response).or_fail()?;
+            let (a, b) = body.split_at(10);
+            writer.write_all(&[0x01, a.len() as u8]).or_fail()?;
+            writer.write_all(a).or_fail()?;
+            writer.write_all(&[0x80, b.len() as u8]).or_fail()?;
+            writer.write_all(b).or_fail()?;
+
+            // Close.
+            writer.write_all(&[0x88, 2, 0x03, 0xE8]).or_fail()?;
+            let (opcode, _) = read_frame(&mut reader)?;
+            assert_eq!(opcode, 0x8);
+            Ok(pong)
+        });
+
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server_addr)
+            .websocket(Some("/ws"))
+            .build();
+        client.call(&mut poller, "foo", None).or_fail()?;
+
+        let mut result = None;
+        let mut error = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                if let Err(e) = client.handle_event(&mut poller, event) {
+                    error = Some(e);
+                }
+            }
+            if let Some(response) = client.try_recv() {
+                result = response.into_std_result().ok();
+            }
+            if error.is_some() {
+                break;
+            }
+        }
+        assert_eq!(result, Some(serde_json::json!("foo")));
+        let error = error.or_fail()?;
+        assert_eq!(
+            error.io_error_kind(),
+            Some(std::io::ErrorKind::ConnectionAborted)
+        );
+
+        let pong = server.join().expect("thread panicked")?;
+        assert_eq!(pong, b"hi");
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_max_in_flight_requests() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        // Backpressure error.
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server.listen_addr())
+            .max_in_flight_requests(2)
+            .build();
+        client.call(&mut poller, "foo", None).or_fail()?;
+        client.call(&mut poller, "foo", None).or_fail()?;
+        let error = client.call(&mut poller, "foo", None).err().or_fail()?;
+        assert_eq!(error.io_error_kind(), Some(std::io::ErrorKind::WouldBlock));
+        client.close(&mut poller);
+
+        // Local queueing.
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server.listen_addr())
+            .max_in_flight_requests(1)
+            .queue_excess_requests(true)
+            .build();
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            ids.push(client.call(&mut poller, "foo", None).or_fail()?);
+        }
+        assert_eq!(client.stats().in_flight_requests, 1);
+
+        let mut received = Vec::new();
+        let mut responses = Vec::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            let mut outstanding = 0;
+            while let Some((from, incoming)) = server.try_recv() {
+                let id = incoming.into_request().id.or_fail()?;
+                received.push(id.clone());
+                server.reply_ok(&mut poller, from, id, "bar").or_fail()?;
+                outstanding += 1;
+            }
+            assert!(outstanding <= 1);
+            while let Some(response) = client.try_recv() {
+                responses.push(response.id().cloned().or_fail()?);
+            }
+            if responses.len() == ids.len() {
+                break;
+            }
+        }
+        assert_eq!(received, ids);
+        assert_eq!(responses, ids);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_host() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let host = format!("127.0.0.1:{}", server.listen_addr().port());
+        let mut client = RpcClient::new_host(CLIENT_TOKEN, &host);
+        assert_eq!(client.server_host(), Some(host.as_str()));
+
+        let mut result = None;
+        for i in 0..2 {
+            client.call(&mut poller, "foo", None).or_fail()?;
+            assert_eq!(client.server_addr(), server.listen_addr());
+            for _ in 0..10 {
+                poller
+                    .poll(&mut events, Some(Duration::from_millis(100)))
+                    .or_fail()?;
+                for event in events.iter() {
+                    server.handle_event(&mut poller, event).or_fail()?;
+                    while let Some((from, incoming)) = server.try_recv() {
+                        let id = incoming.request().id.clone().or_fail()?;
+                        server.reply_ok(&mut poller, from, id, i).or_fail()?;
+                    }
+                    client.handle_event(&mut poller, event).or_fail()?;
+                }
+                result = client.try_recv().and_then(|r| r.into_std_result().ok());
+                if result.is_some() {
+                    break;
+                }
+            }
+            assert_eq!(result.take(), Some(serde_json::json!(i)));
+
+            // Resolved again on the next connection.
+            client.close(&mut poller);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_cork() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        client.connect(&mut poller).or_fail()?;
+        for _ in 0..10 {
+            if client.state() == ClientState::Connected {
+                break;
+            }
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+        }
+        assert_eq!(client.state(), ClientState::Connected);
+
+        client.cork();
+        for _ in 0..3 {
+            client.call(&mut poller, "foo", None).or_fail()?;
+        }
+        assert!(client.queued_bytes_len() > 0);
+        assert_eq!(client.queued_messages_len(), 3);
+        client.uncork(&mut poller).or_fail()?;
+        assert_eq!(client.queued_bytes_len(), 0);
+        assert_eq!(client.queued_messages_len(), 0);
+
+        let mut responses = 0;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    let id = incoming.request().id.clone().or_fail()?;
+                    server.reply_ok(&mut poller, from, id, "bar").or_fail()?;
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            while client.try_recv().is_some() {
+                responses += 1;
+            }
+            if responses == 3 {
+                break;
+            }
+        }
+        assert_eq!(responses, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_split() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let (sender, receiver) = client.split();
+        let id = sender.call(&mut poller, "foo", None).or_fail()?;
+
+        let mut response = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    let id = incoming.request().id.clone().or_fail()?;
+                    server.reply_ok(&mut poller, from, id, "bar").or_fail()?;
+                }
+                receiver.handle_event(&mut poller, event).or_fail()?;
+            }
+            response = receiver.try_take_response(&id);
+            if response.is_some() {
+                break;
+            }
+        }
+        assert_eq!(
+            response.or_fail()?.into_std_result().ok(),
+            Some(serde_json::json!("bar"))
+        );
+
+        let other = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let (other_sender, _) = other.split();
+        let (_, receiver) = receiver.reunite(other_sender).err().or_fail()?;
+        let client = receiver.reunite(sender).ok().or_fail()?;
+        assert_eq!(client.stats().received_responses, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_unsolicited_batch_response() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        // A batch request sent via `send()` instead of `send_batch()`.
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let batch = serde_json::json!([
+            {"jsonrpc": "2.0", "method": "foo", "id": 1},
+            {"jsonrpc": "2.0", "method": "bar", "id": 2},
+        ]);
+        client.send(&mut poller, &batch).or_fail()?;
+        client.call(&mut poller, "baz", None).or_fail()?;
+
+        let mut batches = Vec::new();
+        let mut responses = 0;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    let id = incoming.request().id.clone().or_fail()?;
+                    let method = incoming.request().method.clone();
+                    server.reply_ok(&mut poller, from, id, method).or_fail()?;
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            batches.extend(std::iter::from_fn(|| client.try_recv_batch()));
+            responses += std::iter::from_fn(|| client.try_recv()).count();
+            if !batches.is_empty() && responses > 0 {
+                break;
+            }
+        }
+        assert_eq!(client.state(), ClientState::Connected);
+        assert_eq!(responses, 1);
+        assert_eq!(batches.len(), 1);
+        let results = batches[0]
+            .iter()
+            .map(|r| r.clone().into_std_result().ok())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                Some(serde_json::json!("foo")),
+                Some(serde_json::json!("bar"))
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_send_raw() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let error = client.send_raw(&mut poller, b"{").err().or_fail()?;
+        assert!(matches!(error, ClientError::Serialize(_)));
+        let error = client
+            .send_raw(&mut poller, b"{\"jsonrpc\":\"2.0\",\n\"method\":\"foo\"}")
+            .err()
+            .or_fail()?;
+        assert_eq!(
+            error.io_error_kind(),
+            Some(std::io::ErrorKind::InvalidInput)
+        );
+
+        client
+            .send_raw(
+                &mut poller,
+                br#" {"jsonrpc":"2.0","method":"foo","id":"raw"} "#,
+            )
+            .or_fail()?;
+
+        let mut response = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    let id = incoming.request().id.clone().or_fail()?;
+                    let method = incoming.request().method.clone();
+                    server.reply_ok(&mut poller, from, id, method).or_fail()?;
+                }
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            response = client.try_recv();
+            if response.is_some() {
+                break;
+            }
+        }
+        let response = response.or_fail()?;
+        assert_eq!(response.id(), Some(&RequestId::String("raw".to_owned())));
+        assert_eq!(
+            response.into_std_result().ok(),
+            Some(serde_json::json!("foo"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn connection_addrs() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        client
+            .send_notification(&mut poller, "foo", None)
+            .or_fail()?;
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some((id, _)) = server.try_recv() {
+                from = Some(id);
+                break;
+            }
+        }
+        let from = from.or_fail()?;
+
+        let client_conn = client.connection().or_fail()?;
+        assert_eq!(client_conn.peer_addr(), Some(server.listen_addr()));
+        let client_addr = client_conn.local_addr().or_fail()?;
+
+        let server_conn = server.connection(from).or_fail()?;
+        assert_eq!(server_conn.peer_addr(), Some(client_addr));
+        assert_eq!(server_conn.local_addr(), Some(server.listen_addr()));
+
+        // Still available after the connection is closed.
+        let mut conn = server.take_connection(&mut poller, from).or_fail()?;
+        conn.close(&mut poller);
+        assert_eq!(conn.peer_addr(), Some(client_addr));
+
+        Ok(())
+    }
+
+    #[test]
+    fn connection_user_data() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        #[derive(Debug, PartialEq)]
+        struct Session {
+            calls: usize,
+        }
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        for _ in 0..2 {
+            client
+                .send_notification(&mut poller, "foo", None)
+                .or_fail()?;
+        }
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            while let Some((id, _)) = server.try_recv() {
+                match server.user_data_mut::<Session>(id) {
+                    Some(session) => session.calls += 1,
+                    None => assert!(server.set_user_data(id, Session { calls: 1 })),
+                }
+                from = Some(id);
+            }
+            if from.is_some_and(|id| {
+                server
+                    .connection(id)
+                    .and_then(|c| c.user_data::<Session>())
+                    .is_some_and(|s| s.calls == 2)
+            }) {
+                break;
+            }
+        }
+        let from = from.or_fail()?;
+
+        let mut conn = server.take_connection(&mut poller, from).or_fail()?;
+        assert!(conn.user_data::<String>().is_none());
+        assert!(conn.take_user_data::<String>().is_none());
+        assert_eq!(conn.take_user_data(), Some(Session { calls: 2 }));
+        assert!(conn.user_data::<Session>().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_stall_timeout() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .write_stall_timeout(Some(Duration::from_millis(200)))
+            .events(true)
+            .start(&mut poller)
+            .or_fail()?;
+
+        // A client that never reads.
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"hello\"}\n")
+            .or_fail()?;
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            from = server.try_recv().map(|(from, _)| from);
+            if from.is_some() {
+                break;
+            }
+        }
+        let from = from.or_fail()?;
+
+        let params = jsonlrpc::RequestParams::Array(vec![serde_json::json!("a".repeat(64 * 1024))]);
+        for _ in 0..10000 {
+            let stalled = server.connection(from).or_fail()?.write_stalled_for();
+            if stalled.is_some() {
+                break;
+            }
+            server
+                .notify(&mut poller, from, "data", Some(params.clone()))
+                .or_fail()?;
+        }
+        assert!(server
+            .connection(from)
+            .or_fail()?
+            .write_stalled_for()
+            .is_some());
+        assert!(server.next_timeout().is_some());
+
+        let start = Instant::now();
+        let mut reason = None;
+        while reason.is_none() && start.elapsed() < Duration::from_secs(2) {
+            poller.poll(&mut events, server.next_timeout()).or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            server.handle_timeout(&mut poller);
+            while let Some(event) = server.try_recv_event() {
+                if let ServerEvent::Disconnected { client, reason: r } = event {
+                    assert_eq!(client, from);
+                    reason = Some(r);
+                }
+            }
+        }
+        assert_eq!(reason, Some(DisconnectReason::WriteStalled));
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_retained_buf_size() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let large_request = format!(
+            "{{\"jsonrpc\":\"2.0\",\"method\":\"foo\",\"params\":[\"{}\"]}}\n",
+            "a".repeat(1024 * 1024)
+        );
+        let small_request = "{\"jsonrpc\":\"2.0\",\"method\":\"foo\"}\n";
+        let delay = Duration::from_millis(200);
+        for (max, shrink_delay) in [
+            (None, Duration::ZERO),
+            (Some(8192), Duration::ZERO),
+            (Some(8192), delay),
+        ] {
+            let mut builder = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+                .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+                .buf_shrink_delay(shrink_delay);
+            if let Some(max) = max {
+                builder = builder.max_retained_buf_size(max);
+            }
+            let mut server: RpcServer = builder.start(&mut poller).or_fail()?;
+
+            let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+            let mut recv = |server: &mut RpcServer, request: &str| -> orfail::Result<ClientId> {
+                stream.write_all(request.as_bytes()).or_fail()?;
+                for _ in 0..50 {
+                    poller
+                        .poll(&mut events, Some(Duration::from_millis(100)))
+                        .or_fail()?;
+                    for event in events.iter() {
+                        server.handle_event(&mut poller, event).or_fail()?;
+                    }
+                    if let Some((from, _)) = server.try_recv() {
+                        return Ok(from);
+                    }
+                }
+                Err(orfail::Failure::new("no request received"))
+            };
+
+            let from = recv(&mut server, &large_request).or_fail()?;
+            let size = server.connection(from).or_fail()?.allocated_buf_size();
+            match max {
+                Some(max) if shrink_delay.is_zero() => assert!(size <= max * 2, "{size}"),
+                _ => assert!(size > 1024 * 1024, "{size}"),
+            }
+
+            if max.is_some() && !shrink_delay.is_zero() {
+                // Small messages within the delay do not shrink the buffer.
+                recv(&mut server, small_request).or_fail()?;
+                recv(&mut server, small_request).or_fail()?;
+                let size = server.connection(from).or_fail()?.allocated_buf_size();
+                assert!(size > 1024 * 1024, "{size}");
+
+                std::thread::sleep(delay);
+                recv(&mut server, small_request).or_fail()?;
+                recv(&mut server, small_request).or_fail()?;
+                let size = server.connection(from).or_fail()?.allocated_buf_size();
+                assert!(size <= 8192 * 2, "{size}");
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_message_size() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .max_response_size(64)
+            .start(&mut poller)
+            .or_fail()?;
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server.listen_addr())
+            .max_message_size(64)
+            .build();
+
+        let large = jsonlrpc::RequestParams::Array(vec![serde_json::json!("a".repeat(100))]);
+        let error = client
+            .call(&mut poller, "foo", Some(large))
+            .err()
+            .or_fail()?;
+        assert!(
+            matches!(&error, ClientError::Io(e) if e.kind() == std::io::ErrorKind::InvalidInput),
+            "{error:?}"
+        );
+        assert_eq!(client.stats().in_flight_requests, 0);
+        client.call(&mut poller, "ping", None).or_fail()?;
+
+        let mut response = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                if event.token() == CLIENT_TOKEN {
+                    client.handle_event(&mut poller, event).or_fail()?;
+                } else {
+                    server.handle_event(&mut poller, event).or_fail()?;
+                }
+            }
+            if let Some((from, Incoming::Call(request))) = server.try_recv() {
+                let id = request.id.or_fail()?;
+                let error = server
+                    .reply_ok(&mut poller, from, id.clone(), "a".repeat(100))
+                    .err()
+                    .or_fail()?;
+                assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+                assert!(server.connection(from).is_some());
+                server.reply_ok(&mut poller, from, id, "pong").or_fail()?;
+            }
+            response = client.try_recv();
+            if response.is_some() {
+                break;
+            }
+        }
+        let response = response.or_fail()?;
+        assert_eq!(response.into_std_result(), Ok(serde_json::json!("pong")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn half_close() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let server_addr = server.listen_addr();
+        let client = std::thread::spawn(move || -> orfail::Result<Vec<u8>> {
+            let mut stream = std::net::TcpStream::connect(server_addr).or_fail()?;
+            stream
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"ping\",\"id\":1}\n")
+                .or_fail()?;
+            stream.shutdown(std::net::Shutdown::Write).or_fail()?;
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).or_fail()?;
+            Ok(buf)
+        });
+
+        let mut request = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if request.is_none() {
+                request = server.try_recv();
+            }
+            let read_closed = request
+                .as_ref()
+                .and_then(|(from, _)| server.connection(*from))
+                .is_some_and(|c| c.is_read_closed());
+            if read_closed {
+                break;
+            }
+        }
+        let Some((from, Incoming::Call(request))) = request else {
+            return Err(orfail::Failure::new("no request"));
+        };
+        assert!(server.connection(from).or_fail()?.is_read_closed());
+
+        let id = request.id.or_fail()?;
+        server.reply_ok(&mut poller, from, id, "pong").or_fail()?;
+        assert!(server.connection(from).is_none());
+
+        let buf = client.join().expect("client thread panicked").or_fail()?;
+        let response: ResponseObject = serde_json::from_slice(&buf).or_fail()?;
+        assert_eq!(response.into_std_result(), Ok(serde_json::json!("pong")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn flush() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        client.connect(&mut poller).or_fail()?;
+        for _ in 0..10 {
+            if client.state() == ClientState::Connected {
+                break;
+            }
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+        }
+        assert_eq!(client.state(), ClientState::Connected);
+
+        // Flushing writes the queued data even while corked.
+        client.cork();
+        client.call(&mut poller, "foo", None).or_fail()?;
+        assert!(client.queued_bytes_len() > 0);
+        client.flush(&mut poller).or_fail()?;
+        assert_eq!(client.queued_bytes_len(), 0);
+
+        let mut response = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                while let Some((from, incoming)) = server.try_recv() {
+                    let id = incoming.request().id.clone().or_fail()?;
+                    server.reply_ok(&mut poller, from, id, "bar").or_fail()?;
+                }
+                server.flush(&mut poller);
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            response = client.try_recv();
+            if response.is_some() {
+                break;
+            }
+        }
+        let response = response.or_fail()?;
+        assert_eq!(response.into_std_result(), Ok(serde_json::json!("bar")));
+        assert_eq!(server.stats().active_connections, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_rate_limit() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .read_rate_limit(1000, 1000)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let request = format!(
+            "{{\"jsonrpc\":\"2.0\",\"method\":\"foo\",\"params\":[\"{}\"]}}\n",
+            "a".repeat(500)
+        );
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        stream.write_all(request.repeat(3).as_bytes()).or_fail()?;
+
+        let start = Instant::now();
+        let mut received = 0;
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            while let Some((id, _)) = server.try_recv() {
+                from = Some(id);
+                received += 1;
+            }
+            if received > 0 {
+                break;
+            }
+        }
+        assert_eq!(received, 1);
+        assert!(server.next_timeout().is_some());
+
+        // Nothing is read from the throttled connection until it resumes.
+        let from = from.or_fail()?;
+        let received_bytes = server.connection(from).or_fail()?.received_bytes();
+        assert!(!server.connection(from).or_fail()?.read_interest());
+        stream.write_all(request.as_bytes()).or_fail()?;
+        poller
+            .poll(&mut events, Some(Duration::from_millis(100)))
+            .or_fail()?;
+        for event in events.iter() {
+            server.handle_event(&mut poller, event).or_fail()?;
+        }
+        assert!(server.next_timeout().is_some_and(|t| t > Duration::ZERO));
+        let connection = server.connection(from).or_fail()?;
+        assert_eq!(connection.received_bytes(), received_bytes);
+
+        while received < 4 && start.elapsed() < Duration::from_secs(5) {
+            poller.poll(&mut events, server.next_timeout()).or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            server.handle_timeout(&mut poller);
+            while server.try_recv().is_some() {
+                received += 1;
+            }
+        }
+        assert_eq!(received, 4);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+
+        // An empty bucket is rejected.
+        let result: std::io::Result<RpcServer> =
+            RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+                .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+                .read_rate_limit(1000, 0)
+                .start(&mut poller);
+        let e = result.err().or_fail()?;
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+
+        Ok(())
+    }
+
+    #[test]
+    fn connection_state_observer() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"foo\"}\n")
+            .or_fail()?;
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some((id, _)) = server.try_recv() {
+                from = Some(id);
+                break;
+            }
+        }
+        let mut conn = server
+            .take_connection(&mut poller, from.or_fail()?)
+            .or_fail()?;
+
+        let transitions = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let observed = transitions.clone();
+        conn.set_state_observer(move |old, new| {
+            observed.lock().expect("poisoned").push((old, new))
+        });
+        conn.begin_shutdown();
+        conn.close(&mut poller);
+        assert_eq!(
+            *transitions.lock().expect("poisoned"),
+            [
+                (ConnectionState::Connected, ConnectionState::ShuttingDown),
+                (ConnectionState::ShuttingDown, ConnectionState::Closed)
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn connection_into_inner() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"upgrade\"}\nraw bytes")
+            .or_fail()?;
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some((id, _)) = server.try_recv() {
+                from = Some(id);
+                break;
+            }
+        }
+        let conn = server
+            .take_connection(&mut poller, from.or_fail()?)
+            .or_fail()?;
+        let (inner, unread) = conn.into_inner(&mut poller);
+        assert_eq!(unread, b"raw bytes");
+
+        let Stream::Tcp(mut inner) = inner else {
+            return Err(orfail::Failure::new("not a TCP stream"));
+        };
+        inner.write_all(b"done").or_fail()?;
+        drop(inner);
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).or_fail()?;
+        assert_eq!(buf, b"done");
+
+        Ok(())
+    }
+
+    #[test]
+    fn connection_idle_time() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        std::thread::sleep(Duration::from_millis(50));
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"ping\",\"id\":1}\n")
+            .or_fail()?;
+
+        let mut request = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            request = server.try_recv();
+            if request.is_some() {
+                break;
+            }
+        }
+        let (from, incoming) = request.or_fail()?;
+        let conn = server.connection(from).or_fail()?;
+        assert!(conn.last_read_at() > conn.last_write_at());
+        let last_read_at = conn.last_read_at();
+
+        std::thread::sleep(Duration::from_millis(10));
+        let id = incoming.request().id.clone().or_fail()?;
+        server.reply_ok(&mut poller, from, id, "pong").or_fail()?;
+        let conn = server.connection(from).or_fail()?;
+        assert_eq!(conn.last_read_at(), last_read_at);
+        assert!(conn.last_write_at() > last_read_at);
+        assert!(conn.idle_for() < Duration::from_secs(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn connection_read_interest() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"foo\"}\n")
+            .or_fail()?;
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some((id, _)) = server.try_recv() {
+                from = Some(id);
+                break;
+            }
+        }
+        let mut conn = server
+            .take_connection(&mut poller, from.or_fail()?)
+            .or_fail()?;
+        let token = Token(200);
+        conn.register(&mut poller, token).or_fail()?;
+        conn.set_read_interest(&mut poller, false).or_fail()?;
+        assert!(!conn.read_interest());
+
+        // Sending does not re-enable the read interest.
+        conn.send(&mut poller, &serde_json::json!("hello"))
+            .or_fail()?;
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"bar\"}\n")
+            .or_fail()?;
+        for _ in 0..3 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(50)))
+                .or_fail()?;
+            for event in events.iter() {
+                assert!(!event.is_readable());
+                conn.handle_event(&mut poller, event, |_, _| Ok(()))
+                    .or_fail()?;
+            }
+        }
+
+        conn.set_read_interest(&mut poller, true).or_fail()?;
+        let mut received = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                assert_eq!(event.token(), token);
+                conn.handle_event(&mut poller, event, |c, _| {
+                    received = Some(c.read_value::<RequestObject>()?.method);
+                    Ok(())
+                })
+                .or_fail()?;
+            }
+            if received.is_some() {
+                break;
+            }
+        }
+        assert_eq!(received.as_deref(), Some("bar"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn pause_reading() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let request = b"{\"jsonrpc\":\"2.0\",\"method\":\"foo\"}\n";
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        stream.write_all(request).or_fail()?;
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some((id, _)) = server.try_recv() {
+                from = Some(id);
+                break;
+            }
+        }
+        let from = from.or_fail()?;
+
+        assert!(server.pause_reading(&mut poller, from));
+        assert!(server.connection(from).or_fail()?.is_reading_paused());
+        stream.write_all(request).or_fail()?;
+        stream.write_all(request).or_fail()?;
+        for _ in 0..3 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(50)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+        }
+        assert!(server.try_recv().is_none());
+
+        assert!(server.resume_reading(&mut poller, from));
+        let mut received = 0;
+        while server.try_recv().is_some() {
+            received += 1;
+        }
+        assert_eq!(received, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn connection_last_error() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"foo\"}\n")
+            .or_fail()?;
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some((id, _)) = server.try_recv() {
+                from = Some(id);
+                break;
+            }
+        }
+        let mut conn = server
+            .take_connection(&mut poller, from.or_fail()?)
+            .or_fail()?;
+        conn.register(&mut poller, Token(200)).or_fail()?;
+        assert!(conn.last_error().is_none());
+
+        stream.write_all(b"not json\n").or_fail()?;
+        let mut result = Ok(());
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                result = conn.handle_event(&mut poller, event, |c, _| {
+                    c.read_value::<RequestObject>().map(|_| ())
+                });
+            }
+            if conn.state() == ConnectionState::Closed {
+                break;
+            }
+        }
+        assert!(result.is_err());
+        assert_eq!(conn.state(), ConnectionState::Closed);
+        let error = conn.last_error().or_fail()?;
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        Ok(())
+    }
+
+    #[test]
+    fn initial_buf_capacities() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .initial_buf_capacities(128, 0)
+            .start(&mut poller)
+            .or_fail()?;
+
+        // Lines larger than the read buffer can still be received.
+        let request = format!(
+            "{{\"jsonrpc\":\"2.0\",\"method\":\"foo\",\"params\":[\"{}\"]}}\n",
+            "a".repeat(300)
+        );
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"foo\"}\n")
+            .or_fail()?;
+
+        let mut received = Vec::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            while let Some((from, _)) = server.try_recv() {
+                received.push(from);
+                if received.len() == 1 {
+                    let size = server.connection(from).or_fail()?.allocated_buf_size();
+                    assert_eq!(size, 128);
+                    stream.write_all(request.as_bytes()).or_fail()?;
+                }
+            }
+            if received.len() == 2 {
+                break;
+            }
+        }
+        assert_eq!(received.len(), 2);
+
+        assert!(server.set_buf_capacities(received[0], 16, 16));
+        let size = server
+            .connection(received[0])
+            .or_fail()?
+            .allocated_buf_size();
+        assert_eq!(size, 32);
+
+        Ok(())
+    }
+
+    #[test]
+    fn connection_raw_lines() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"foo\"}\n")
+            .or_fail()?;
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some((id, _)) = server.try_recv() {
+                from = Some(id);
+                break;
+            }
+        }
+        let mut conn = server
+            .take_connection(&mut poller, from.or_fail()?)
+            .or_fail()?;
+        conn.register(&mut poller, Token(200)).or_fail()?;
+
+        stream
+            .write_all(b"PING\n{\"jsonrpc\":\"2.0\",\"method\":\"bar\"}\n\n")
+            .or_fail()?;
+        let mut lines = Vec::new();
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                conn.handle_event_raw(&mut poller, event, |line| {
+                    lines.push(line.to_vec());
+                    Ok(())
+                })
+                .or_fail()?;
+            }
+            if lines.len() == 3 {
+                break;
+            }
+        }
+        assert_eq!(
+            lines,
+            [
+                b"PING".to_vec(),
+                b"{\"jsonrpc\":\"2.0\",\"method\":\"bar\"}".to_vec(),
+                Vec::new()
+            ]
+        );
+        assert_eq!(conn.state(), ConnectionState::Connected);
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tcp_socket_options() -> orfail::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        fn get_option(fd: i32, level: i32, name: i32) -> i32 {
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            // SAFETY: `value` and `len` are valid for writes.
+            let result = unsafe {
+                libc::getsockopt(fd, level, name, &mut value as *mut _ as *mut _, &mut len)
+            };
+            assert_eq!(result, 0);
+            value
+        }
+
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .tcp_keepalive(Duration::from_secs(30), Duration::from_secs(5), 3)
+            .recv_buffer_size(64 * 1024)
+            .start(&mut poller)
+            .or_fail()?;
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server.listen_addr())
+            .nodelay(false)
+            .tcp_keepalive(Duration::from_secs(60), Duration::from_secs(10), 5)
+            .send_buffer_size(32 * 1024)
+            .build();
+        client
+            .send_notification(&mut poller, "foo", None)
+            .or_fail()?;
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                client.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some((id, _)) = server.try_recv() {
+                from = Some(id);
+                break;
+            }
+        }
+
+        let fd = server
+            .connection(from.or_fail()?)
+            .or_fail()?
+            .stream()
+            .as_tcp()
+            .or_fail()?
+            .as_raw_fd();
+        assert_eq!(get_option(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY), 1);
+        assert_eq!(get_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+        assert_eq!(get_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 30);
+        assert_eq!(get_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL), 5);
+        assert_eq!(get_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT), 3);
+        // Linux doubles the requested size.
+        assert!(get_option(fd, libc::SOL_SOCKET, libc::SO_RCVBUF) >= 64 * 1024);
+
+        let fd = client
+            .connection()
+            .or_fail()?
+            .stream()
+            .as_tcp()
+            .or_fail()?
+            .as_raw_fd();
+        assert_eq!(get_option(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY), 0);
+        assert_eq!(get_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 60);
+        assert_eq!(get_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT), 5);
+        assert!(get_option(fd, libc::SOL_SOCKET, libc::SO_SNDBUF) >= 32 * 1024);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_watermarks() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::builder(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .token_range(SERVER_TOKEN_MIN, SERVER_TOKEN_MAX)
+            .write_watermarks(0, 64 * 1024)
+            .events(true)
+            .start(&mut poller)
+            .or_fail()?;
+
+        let server_addr = server.listen_addr();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let reader = std::thread::spawn(move || -> orfail::Result<()> {
+            let mut stream = std::net::TcpStream::connect(server_addr).or_fail()?;
+            stream
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"a\",\"id\":0}\n")
+                .or_fail()?;
+            let count = rx.recv().or_fail()?;
+            let mut reader = std::io::BufReader::new(stream);
+            for _ in 0..count {
+                let mut line = String::new();
+                std::io::BufRead::read_line(&mut reader, &mut line).or_fail()?;
+            }
+            Ok(())
+        });
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some((id, _)) = server.try_recv() {
+                from = Some(id);
+                break;
+            }
+        }
+        let from = from.or_fail()?;
+
+        let large = "x".repeat(1024 * 1024);
+        let mut replies = 0;
+        while !server.connection(from).or_fail()?.is_write_buffer_high() {
+            server
+                .reply_ok(&mut poller, from, RequestId::Number(0), &large)
+                .or_fail()?;
+            replies += 1;
+        }
+        let queued = server.connection(from).or_fail()?.queued_messages_len();
+        assert!((1..=replies).contains(&queued));
+        assert_eq!(
+            server.try_recv_event(),
+            Some(ServerEvent::WriteBufferHigh { client: from })
+        );
+        tx.send(replies).or_fail()?;
+
+        let mut low = false;
+        for _ in 0..100 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            while let Some(event) = server.try_recv_event() {
+                low |= event == ServerEvent::WriteBufferLow { client: from };
+            }
+            if low {
+                break;
+            }
+        }
+        assert!(low);
+        assert!(!server.connection(from).or_fail()?.is_write_buffer_high());
+        reader.join().expect("reader thread panicked").or_fail()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn reply_chunked() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut client = RpcClient::new(CLIENT_TOKEN, server.listen_addr());
+        let id = client.call(&mut poller, "range", None).or_fail()?;
+
+        let mut responses = Vec::new();
+        'root: for _ in 0..20 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+                if let Some((from, _)) = server.try_recv() {
+                    let request_id = serde_json::to_string(&id).or_fail()?;
+                    let mut next = 0;
+                    let status = server
+                        .reply_chunked(&mut poller, from, move |w| {
+                            if next == 0 {
+                                write!(w, r#"{{"jsonrpc":"2.0","id":{request_id},"result":["#)?;
+                            }
+                            for i in next..next + 100 {
+                                if i > 0 {
+                                    write!(w, ",")?;
+                                }
+                                write!(w, "{i}")?;
+                            }
+                            next += 100;
+                            if next < 1000 {
+                                return Ok(false);
+                            }
+                            write!(w, "]}}")?;
+                            Ok(true)
+                        })
+                        .or_fail()?;
+                    assert_eq!(status, ReplyStatus::Queued);
+
+                    // Subsequent responses are sent after the chunked one.
+                    server
+                        .reply_ok(&mut poller, from, RequestId::Number(100), "after")
+                        .or_fail()?;
+                }
+
+                client.handle_event(&mut poller, event).or_fail()?;
+                responses.extend(std::iter::from_fn(|| client.try_recv()));
+                if responses.len() == 2 {
+                    break 'root;
+                }
+            }
+        }
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].id(), Some(&id));
+        let result = responses.remove(0).into_std_result().ok().or_fail()?;
+        let values: Vec<u32> = serde_json::from_value(result).or_fail()?;
+        assert_eq!(values, (0..1000).collect::<Vec<_>>());
+        assert_eq!(responses[0].id(), Some(&RequestId::Number(100)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_reconnect_after_protocol_error() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").or_fail()?;
+        let addr = listener.local_addr().or_fail()?;
+        let server = std::thread::spawn(move || -> orfail::Result<()> {
+            let (mut stream, _) = listener.accept().or_fail()?;
+            stream.write_all(b"garbage\n").or_fail()?;
+            let (_stream, _) = listener.accept().or_fail()?;
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(())
+        });
+
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, addr)
+            .reconnect_backoff(Duration::from_millis(10), Duration::from_secs(1))
+            .auto_reconnect(true)
+            .fail_pending_on_disconnect(true)
+            .events(true)
+            .build();
+        let id = client.call(&mut poller, "foo", None).or_fail()?;
+
+        let mut protocol_error = false;
+        let mut client_events = Vec::new();
+        'root: for _ in 0..20 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(50)))
+                .or_fail()?;
+            for event in events.iter() {
+                if let Err(e) = client.handle_event(&mut poller, event) {
+                    protocol_error |= matches!(e, ClientError::Protocol(_));
+                }
+            }
+            if client.next_timeout() == Some(Duration::ZERO) {
+                client.handle_timeout(&mut poller).or_fail()?;
+            }
+            client_events.extend(std::iter::from_fn(|| client.try_recv_event()));
+            if client_events.len() == 5 {
+                break 'root;
+            }
+        }
+        assert!(protocol_error);
+        assert_eq!(client_events.len(), 5);
+        assert_eq!(client_events[0], ClientEvent::Connected);
+        assert_eq!(
+            client_events[1],
+            ClientEvent::Disconnected {
+                error_kind: std::io::ErrorKind::InvalidData
+            }
+        );
+        assert_eq!(
+            client_events[2..],
+            [
+                ClientEvent::ReconnectAttempt { attempt: 1 },
+                ClientEvent::Connected,
+                ClientEvent::Reconnected { attempts: 1 }
+            ]
+        );
+
+        // The pending call has failed on the disconnection.
+        let response = client.try_recv().or_fail()?;
+        assert_eq!(response.id(), Some(&id));
+        let code = response.into_std_result().err().map(|e| e.code);
+        assert_eq!(code, Some(error_codes::CONNECTION_CLOSED));
+
+        server.join().expect("server thread panicked").or_fail()?;
+        Ok(())
+    }
+
+    #[test]
+    fn http_response_size_limits() -> orfail::Result<()> {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        let (len, body) = http::parse_response(response, 5).or_fail()?.or_fail()?;
+        assert_eq!((len, &body[..]), (response.len(), &b"hello"[..]));
+
+        // Bodies exceeding the limit are rejected before they are received.
+        let e = http::parse_response(response, 4).expect_err("too large");
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        let e = http::parse_response(response.as_bytes(), usize::MAX).expect_err("overflow");
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n";
+        let (_, body) = http::parse_response(chunked, 6).or_fail()?.or_fail()?;
+        assert_eq!(body, b"abcdef");
+        let e = http::parse_response(chunked, 5).expect_err("too large");
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        let chunked = format!(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+            usize::MAX
+        );
+        let e = http::parse_response(chunked.as_bytes(), usize::MAX).expect_err("overflow");
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_websocket_max_message_size() -> orfail::Result<()> {
+        use std::io::BufRead;
+
+        // Announced payload lengths are checked before the payload is received.
+        let mut frame = vec![0x81, 127];
+        frame.extend_from_slice(&(1u64 << 63).to_be_bytes());
+        let e = websocket::parse_frame(&frame, 1024 * 1024).expect_err("too large");
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        let e = websocket::parse_frame(&[0x81, 16], 15).expect_err("too large");
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").or_fail()?;
+        let server_addr = listener.local_addr().or_fail()?;
+        let server = std::thread::spawn(move || -> orfail::Result<()> {
+            let (stream, _) = listener.accept().or_fail()?;
+            let mut reader = std::io::BufReader::new(stream.try_clone().or_fail()?);
+            let mut writer = stream;
+
+            let mut key = None;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).or_fail()?;
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(v) = line.strip_prefix("Sec-WebSocket-Key: ") {
+                    key = Some(v.trim().to_owned());
+                }
+            }
+            let accept = websocket::accept_key(&key.or_fail()?);
+            write!(
+                writer,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+            )
+            .or_fail()?;
+
+            // Each fragment is within the limit, but the whole message is not.
+            writer.write_all(&[0x01, 60]).or_fail()?;
+            writer.write_all(&[b'x'; 60]).or_fail()?;
+            writer.write_all(&[0x80, 60]).or_fail()?;
+            writer.write_all(&[b'x'; 60]).or_fail()?;
+
+            // Waits for the client to close the connection.
+            let _ = reader.read_to_end(&mut Vec::new());
+            Ok(())
+        });
+
+        let mut client: RpcClient = RpcClient::builder(CLIENT_TOKEN, server_addr)
+            .websocket(Some("/ws"))
+            .max_message_size(100)
+            .build();
+        client.call(&mut poller, "foo", None).or_fail()?;
+
+        let mut error = None;
+        'root: for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                if let Err(e) = client.handle_event(&mut poller, event) {
+                    error = Some(e);
+                    break 'root;
+                }
+            }
+        }
+        let error = error.or_fail()?;
+        assert_eq!(error.io_error_kind(), Some(std::io::ErrorKind::InvalidData));
+        assert_eq!(client.state(), ClientState::Disconnected);
+
+        server.join().expect("thread panicked")?;
+        Ok(())
+    }
+
+    #[test]
+    fn size_limit_errors() {
+        let e = connection::message_too_large_error();
+        assert!(connection::is_message_too_large_error(&e));
+        assert!(!connection::is_line_too_large_error(&e));
+        assert_eq!(e.io_error_kind(), Some(std::io::ErrorKind::InvalidInput));
+        let e = connection::line_too_large_error();
+        assert!(connection::is_line_too_large_error(&e));
+        assert!(!connection::is_message_too_large_error(&e));
+        assert_eq!(e.io_error_kind(), Some(std::io::ErrorKind::InvalidData));
+
+        // Errors from the underlying stream with the same kinds are not mistaken for size limit errors.
+        let e = serde_json::Error::io(std::io::ErrorKind::InvalidInput.into());
+        assert!(!connection::is_message_too_large_error(&e));
+        let e = serde_json::Error::io(std::io::Error::other("foo"));
+        assert!(!connection::is_message_too_large_error(&e));
+        let e = serde_json::Error::io(std::io::ErrorKind::InvalidData.into());
+        assert!(!connection::is_line_too_large_error(&e));
+
+        // The marker survives conversions to `std::io::Error` and back.
+        let e = serde_json::Error::io(connection::line_too_large_error().into());
+        assert!(connection::is_line_too_large_error(&e));
+    }
+
+    #[test]
+    fn request_probe() -> orfail::Result<()> {
+        #[derive(Debug, serde::Deserialize)]
+        struct MethodOnly {
+            method: String,
+        }
+
+        let line = br#"{"jsonrpc":"2.0","method":"foo","params":{"id":"x"},"id":"a"}"#;
+        let (request, probe) = probe::from_slice::<RequestObject>(line).or_fail()?;
+        assert_eq!(request.method, "foo");
+        assert!(probe.is_call());
+        assert_eq!(probe.id(), Some(&RequestId::String("a".to_owned())));
+        assert_eq!(probe.method(), Some("foo"));
+
+        // Members ignored by the request type are probed as well.
+        let line = br#"{"id":3,"method":"bar"}"#;
+        let (request, probe) = probe::from_slice::<MethodOnly>(line).or_fail()?;
+        assert_eq!(request.method, "bar");
+        assert!(probe.is_call());
+        assert_eq!(probe.id(), Some(&RequestId::Number(3)));
+
+        for line in [
+            &br#"{"method":"bar"}"#[..],
+            br#"{"method":"bar","id":null}"#,
+        ] {
+            let (_, probe) = probe::from_slice::<MethodOnly>(line).or_fail()?;
+            assert!(!probe.is_call());
+            assert_eq!(probe.id(), None);
+        }
+
+        let value = serde_json::json!({"jsonrpc": "2.0", "method": "baz", "id": 1});
+        let (_, probe) = probe::deserialize::<RequestObject, _>(&value).or_fail()?;
+        assert_eq!(probe.id(), Some(&RequestId::Number(1)));
+        assert_eq!(probe.method(), Some("baz"));
+
+        assert!(probe::from_slice::<MethodOnly>(br#"{"method":"bar"} x"#).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn peer_addr() -> orfail::Result<()> {
+        let mut poller = Poll::new().or_fail()?;
+        let mut events = Events::with_capacity(1024);
+
+        let mut server: RpcServer = RpcServer::start(
+            &mut poller,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            SERVER_TOKEN_MIN,
+            SERVER_TOKEN_MAX,
+        )
+        .or_fail()?;
+
+        let mut stream = std::net::TcpStream::connect(server.listen_addr()).or_fail()?;
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"foo\"}\n")
+            .or_fail()?;
+
+        let mut from = None;
+        for _ in 0..10 {
+            poller
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .or_fail()?;
+            for event in events.iter() {
+                server.handle_event(&mut poller, event).or_fail()?;
+            }
+            if let Some((id, _)) = server.try_recv() {
+                from = Some(id);
+                break;
+            }
+        }
+        let from = from.or_fail()?;
+        assert_eq!(server.peer_addr(from), Some(stream.local_addr().or_fail()?));
+
+        server.disconnect(&mut poller, from);
+        assert_eq!(server.peer_addr(from), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn types_are_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<RpcServer>();
+        assert_send::<RpcClientPool>();
+        assert_send::<Router>();
+    }
 }
@@ -0,0 +1,193 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use jsonlrpc::{RequestId, RequestParams, ResponseObject};
+use mio::{event::Event, Poll, Token};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::{ClientError, RpcClient},
+    slab::TokenSlab,
+};
+
+/// Pool of [`RpcClient`]s that manages one client for each server address.
+///
+/// Clients are created on demand and are assigned tokens from the range specified at construction.
+/// Responses received by the clients are collected into a single receive queue.
+#[derive(Debug)]
+pub struct RpcClientPool<RESP = ResponseObject> {
+    clients: TokenSlab<(SocketAddr, RpcClient<RESP>)>,
+    tokens: HashMap<SocketAddr, Token>,
+    factory: ClientFactory<RESP>,
+    responses: VecDeque<(SocketAddr, RESP)>,
+}
+
+impl RpcClientPool {
+    /// Makes a new [`RpcClientPool`] instance that assigns tokens in the range from `token_min` to `token_max` (inclusive).
+    pub fn new(token_min: Token, token_max: Token) -> Self {
+        Self::with_factory(token_min, token_max, |token, addr| {
+            RpcClient::builder(token, addr).build()
+        })
+    }
+}
+
+impl<RESP> RpcClientPool<RESP>
+where
+    RESP: for<'de> Deserialize<'de>,
+{
+    /// Makes a new [`RpcClientPool`] instance that creates clients using `factory`.
+    ///
+    /// `factory` is called with the token and the server address assigned to a new client
+    /// (e.g., `|token, addr| RpcClient::builder(token, addr).reconnect_backoff(..).build()`).
+    pub fn with_factory<F>(token_min: Token, token_max: Token, factory: F) -> Self
+    where
+        F: 'static + Send + FnMut(Token, SocketAddr) -> RpcClient<RESP>,
+    {
+        let capacity = token_max
+            .0
+            .checked_sub(token_min.0)
+            .map_or(0, |n| n.saturating_add(1));
+        Self {
+            clients: TokenSlab::new(token_min, capacity),
+            tokens: HashMap::new(),
+            factory: ClientFactory(Box::new(factory)),
+            responses: VecDeque::new(),
+        }
+    }
+
+    /// Sends a JSON-RPC request to the specified server.
+    ///
+    /// If there is no client for the server yet, a new one is created.
+    /// If no tokens are available, this method returns an error without sending the request.
+    pub fn send<T: Serialize>(
+        &mut self,
+        poller: &mut Poll,
+        server_addr: SocketAddr,
+        request: &T,
+    ) -> Result<(), ClientError> {
+        self.get_or_insert_client(server_addr)?
+            .send(poller, request)
+    }
+
+    /// Sends a JSON-RPC request to the specified server and returns the ID assigned to the request.
+    ///
+    /// See [`RpcClient::call()`] for details.
+    pub fn call(
+        &mut self,
+        poller: &mut Poll,
+        server_addr: SocketAddr,
+        method: &str,
+        params: Option<RequestParams>,
+    ) -> Result<RequestId, ClientError> {
+        self.get_or_insert_client(server_addr)?
+            .call(poller, method, params)
+    }
+
+    /// Takes a JSON-RPC response, along with the address of the server that sent it, from the receive queue.
+    pub fn try_recv(&mut self) -> Option<(SocketAddr, RESP)> {
+        self.responses.pop_front()
+    }
+
+    /// Handles an `mio` event.
+    ///
+    /// Events with tokens that are not assigned to any client in this pool are ignored.
+    pub fn handle_event(&mut self, poller: &mut Poll, event: &Event) -> Result<(), ClientError> {
+        let Some((addr, client)) = self.clients.get_mut(event.token()) else {
+            return Ok(());
+        };
+        let result = client.handle_event(poller, event);
+        let addr = *addr;
+        self.responses
+            .extend(std::iter::from_fn(|| client.try_recv()).map(|r| (addr, r)));
+        result
+    }
+
+    /// Returns the minimum of [`RpcClient::next_timeout()`] of the clients in this pool.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        self.clients
+            .values()
+            .filter_map(|(_, c)| c.next_timeout())
+            .min()
+    }
+
+    /// Calls [`RpcClient::handle_timeout()`] of all clients in this pool.
+    ///
+    /// If some clients fail, the first error is returned after all clients have been handled.
+    pub fn handle_timeout(&mut self, poller: &mut Poll) -> Result<(), ClientError> {
+        let mut result = Ok(());
+        for (&addr, &token) in &self.tokens {
+            let (_, client) = self.clients.get_mut(token).expect("unreachable");
+            let r = client.handle_timeout(poller);
+            self.responses
+                .extend(std::iter::from_fn(|| client.try_recv()).map(|r| (addr, r)));
+            if result.is_ok() {
+                result = r;
+            }
+        }
+        result
+    }
+
+    /// Returns the client for the specified server.
+    pub fn client(&self, server_addr: SocketAddr) -> Option<&RpcClient<RESP>> {
+        let token = self.tokens.get(&server_addr)?;
+        self.clients.get(*token).map(|(_, c)| c)
+    }
+
+    /// Returns the mutable client for the specified server.
+    ///
+    /// Note that responses are moved from the client to the pool's receive queue
+    /// whenever [`RpcClientPool::handle_event()`] or [`RpcClientPool::handle_timeout()`] is called.
+    pub fn client_mut(&mut self, server_addr: SocketAddr) -> Option<&mut RpcClient<RESP>> {
+        let token = self.tokens.get(&server_addr)?;
+        self.clients.get_mut(*token).map(|(_, c)| c)
+    }
+
+    /// Returns the clients in this pool.
+    pub fn clients(&self) -> impl '_ + Iterator<Item = &RpcClient<RESP>> {
+        self.clients.values().map(|(_, c)| c)
+    }
+
+    /// Closes and removes the client for the specified server, releasing its token.
+    ///
+    /// Returns `false` if there is no such client.
+    pub fn remove_client(&mut self, poller: &mut Poll, server_addr: SocketAddr) -> bool {
+        let Some(token) = self.tokens.remove(&server_addr) else {
+            return false;
+        };
+        let (_, mut client) = self.clients.remove(token).expect("unreachable");
+        client.close(poller);
+        true
+    }
+
+    fn get_or_insert_client(
+        &mut self,
+        server_addr: SocketAddr,
+    ) -> Result<&mut RpcClient<RESP>, ClientError> {
+        let token = match self.tokens.get(&server_addr) {
+            Some(&token) => token,
+            None => {
+                let Some(token) = self.clients.next_token() else {
+                    return Err(ClientError::Io(std::io::Error::other(
+                        "No available tokens",
+                    )));
+                };
+                let client = (self.factory.0)(token, server_addr);
+                self.clients.insert((server_addr, client));
+                self.tokens.insert(server_addr, token);
+                token
+            }
+        };
+        Ok(&mut self.clients.get_mut(token).expect("unreachable").1)
+    }
+}
+
+struct ClientFactory<RESP>(Box<dyn Send + FnMut(Token, SocketAddr) -> RpcClient<RESP>>);
+
+impl<RESP> std::fmt::Debug for ClientFactory<RESP> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ClientFactory").finish_non_exhaustive()
+    }
+}
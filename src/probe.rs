@@ -0,0 +1,422 @@
+use jsonlrpc::RequestId;
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+
+/// Top-level `id` and `method` members observed while a request was being deserialized.
+#[derive(Debug, Default)]
+pub(crate) struct RequestProbe {
+    is_object: bool,
+    has_id: bool,
+    id: Option<RequestId>,
+    method: Option<String>,
+}
+
+impl RequestProbe {
+    /// Returns `false` if the request is an object without a non-null `id` member (i.e., a notification).
+    pub(crate) fn is_call(&self) -> bool {
+        !self.is_object || self.has_id
+    }
+
+    pub(crate) fn id(&self) -> Option<&RequestId> {
+        self.id.as_ref()
+    }
+
+    pub(crate) fn method(&self) -> Option<&str> {
+        self.method.as_deref()
+    }
+}
+
+/// Deserializes `T` while recording its top-level `id` and `method` members,
+/// so that the input does not have to be parsed again to inspect them.
+pub(crate) fn deserialize<'de, T, D>(deserializer: D) -> Result<(T, RequestProbe), D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    let mut probe = RequestProbe::default();
+    let value = T::deserialize(RootDeserializer {
+        inner: deserializer,
+        probe: &mut probe,
+    })?;
+    Ok((value, probe))
+}
+
+/// Same as [`deserialize()`] but for a JSON text.
+pub(crate) fn from_slice<'de, T>(json: &'de [u8]) -> serde_json::Result<(T, RequestProbe)>
+where
+    T: Deserialize<'de>,
+{
+    let mut deserializer = serde_json::Deserializer::from_slice(json);
+    let result = deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(result)
+}
+
+macro_rules! forward_deserialize {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            fn $method<V>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, Self::Error>
+            where
+                V: Visitor<'de>,
+            {
+                let (inner, visitor) = self.wrap(visitor);
+                inner.$method($($arg,)* visitor)
+            }
+        )*
+    };
+}
+
+macro_rules! forward_all_deserialize {
+    () => {
+        forward_deserialize! {
+            deserialize_any();
+            deserialize_bool();
+            deserialize_i8();
+            deserialize_i16();
+            deserialize_i32();
+            deserialize_i64();
+            deserialize_i128();
+            deserialize_u8();
+            deserialize_u16();
+            deserialize_u32();
+            deserialize_u64();
+            deserialize_u128();
+            deserialize_f32();
+            deserialize_f64();
+            deserialize_char();
+            deserialize_str();
+            deserialize_string();
+            deserialize_bytes();
+            deserialize_byte_buf();
+            deserialize_option();
+            deserialize_unit();
+            deserialize_unit_struct(name: &'static str);
+            deserialize_newtype_struct(name: &'static str);
+            deserialize_seq();
+            deserialize_tuple(len: usize);
+            deserialize_tuple_struct(name: &'static str, len: usize);
+            deserialize_map();
+            deserialize_struct(name: &'static str, fields: &'static [&'static str]);
+            deserialize_enum(name: &'static str, variants: &'static [&'static str]);
+            deserialize_identifier();
+        }
+
+        fn is_human_readable(&self) -> bool {
+            self.inner.is_human_readable()
+        }
+    };
+}
+
+macro_rules! forward_visit {
+    ($($method:ident($ty:ty);)*) => {
+        $(
+            fn $method<E: de::Error>(self, v: $ty) -> Result<Self::Value, E> {
+                self.inner.$method(v)
+            }
+        )*
+    };
+}
+
+macro_rules! forward_visit_common {
+    () => {
+        fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            self.inner.expecting(f)
+        }
+
+        forward_visit! {
+            visit_bool(bool);
+            visit_i8(i8);
+            visit_i16(i16);
+            visit_i32(i32);
+            visit_i128(i128);
+            visit_u8(u8);
+            visit_u16(u16);
+            visit_u32(u32);
+            visit_u128(u128);
+            visit_f32(f32);
+            visit_f64(f64);
+            visit_char(char);
+            visit_bytes(&[u8]);
+            visit_borrowed_bytes(&'de [u8]);
+            visit_byte_buf(Vec<u8>);
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+            self.inner.visit_seq(seq)
+        }
+
+        fn visit_enum<A: de::EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+            self.inner.visit_enum(data)
+        }
+    };
+}
+
+struct RootDeserializer<'p, D> {
+    inner: D,
+    probe: &'p mut RequestProbe,
+}
+
+impl<'p, D> RootDeserializer<'p, D> {
+    fn wrap<V>(self, visitor: V) -> (D, RootVisitor<'p, V>) {
+        let visitor = RootVisitor {
+            inner: visitor,
+            probe: self.probe,
+        };
+        (self.inner, visitor)
+    }
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for RootDeserializer<'_, D> {
+    type Error = D::Error;
+
+    forward_all_deserialize!();
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.inner.deserialize_ignored_any(visitor)
+    }
+}
+
+struct RootVisitor<'p, V> {
+    inner: V,
+    probe: &'p mut RequestProbe,
+}
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for RootVisitor<'_, V> {
+    type Value = V::Value;
+
+    forward_visit_common!();
+
+    forward_visit! {
+        visit_i64(i64);
+        visit_u64(u64);
+        visit_str(&str);
+        visit_borrowed_str(&'de str);
+        visit_string(String);
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.inner.visit_none()
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.inner.visit_unit()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Self::Value, D::Error> {
+        self.inner.visit_some(RootDeserializer {
+            inner: d,
+            probe: self.probe,
+        })
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(self, d: D) -> Result<Self::Value, D::Error> {
+        self.inner.visit_newtype_struct(RootDeserializer {
+            inner: d,
+            probe: self.probe,
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        self.probe.is_object = true;
+        self.inner.visit_map(ProbeMapAccess {
+            inner: map,
+            probe: self.probe,
+            key: Key::Other,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Id,
+    Method,
+    Other,
+}
+
+struct ProbeMapAccess<'p, A> {
+    inner: A,
+    probe: &'p mut RequestProbe,
+    key: Key,
+}
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for ProbeMapAccess<'_, A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        self.key = Key::Other;
+        self.inner.next_key_seed(Captured {
+            inner: seed,
+            capture: Capture::Key(&mut self.key),
+        })
+    }
+
+    fn next_value_seed<S>(&mut self, seed: S) -> Result<S::Value, Self::Error>
+    where
+        S: DeserializeSeed<'de>,
+    {
+        match self.key {
+            Key::Id => {
+                self.probe.has_id = true;
+                self.probe.id = None;
+                self.inner.next_value_seed(Captured {
+                    inner: seed,
+                    capture: Capture::Id(&mut *self.probe),
+                })
+            }
+            Key::Method => {
+                self.probe.method = None;
+                self.inner.next_value_seed(Captured {
+                    inner: seed,
+                    capture: Capture::Method(&mut self.probe.method),
+                })
+            }
+            Key::Other => self.inner.next_value_seed(seed),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner.size_hint()
+    }
+}
+
+/// Destination of the scalar value visited by a [`Captured`] deserializer.
+enum Capture<'p> {
+    Key(&'p mut Key),
+    Id(&'p mut RequestProbe),
+    Method(&'p mut Option<String>),
+}
+
+impl Capture<'_> {
+    fn null(self) {
+        if let Self::Id(probe) = self {
+            probe.has_id = false;
+        }
+    }
+
+    fn integer(self, v: Option<i64>) {
+        if let Self::Id(probe) = self {
+            probe.id = v.map(RequestId::Number);
+        }
+    }
+
+    fn str(self, v: &str) {
+        match self {
+            Self::Key(key) => {
+                *key = match v {
+                    "id" => Key::Id,
+                    "method" => Key::Method,
+                    _ => Key::Other,
+                }
+            }
+            Self::Id(probe) => probe.id = Some(RequestId::String(v.to_owned())),
+            Self::Method(method) => *method = Some(v.to_owned()),
+        }
+    }
+}
+
+/// Seed, deserializer and visitor wrapper that records the visited scalar value into `capture`.
+struct Captured<'p, T> {
+    inner: T,
+    capture: Capture<'p>,
+}
+
+impl<'p, T> Captured<'p, T> {
+    fn wrap<V>(self, visitor: V) -> (T, Captured<'p, V>) {
+        let visitor = Captured {
+            inner: visitor,
+            capture: self.capture,
+        };
+        (self.inner, visitor)
+    }
+}
+
+impl<'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for Captured<'_, S> {
+    type Value = S::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, d: D) -> Result<Self::Value, D::Error> {
+        self.inner.deserialize(Captured {
+            inner: d,
+            capture: self.capture,
+        })
+    }
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for Captured<'_, D> {
+    type Error = D::Error;
+
+    forward_all_deserialize!();
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        // Members ignored by the request type still need to be inspected.
+        let (inner, visitor) = self.wrap(visitor);
+        inner.deserialize_any(visitor)
+    }
+}
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for Captured<'_, V> {
+    type Value = V::Value;
+
+    forward_visit_common!();
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        self.capture.integer(Some(v));
+        self.inner.visit_i64(v)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        self.capture.integer(i64::try_from(v).ok());
+        self.inner.visit_u64(v)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        self.capture.str(v);
+        self.inner.visit_str(v)
+    }
+
+    fn visit_borrowed_str<E: de::Error>(self, v: &'de str) -> Result<Self::Value, E> {
+        self.capture.str(v);
+        self.inner.visit_borrowed_str(v)
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+        self.capture.str(&v);
+        self.inner.visit_string(v)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.capture.null();
+        self.inner.visit_none()
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.capture.null();
+        self.inner.visit_unit()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Self::Value, D::Error> {
+        self.inner.visit_some(Captured {
+            inner: d,
+            capture: self.capture,
+        })
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(self, d: D) -> Result<Self::Value, D::Error> {
+        self.inner.visit_newtype_struct(Captured {
+            inner: d,
+            capture: self.capture,
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        self.inner.visit_map(map)
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+/// Parses a [PROXY protocol] (v1 or v2) header at the beginning of `buf`.
+///
+/// Returns `Ok(None)` if more data is needed.
+/// Otherwise, returns the length of the header and the source address it conveys
+/// (`None` if the header does not convey an address, e.g., `UNKNOWN` or `LOCAL`).
+///
+/// [PROXY protocol]: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+pub(crate) fn parse_header(buf: &[u8]) -> std::io::Result<Option<(usize, Option<SocketAddr>)>> {
+    if is_prefix(buf, V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if is_prefix(buf, V1_PREFIX) {
+        parse_v1(buf)
+    } else {
+        Err(invalid_header_error())
+    }
+}
+
+fn is_prefix(buf: &[u8], prefix: &[u8]) -> bool {
+    let n = buf.len().min(prefix.len());
+    buf[..n] == prefix[..n]
+}
+
+fn parse_v1(buf: &[u8]) -> std::io::Result<Option<(usize, Option<SocketAddr>)>> {
+    let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(invalid_header_error());
+        }
+        return Ok(None);
+    };
+    let line =
+        std::str::from_utf8(&buf[V1_PREFIX.len()..end]).map_err(|_| invalid_header_error())?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    let addr = match fields.as_slice() {
+        ["UNKNOWN", ..] => None,
+        ["TCP4", src, _, sport, _] => {
+            let ip = src
+                .parse::<Ipv4Addr>()
+                .map_err(|_| invalid_header_error())?;
+            Some(SocketAddr::from((ip, parse_port(sport)?)))
+        }
+        ["TCP6", src, _, sport, _] => {
+            let ip = src
+                .parse::<Ipv6Addr>()
+                .map_err(|_| invalid_header_error())?;
+            Some(SocketAddr::from((ip, parse_port(sport)?)))
+        }
+        _ => return Err(invalid_header_error()),
+    };
+    Ok(Some((end + 2, addr)))
+}
+
+fn parse_port(s: &str) -> std::io::Result<u16> {
+    s.parse().map_err(|_| invalid_header_error())
+}
+
+fn parse_v2(buf: &[u8]) -> std::io::Result<Option<(usize, Option<SocketAddr>)>> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let version_command = buf[12];
+    let family = buf[13];
+    let len = usize::from(u16::from_be_bytes([buf[14], buf[15]]));
+    if version_command >> 4 != 2 {
+        return Err(invalid_header_error());
+    }
+    let total_len = V2_HEADER_LEN + len;
+    if buf.len() < total_len {
+        return Ok(None);
+    }
+
+    let body = &buf[V2_HEADER_LEN..total_len];
+    let addr = match (version_command & 0x0F, family) {
+        // LOCAL command (e.g., health checks by the proxy itself).
+        (0, _) => None,
+        (1, 0x11) if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            Some(SocketAddr::from((
+                ip,
+                u16::from_be_bytes([body[8], body[9]]),
+            )))
+        }
+        (1, 0x21) if body.len() >= 36 => {
+            let octets: [u8; 16] = body[..16].try_into().expect("unreachable");
+            let ip = Ipv6Addr::from(octets);
+            Some(SocketAddr::from((
+                ip,
+                u16::from_be_bytes([body[32], body[33]]),
+            )))
+        }
+        // Unsupported address families (e.g., UNIX) don't convey a usable socket address.
+        (1, _) => None,
+        _ => return Err(invalid_header_error()),
+    };
+    Ok(Some((total_len, addr)))
+}
+
+fn invalid_header_error() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, "Invalid PROXY protocol header")
+}
@@ -0,0 +1,38 @@
+use jsonlrpc::{JsonRpcVersion, RequestId};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+/// JSON-RPC request whose parameters are kept as raw JSON until explicitly decoded.
+///
+/// Using this type as the request type of [`RpcServer`](crate::RpcServer)
+/// (i.e., `RpcServer<RawRequest>`) avoids decoding parameters that are only forwarded
+/// (e.g., by proxies) or that are decoded into handler-specific types later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawRequest {
+    /// JSON-RPC version.
+    pub jsonrpc: JsonRpcVersion,
+
+    /// Request ID.
+    ///
+    /// If `None`, the request is a notification.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+
+    /// Method name.
+    pub method: String,
+
+    /// Raw JSON of the request parameters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Box<RawValue>>,
+}
+
+impl RawRequest {
+    /// Decodes the parameters into `T` (missing parameters are treated as `null`).
+    pub fn parse_params<T>(&self) -> serde_json::Result<T>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let json = self.params.as_ref().map_or("null", |p| p.get());
+        serde_json::from_str(json)
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+use jsonlrpc::{ErrorCode, ErrorObject, JsonRpcVersion, RequestObject, ResponseObject};
+use serde::{Deserialize, Serialize};
+
+type Handler = Box<dyn Send + FnMut(serde_json::Value) -> Result<serde_json::Value, ErrorObject>>;
+
+/// Method router that dispatches JSON-RPC requests to the handlers registered for each method name.
+///
+/// See also: [`RpcServer::dispatch()`](crate::RpcServer::dispatch)
+///
+/// # Examples
+///
+/// ```
+/// use jsonlrpc::{JsonRpcVersion, RequestId, RequestObject, ResponseObject};
+/// use jsonlrpc_mio::Router;
+///
+/// let mut router = Router::new();
+/// router.route("sum", |params: Vec<i64>| Ok(params.iter().sum::<i64>()));
+///
+/// let request = RequestObject {
+///     jsonrpc: JsonRpcVersion::V2,
+///     id: Some(RequestId::Number(1)),
+///     method: "sum".to_owned(),
+///     params: Some(serde_json::from_str("[1, 2, 3]").unwrap()),
+/// };
+/// let response = router.handle(request).unwrap();
+/// assert_eq!(response.into_std_result(), Ok(serde_json::json!(6)));
+/// ```
+#[derive(Default)]
+pub struct Router {
+    handlers: HashMap<String, Handler>,
+}
+
+impl Router {
+    /// Makes a new [`Router`] instance without any routes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler for the specified method.
+    ///
+    /// The request parameters are deserialized into `P` (missing parameters are treated as `null`).
+    /// If the deserialization fails, the router responds with an [`ErrorCode::INVALID_PARAMS`] error
+    /// without calling the handler.
+    pub fn route<P, R, F>(&mut self, method: &str, mut handler: F) -> &mut Self
+    where
+        P: for<'de> Deserialize<'de>,
+        R: Serialize,
+        F: 'static + Send + FnMut(P) -> Result<R, ErrorObject>,
+    {
+        let handler = move |params: serde_json::Value| {
+            let params = serde_json::from_value(params).map_err(|e| ErrorObject {
+                code: ErrorCode::INVALID_PARAMS,
+                message: e.to_string(),
+                data: None,
+            })?;
+            let result = handler(params)?;
+            serde_json::to_value(result).map_err(|e| ErrorObject {
+                code: ErrorCode::INTERNAL_ERROR,
+                message: e.to_string(),
+                data: None,
+            })
+        };
+        self.handlers.insert(method.to_owned(), Box::new(handler));
+        self
+    }
+
+    /// Returns `true` if a handler is registered for the specified method.
+    pub fn contains(&self, method: &str) -> bool {
+        self.handlers.contains_key(method)
+    }
+
+    /// Handles a JSON-RPC request and returns the response.
+    ///
+    /// Requests for unregistered methods result in an [`ErrorCode::METHOD_NOT_FOUND`] error.
+    /// If the request is a notification, this method returns `None` even if an error occurs.
+    pub fn handle(&mut self, request: RequestObject) -> Option<ResponseObject> {
+        let result = match self.handlers.get_mut(&request.method) {
+            None => Err(ErrorObject {
+                code: ErrorCode::METHOD_NOT_FOUND,
+                message: format!("Method not found: {}", request.method),
+                data: None,
+            }),
+            Some(handler) => {
+                let params = request
+                    .params
+                    .map(|params| serde_json::to_value(params).expect("unreachable"))
+                    .unwrap_or(serde_json::Value::Null);
+                handler(params)
+            }
+        };
+
+        let id = request.id?;
+        Some(match result {
+            Ok(result) => ResponseObject::Ok {
+                jsonrpc: JsonRpcVersion::V2,
+                id,
+                result,
+            },
+            Err(error) => ResponseObject::Err {
+                jsonrpc: JsonRpcVersion::V2,
+                id: Some(id),
+                error,
+            },
+        })
+    }
+}
+
+impl std::fmt::Debug for Router {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Router")
+            .field("methods", &self.handlers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
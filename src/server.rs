@@ -1,74 +1,288 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    io::ErrorKind,
+    any::Any,
+    collections::{HashMap, HashSet, VecDeque},
+    io::{ErrorKind, Write},
     marker::PhantomData,
-    net::SocketAddr,
+    net::{IpAddr, Shutdown, SocketAddr},
+    time::{Duration, Instant},
 };
 
-use jsonlrpc::{ErrorCode, ErrorObject, RequestObject, ResponseObject};
-use mio::{
-    event::Event,
-    net::{TcpListener, TcpStream},
-    Interest, Poll, Token,
-};
+use jsonlrpc::{ErrorCode, ErrorObject, RequestId, RequestObject, RequestParams, ResponseObject};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use mio::net::UnixListener;
+use mio::{event::Event, net::TcpListener, Interest, Poll, Token};
 use serde::{Deserialize, Serialize};
 
-use crate::connection::{Connection, ConnectionState};
+use crate::{
+    connection::{
+        is_line_too_large_error, is_message_too_large_error, message_too_large_error, Connection,
+        ConnectionState, READ_CHUNK_SIZE,
+    },
+    error_codes::{PAYLOAD_TOO_LARGE, SERVER_OVERLOADED},
+    probe::{self, RequestProbe},
+    proxy,
+    router::Router,
+    slab::TokenSlab,
+    socket::{self, ListenerOptions, SocketOptions, TcpKeepalive},
+    stream::Stream,
+};
 
 /// RPC server.
 #[derive(Debug)]
 pub struct RpcServer<REQ = RequestObject> {
-    listen_addr: SocketAddr,
-    listener: TcpListener,
+    listen_addr: Option<SocketAddr>,
+    listeners: Vec<Listener>,
+    listener_count: usize,
+    listener_options: ListenerOptions,
+    accepting: bool,
+    socket_options: SocketOptions,
     token_min: Token,
-    token_max: Token,
-    next_token: Token,
-    connections: HashMap<Token, Connection>,
-    requests: VecDeque<(ClientId, REQ)>,
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    connections_per_ip: HashMap<IpAddr, usize>,
+    accept_filter: Option<AcceptFilter>,
+    connections: TokenSlab<ClientConnection>,
+    next_generation: u64,
+    idle_timeout: Option<Duration>,
+    write_stall_timeout: Option<Duration>,
+    read_rate_limit: Option<(u64, u64)>,
+    max_request_size: usize,
+    max_response_size: usize,
+    max_write_buf_size: usize,
+    write_watermarks: Option<(usize, usize)>,
+    max_retained_buf_size: usize,
+    buf_shrink_delay: Duration,
+    initial_buf_capacities: (usize, usize),
+    max_in_flight_requests: usize,
+    max_accepts_per_event: usize,
+    methods: Option<HashSet<String>>,
+    parse_error_policy: ParseErrorPolicy,
+    next_idle_check: Option<Instant>,
+    next_stall_check: Option<Instant>,
+    next_read_resume: Option<Instant>,
+    next_drain_check: Option<Instant>,
+    accept_backoff: Duration,
+    accept_resume_at: Option<Instant>,
+    proxy_protocol: bool,
+    events_enabled: bool,
+    events: VecDeque<ServerEvent>,
+    stats: ServerStats,
+    requests: VecDeque<(RequestContext, Incoming<REQ>)>,
+    responses: VecDeque<(ClientId, ResponseObject)>,
+    next_request_id: i64,
     _request: PhantomData<REQ>,
 }
 
+impl RpcServer {
+    /// Makes a new [`RpcServerBuilder`] instance.
+    ///
+    /// Note that the builder can also start servers with request types other than [`RequestObject`].
+    pub fn builder(listen_addr: SocketAddr) -> RpcServerBuilder {
+        RpcServerBuilder::new(listen_addr)
+    }
+
+    /// Makes a new [`RpcServerBuilder`] instance for a server that listens on both
+    /// the IPv4 address `0.0.0.0:port` and the IPv6 address `[::]:port`.
+    ///
+    /// `IPV6_V6ONLY` is enabled on the IPv6 listener so that the two listeners don't conflict.
+    /// If `port` is `0`, both listeners use the same port assigned to the IPv4 listener.
+    pub fn builder_dual_stack(port: u16) -> RpcServerBuilder {
+        RpcServerBuilder::dual_stack(port)
+    }
+
+    /// Makes a new [`RpcServerBuilder`] instance for a server that listens on a Unix domain socket.
+    ///
+    /// Starting the server fails if a file already exists at `path`.
+    #[cfg(unix)]
+    pub fn builder_unix<P: AsRef<Path>>(path: P) -> RpcServerBuilder {
+        RpcServerBuilder::without_listeners().add_unix_listen_path(path)
+    }
+
+    /// Dispatches all requests in the receive queue to `router` and sends the responses.
+    ///
+    /// This method is intended to be called after [`RpcServer::handle_event()`],
+    /// instead of processing requests one by one via [`RpcServer::try_recv()`].
+    pub fn dispatch(&mut self, poller: &mut Poll, router: &mut Router) -> std::io::Result<()> {
+        while let Some((from, incoming)) = self.try_recv() {
+            if let Some(response) = router.handle(incoming.into_request()) {
+                self.reply(poller, from, &response)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl<REQ> RpcServer<REQ>
 where
     REQ: for<'de> Deserialize<'de>,
 {
     /// Starts an [`RpcServer`] that listens on the specified address.
+    ///
+    /// This is equivalent to `RpcServer::builder(listen_addr).token_range(token_min, token_max).start(poller)`.
     pub fn start(
         poller: &mut Poll,
         listen_addr: SocketAddr,
         token_min: Token,
         token_max: Token,
     ) -> std::io::Result<Self> {
-        if token_min > token_max {
+        RpcServerBuilder::new(listen_addr)
+            .token_range(token_min, token_max)
+            .start(poller)
+    }
+
+    /// Starts an [`RpcServer`] with `SO_REUSEPORT` enabled on the listening socket.
+    ///
+    /// This allows multiple servers (e.g., one per thread, each with its own [`Poll`])
+    /// to listen on the same address, letting the kernel distribute incoming connections among them.
+    ///
+    /// This is equivalent to `RpcServer::builder(listen_addr).token_range(token_min, token_max).reuse_port(true).start(poller)`.
+    pub fn start_reuseport(
+        poller: &mut Poll,
+        listen_addr: SocketAddr,
+        token_min: Token,
+        token_max: Token,
+    ) -> std::io::Result<Self> {
+        RpcServerBuilder::new(listen_addr)
+            .token_range(token_min, token_max)
+            .reuse_port(true)
+            .start(poller)
+    }
+
+    /// Starts an [`RpcServer`] that listens on both `0.0.0.0:port` and `[::]:port`.
+    ///
+    /// This is equivalent to `RpcServer::builder_dual_stack(port).token_range(token_min, token_max).start(poller)`.
+    pub fn start_dual_stack(
+        poller: &mut Poll,
+        port: u16,
+        token_min: Token,
+        token_max: Token,
+    ) -> std::io::Result<Self> {
+        RpcServerBuilder::dual_stack(port)
+            .token_range(token_min, token_max)
+            .start(poller)
+    }
+
+    /// Returns the address on which this server is listening.
+    ///
+    /// If the server listens on multiple addresses, this method returns the first one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server listens only on Unix domain sockets.
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr.expect("no TCP listener")
+    }
+
+    /// Returns all TCP addresses on which this server is listening.
+    pub fn listen_addrs(&self) -> impl '_ + Iterator<Item = SocketAddr> {
+        self.listeners.iter().filter_map(|l| match l {
+            Listener::Tcp { addr, .. } => Some(*addr),
+            #[cfg(unix)]
+            Listener::Unix { .. } => None,
+        })
+    }
+
+    /// Replaces the listener of [`RpcServer::listen_addr()`] with a new one bound to `new_addr`.
+    ///
+    /// The new listener is bound before the old one is closed, and established connections are kept alive.
+    /// Returns the address to which the new listener is actually bound.
+    pub fn rebind(
+        &mut self,
+        poller: &mut Poll,
+        new_addr: SocketAddr,
+    ) -> std::io::Result<SocketAddr> {
+        let Some(index) = self
+            .listeners
+            .iter()
+            .position(|l| matches!(l, Listener::Tcp { .. }))
+        else {
             return Err(std::io::Error::new(
                 ErrorKind::InvalidInput,
-                "Empty token range",
+                "No TCP listener to rebind",
             ));
+        };
+
+        let listener = socket::bind_listener(new_addr, self.listener_options)?;
+        let addr = listener.local_addr()?;
+        let mut listener = Listener::Tcp { addr, listener };
+        if self.accepting {
+            // The old listener is kept registered until the new one is, so that a failure leaves the server accepting.
+            listener.register(poller, Token(self.token_min.0 + index))?;
+            if let Err(e) = self.listeners[index].deregister(poller) {
+                let _ = listener.deregister(poller);
+                return Err(e);
+            }
         }
+        self.listeners[index] = listener;
+        self.listen_addr = Some(addr);
+        Ok(addr)
+    }
 
-        let mut listener = TcpListener::bind(listen_addr)?;
-        let listen_addr = listener.local_addr()?;
-        poller
-            .registry()
-            .register(&mut listener, token_min, Interest::READABLE)?;
-        Ok(Self {
-            listen_addr,
-            listener,
-            token_min,
-            token_max,
-            next_token: Token(token_min.0 + 1),
-            connections: HashMap::new(),
-            requests: VecDeque::new(),
-            _request: PhantomData,
+    /// Changes the range of `mio` tokens used by the server.
+    ///
+    /// Since the tokens of the listening sockets and the existing connections cannot be changed,
+    /// `token_min` must be the same as the current one.
+    /// If the range shrinks, existing connections holding tokens beyond `token_max` are kept until they are closed.
+    pub fn set_token_range(&mut self, token_min: Token, token_max: Token) -> std::io::Result<()> {
+        if token_min != self.token_min {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Minimum token cannot be changed",
+            ));
+        }
+        let end = token_max.0.checked_add(1).ok_or_else(|| {
+            std::io::Error::new(ErrorKind::InvalidInput, "Maximum token is too large")
+        })?;
+        let capacity = end
+            .checked_sub(token_min.0 + self.listener_count)
+            .ok_or_else(|| {
+                std::io::Error::new(ErrorKind::InvalidInput, "Token range is too small")
+            })?;
+        self.connections.set_capacity(capacity);
+        Ok(())
+    }
+
+    /// Returns all Unix domain socket paths on which this server is listening.
+    #[cfg(unix)]
+    pub fn unix_listen_paths(&self) -> impl '_ + Iterator<Item = &Path> {
+        self.listeners.iter().filter_map(|l| match l {
+            Listener::Tcp { .. } => None,
+            Listener::Unix { path, .. } => Some(path.as_path()),
         })
     }
 
-    /// Returns the address on which this server is listening.
-    pub fn listen_addr(&self) -> SocketAddr {
-        self.listen_addr
+    /// Sets the maximum number of concurrent client connections (the default is unlimited).
+    ///
+    /// Connections accepted beyond this limit receive a [`SERVER_OVERLOADED`](crate::error_codes::SERVER_OVERLOADED)
+    /// error response and are then closed.
+    ///
+    /// Note that the number of connections is also limited by the token range.
+    pub fn set_max_connections(&mut self, max: usize) {
+        self.max_connections = max;
+    }
+
+    /// Returns the maximum number of concurrent client connections.
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// Sets a predicate that decides whether to accept a new connection from the given peer address.
+    ///
+    /// Connections for which the predicate returns `false` are closed immediately,
+    /// without consuming a token.
+    pub fn set_accept_filter<F>(&mut self, filter: F)
+    where
+        F: 'static + Send + FnMut(SocketAddr) -> bool,
+    {
+        self.accept_filter = Some(AcceptFilter(Box::new(filter)));
     }
 
-    /// Takes a JSON-RPC request from the receive queue.
+    /// Takes a JSON-RPC request or notification from the receive queue.
+    ///
+    /// Note that [`Incoming::Notification`] must not be replied to.
     ///
     /// # NOTE
     ///
@@ -76,173 +290,2192 @@ where
     /// it is your responsibility to specify a sufficiently large
     /// token space when calling [`RpcServer::start()`]
     /// to prevent the ABA problem.
-    pub fn try_recv(&mut self) -> Option<(ClientId, REQ)> {
+    pub fn try_recv(&mut self) -> Option<(ClientId, Incoming<REQ>)> {
+        self.requests
+            .pop_front()
+            .map(|(context, incoming)| (context.client, incoming))
+    }
+
+    /// Same as [`RpcServer::try_recv()`] but also returns the [`RequestContext`] of the request.
+    pub fn try_recv_with_context(&mut self) -> Option<(RequestContext, Incoming<REQ>)> {
         self.requests.pop_front()
     }
 
+    /// Takes all JSON-RPC requests and notifications from the receive queue.
+    ///
+    /// The same note as [`RpcServer::try_recv()`] applies to this method.
+    pub fn drain_requests(&mut self) -> impl '_ + Iterator<Item = (ClientId, Incoming<REQ>)> {
+        self.requests
+            .drain(..)
+            .map(|(context, incoming)| (context.client, incoming))
+    }
+
+    /// Returns the number of requests and notifications in the receive queue.
+    pub fn queued_requests_len(&self) -> usize {
+        self.requests.len()
+    }
+
     /// Sends a JSON-RPC response.
+    ///
+    /// If the response is for a request that was part of a batch request,
+    /// it is held until all requests in the batch have been replied to,
+    /// and then sent as a single batch response.
     pub fn reply<T: Serialize>(
         &mut self,
         poller: &mut Poll,
         from: ClientId,
         response: &T,
+    ) -> std::io::Result<ReplyStatus> {
+        let Some(client) = self.client_mut(from) else {
+            return Ok(ReplyStatus::Dropped);
+        };
+
+        let result = if client.batches.is_empty() {
+            client.connection.send(poller, response).map(|()| true)
+        } else {
+            client.reply_batch_member(poller, response)
+        };
+        check_message_size(&result)?;
+        Ok(self.finish_reply(poller, from, result, 1))
+    }
+
+    /// Sends multiple JSON-RPC responses to a client with a single write.
+    ///
+    /// Responses for requests that were part of a batch request are handled
+    /// in the same way as [`RpcServer::reply()`].
+    pub fn reply_batch<'a, T, I>(
+        &mut self,
+        poller: &mut Poll,
+        from: ClientId,
+        responses: I,
+    ) -> std::io::Result<ReplyStatus>
+    where
+        T: 'a + Serialize,
+        I: IntoIterator<Item = &'a T>,
+    {
+        let Some(client) = self.client_mut(from) else {
+            return Ok(ReplyStatus::Dropped);
+        };
+
+        let mut count = 0;
+        let result = if client.batches.is_empty() {
+            let responses = responses.into_iter().inspect(|_| count += 1);
+            client.connection.send_all(poller, responses).map(|()| true)
+        } else {
+            responses.into_iter().try_fold(false, |written, response| {
+                count += 1;
+                Ok(client.reply_batch_member(poller, response)? || written)
+            })
+        };
+        check_message_size(&result)?;
+        Ok(self.finish_reply(poller, from, result, count))
+    }
+
+    /// Sends a JSON-RPC success response with the given result.
+    ///
+    /// This is a shorthand for [`RpcServer::reply()`] with [`ResponseObject::Ok`],
+    /// except that `result` is serialized directly into the write buffer without
+    /// being converted into a [`serde_json::Value`] first (unless it is part of a batch response).
+    pub fn reply_ok<T: Serialize>(
+        &mut self,
+        poller: &mut Poll,
+        from: ClientId,
+        id: RequestId,
+        result: T,
+    ) -> std::io::Result<ReplyStatus> {
+        let response = OkResponse {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            id: &id,
+            result: &result,
+        };
+        self.reply(poller, from, &response)
+    }
+
+    /// Sends a JSON-RPC response written by `write` directly into the write buffer of the connection.
+    ///
+    /// This allows large responses to be serialized in a streaming manner (e.g., via [`serde_json::to_writer()`]).
+    /// `write` must write exactly one JSON value without any newlines;
+    /// if `write` fails (or the response exceeds [`RpcServerBuilder::max_response_size()`]),
+    /// nothing is sent and the error is returned.
+    ///
+    /// Note that, unlike [`RpcServer::reply()`], responses sent via this method are never combined into batch responses.
+    pub fn reply_with<F>(
+        &mut self,
+        poller: &mut Poll,
+        from: ClientId,
+        write: F,
+    ) -> std::io::Result<ReplyStatus>
+    where
+        F: FnOnce(&mut dyn Write) -> std::io::Result<()>,
+    {
+        let Some(client) = self.client_mut(from) else {
+            return Ok(ReplyStatus::Dropped);
+        };
+        let result = client.connection.send_with(poller, write)?;
+        Ok(self.finish_reply(poller, from, result.map(|()| true), 1))
+    }
+
+    /// Sends a JSON-RPC response whose content is written chunk by chunk by `produce`.
+    ///
+    /// Unlike [`RpcServer::reply_with()`], a huge response does not have to be serialized at once:
+    /// `produce` is called to write the next chunk of the response each time the previous chunk has been
+    /// written to the socket (at most once per writable event), and returns `Ok(true)` once the response is complete.
+    /// Other messages sent to the client in the meantime are queued after the response.
+    /// The response must be exactly one JSON value without any newlines.
+    ///
+    /// As part of the response may already have been sent, the connection is closed with [`DisconnectReason::Error`]
+    /// if `produce` fails or the response exceeds [`RpcServerBuilder::max_response_size()`].
+    /// Note that responses sent via this method are never combined into batch responses.
+    pub fn reply_chunked<F>(
+        &mut self,
+        poller: &mut Poll,
+        from: ClientId,
+        produce: F,
+    ) -> std::io::Result<ReplyStatus>
+    where
+        F: 'static + Send + FnMut(&mut dyn Write) -> std::io::Result<bool>,
+    {
+        let Some(client) = self.client_mut(from) else {
+            return Ok(ReplyStatus::Dropped);
+        };
+        let result = client.connection.send_chunked(poller, produce);
+        Ok(self.finish_reply(poller, from, result.map(|()| true), 1))
+    }
+
+    /// Sends a JSON-RPC error response with the given code and message.
+    ///
+    /// This is a shorthand for [`RpcServer::reply()`] with [`ResponseObject::Err`].
+    pub fn reply_err(
+        &mut self,
+        poller: &mut Poll,
+        from: ClientId,
+        id: RequestId,
+        code: ErrorCode,
+        message: &str,
+    ) -> std::io::Result<ReplyStatus> {
+        let response = ResponseObject::Err {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            id: Some(id),
+            error: ErrorObject {
+                code,
+                message: message.to_owned(),
+                data: None,
+            },
+        };
+        self.reply(poller, from, &response)
+    }
+
+    /// Sends a JSON-RPC notification to the specified client.
+    ///
+    /// Returns `Ok(false)` if the client is no longer connected.
+    pub fn notify(
+        &mut self,
+        poller: &mut Poll,
+        to: ClientId,
+        method: &str,
+        params: Option<RequestParams>,
     ) -> std::io::Result<bool> {
-        let Some(connection) = self.connections.get_mut(&from.token) else {
+        let Some(client) = self.client_mut(to) else {
             return Ok(false);
         };
 
-        let token = connection.token();
-        if connection.send(poller, response).is_err() {
-            let _ = self.connections.remove(&token);
-            return Ok(false);
+        let notification = RequestObject {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            id: None,
+            method: method.to_owned(),
+            params,
+        };
+        let result = client.connection.send(poller, &notification);
+        check_message_size(&result)?;
+        Ok(self.finish_send(poller, to, result))
+    }
+
+    /// Sends a JSON-RPC request to the specified client and returns the ID assigned to the request.
+    ///
+    /// The response from the client can be taken via [`RpcServer::try_recv_response()`].
+    /// If the client is no longer connected, this method returns `Ok(None)`.
+    pub fn call(
+        &mut self,
+        poller: &mut Poll,
+        to: ClientId,
+        method: &str,
+        params: Option<RequestParams>,
+    ) -> std::io::Result<Option<RequestId>> {
+        let id = RequestId::Number(self.next_request_id);
+        let Some(client) = self.client_mut(to) else {
+            return Ok(None);
+        };
+
+        let request = RequestObject {
+            jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+            id: Some(id.clone()),
+            method: method.to_owned(),
+            params,
+        };
+        let result = client.connection.send(poller, &request);
+        check_message_size(&result)?;
+        client.pending_calls.insert(id.clone());
+        self.next_request_id += 1;
+        if !self.finish_send(poller, to, result) {
+            return Ok(None);
         }
 
-        Ok(true)
+        Ok(Some(id))
+    }
+
+    /// Takes a JSON-RPC response to a request sent by [`RpcServer::call()`] from the receive queue.
+    ///
+    /// Responses with unknown IDs are discarded,
+    /// and pending requests to a disconnected client never receive responses.
+    pub fn try_recv_response(&mut self) -> Option<(ClientId, ResponseObject)> {
+        self.responses.pop_front()
     }
 
     /// Handles an `mio` event.
     pub fn handle_event(&mut self, poller: &mut Poll, event: &Event) -> std::io::Result<()> {
         let token = event.token();
-        if token == self.token_min {
-            self.handle_listener_event(poller)?;
+        if let Some(i) = token
+            .0
+            .checked_sub(self.token_min.0)
+            .filter(|i| *i < self.listener_count)
+        {
+            self.handle_listener_event(poller, i)?;
             return Ok(());
         }
 
-        let Some(connection) = self.connections.get_mut(&token) else {
+        let Some(client) = self.connections.get_mut(token) else {
             return Ok(());
         };
+        client.last_activity = Instant::now();
+        let client_id = client.id;
+        let had_queued = client.connection.has_pending_writes();
+        self.handle_client_event(poller, client_id, Some(event));
+        if had_queued && self.events_enabled {
+            if let Some(client) = self.client(client_id) {
+                if !client.connection.has_pending_writes() {
+                    let event = ServerEvent::Flushed { client: client_id };
+                    self.events.push_back(event);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_client_event(
+        &mut self,
+        poller: &mut Poll,
+        client_id: ClientId,
+        event: Option<&Event>,
+    ) {
+        let Some(client) = self
+            .connections
+            .get_mut(client_id.token)
+            .filter(|c| c.id.generation == client_id.generation)
+        else {
+            return;
+        };
+
+        let mut closed = None;
+        let batches = &mut client.batches;
+        let pending_calls = &mut client.pending_calls;
+        let peer_addr = &mut client.peer_addr;
+        let awaiting_proxy_header = &mut client.awaiting_proxy_header;
+        let next_sequence = &mut client.next_sequence;
+        let in_flight = &mut client.in_flight;
+        let read_paused = &mut client.read_paused;
+        let read_limiter = &mut client.read_limiter;
+        let mut read_resume_at = None;
+        let peer_closed = &mut client.peer_closed;
+        let draining = client.drain_deadline.is_some();
+        let mut next_context = |peer_addr, received_at| {
+            let sequence = *next_sequence;
+            *next_sequence += 1;
+            RequestContext {
+                client: client_id,
+                peer_addr,
+                received_at,
+                sequence,
+            }
+        };
+        let on_read = |c: &mut Connection, poller: &mut Poll| {
+            if draining {
+                // Stops the read loop without closing the connection.
+                return Err(serde_json::Error::io(std::io::ErrorKind::WouldBlock.into()));
+            }
+            if *in_flight >= self.max_in_flight_requests {
+                // Stops the read loop without closing the connection.
+                *read_paused = true;
+                return Err(serde_json::Error::io(std::io::ErrorKind::WouldBlock.into()));
+            }
+            if let Some(limiter) = read_limiter {
+                if let Some(t) = limiter.throttle(c.received_bytes(), Instant::now()) {
+                    // Stops reading until the bucket is refilled.
+                    read_resume_at = Some(t);
+                    c.set_read_interest(poller, false)
+                        .map_err(serde_json::Error::io)?;
+                    return Err(serde_json::Error::io(std::io::ErrorKind::WouldBlock.into()));
+                }
+            }
+            if *awaiting_proxy_header {
+                match c.read_prefix(proxy::parse_header) {
+                    Err(e) if e.io_error_kind() == Some(std::io::ErrorKind::WouldBlock) => {
+                        return Err(e)
+                    }
+                    Err(e) => {
+                        c.close_with_error(poller, &e);
+                        closed = Some(if e.io_error_kind() == Some(ErrorKind::InvalidData) {
+                            DisconnectReason::InvalidProxyHeader
+                        } else {
+                            DisconnectReason::PeerClosed
+                        });
+                    }
+                    Ok(addr) => {
+                        *awaiting_proxy_header = false;
+                        if addr.is_some() {
+                            *peer_addr = addr;
+                        }
+                    }
+                }
+                return Ok(());
+            }
 
-        let mut closed = false;
-        connection.handle_event(poller, event, |c, poller| {
-            match c.stream_mut().read_value::<REQ>() {
-                Err(e) if e.io_error_kind() == Some(std::io::ErrorKind::WouldBlock) => Err(e),
-                Err(e) if e.is_io() => {
-                    c.close(poller);
-                    closed = true;
-                    Ok(())
+            let line = match c.read_line() {
+                Err(e) if e.io_error_kind() == Some(std::io::ErrorKind::WouldBlock) => {
+                    return Err(e)
+                }
+                Err(e) if is_line_too_large_error(&e) => {
+                    let response = ResponseObject::Err {
+                        jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+                        error: ErrorObject {
+                            code: PAYLOAD_TOO_LARGE,
+                            message: e.to_string(),
+                            data: None,
+                        },
+                        id: None,
+                    };
+                    let _ = c.send(poller, &response);
+                    c.close_with_error(poller, &e);
+                    closed = Some(DisconnectReason::MessageTooLarge);
+                    return Ok(());
+                }
+                Err(e) if e.io_error_kind() == Some(ErrorKind::UnexpectedEof) => {
+                    // The peer has only closed its writing side, so the connection is kept open
+                    // until the in-flight requests are replied to and the responses are flushed.
+                    if !*peer_closed {
+                        *peer_closed = true;
+                        c.begin_shutdown();
+                        if self.events_enabled {
+                            self.events
+                                .push_back(ServerEvent::ShuttingDown { client: client_id });
+                        }
+                    }
+                    return Err(serde_json::Error::io(ErrorKind::WouldBlock.into()));
                 }
                 Err(e) => {
-                    let line = c
-                        .stream_mut()
-                        .read_buf()
-                        .splitn(2, |b| *b == b'\n')
-                        .next()
-                        .unwrap_or(b"");
-                    let response =
-                        if let Ok(request) = serde_json::from_slice::<RequestObject>(line) {
-                            ResponseObject::Err {
-                                jsonrpc: jsonlrpc::JsonRpcVersion::V2,
-                                error: ErrorObject {
-                                    code: ErrorCode::INVALID_PARAMS,
-                                    message: e.to_string(),
-                                    data: None,
-                                },
-                                id: request.id,
-                            }
-                        } else if serde_json::from_slice::<serde_json::Value>(line).is_ok() {
-                            ResponseObject::Err {
-                                jsonrpc: jsonlrpc::JsonRpcVersion::V2,
-                                error: ErrorObject {
-                                    code: ErrorCode::INVALID_REQUEST,
-                                    message: e.to_string(),
-                                    data: None,
-                                },
-                                id: None,
+                    c.close_with_error(poller, &e);
+                    closed = Some(DisconnectReason::PeerClosed);
+                    return Ok(());
+                }
+                Ok(line) => line,
+            };
+            let received_at = Instant::now();
+
+            if !pending_calls.is_empty() {
+                if let Ok(response) = serde_json::from_slice::<ResponseObject>(line) {
+                    if response.id().is_some_and(|id| pending_calls.remove(id)) {
+                        self.responses.push_back((client_id, response));
+                        return Ok(());
+                    }
+                }
+            }
+
+            let e = match probe::from_slice::<REQ>(line) {
+                Ok((request, probe)) => {
+                    let is_call = probe.is_call();
+                    if let Some(response) = check_method(&probe, self.methods.as_ref()) {
+                        if is_call {
+                            let _ = c.send(poller, &response);
+                        }
+                        return Ok(());
+                    }
+                    let incoming = Incoming::new(request, is_call);
+                    *in_flight += usize::from(is_call);
+                    let context = next_context(*peer_addr, received_at);
+                    self.requests.push_back((context, incoming));
+                    self.stats.received_requests += 1;
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+
+            let response = match serde_json::from_slice::<serde_json::Value>(line) {
+                Ok(serde_json::Value::Array(values)) if !values.is_empty() => {
+                    let mut batch = Batch::default();
+                    for value in values {
+                        let id = value
+                            .get("id")
+                            .and_then(|id| serde_json::from_value::<RequestId>(id.clone()).ok());
+                        match probe::deserialize::<REQ, _>(&value) {
+                            Ok((request, probe)) => {
+                                let is_call = probe.is_call();
+                                if let Some(response) = check_method(&probe, self.methods.as_ref())
+                                {
+                                    if is_call {
+                                        batch.responses.push(
+                                            serde_json::to_value(&response).expect("unreachable"),
+                                        );
+                                    }
+                                    continue;
+                                }
+                                let incoming = Incoming::new(request, is_call);
+                                batch.pending_ids.extend(id);
+                                *in_flight += usize::from(is_call);
+                                let context = next_context(*peer_addr, received_at);
+                                self.requests.push_back((context, incoming));
+                                self.stats.received_requests += 1;
                             }
-                        } else {
-                            ResponseObject::Err {
-                                jsonrpc: jsonlrpc::JsonRpcVersion::V2,
-                                error: ErrorObject {
-                                    code: ErrorCode::PARSE_ERROR,
-                                    message: e.to_string(),
-                                    data: None,
-                                },
-                                id: None,
+                            Err(e) => {
+                                let response = invalid_request_response(&value, &e);
+                                batch
+                                    .responses
+                                    .push(serde_json::to_value(&response).expect("unreachable"));
                             }
-                        };
-                    let _ = c.send(poller, &response);
-                    Ok(())
-                }
-                Ok(request) => {
-                    self.requests.push_back((ClientId { token }, request));
-                    Ok(())
+                        }
+                    }
+                    if !batch.pending_ids.is_empty() {
+                        batches.push(batch);
+                        return Ok(());
+                    } else if batch.responses.is_empty() {
+                        return Ok(());
+                    }
+                    let _ = c.send(poller, &batch.responses);
+                    return Ok(());
                 }
+                Ok(value) => invalid_request_response(&value, &e),
+                Err(_) => error_response(ErrorCode::PARSE_ERROR, &e, None),
+            };
+            let _ = c.send(poller, &response);
+            if self.parse_error_policy == ParseErrorPolicy::Close {
+                c.close_with_error(poller, &e);
+                closed = Some(DisconnectReason::InvalidMessage);
             }
-        })?;
+            Ok(())
+        };
+        let result = match event {
+            Some(event) => client.connection.handle_event(poller, event, on_read),
+            None => client.connection.handle_read(poller, on_read),
+        };
+        if let Some(t) = read_resume_at {
+            self.next_read_resume = Some(self.next_read_resume.map_or(t, |n| n.min(t)));
+        }
 
-        if closed {
-            let _ = self.connections.remove(&token);
+        if let Some(reason) = closed {
+            self.remove_client(poller, client_id, reason);
+        } else if self.finish_send(poller, client_id, result) && draining {
+            let flushed = self
+                .client(client_id)
+                .is_some_and(|c| !c.connection.has_pending_writes());
+            if flushed {
+                self.remove_client(poller, client_id, DisconnectReason::Requested);
+            }
+        } else {
+            self.close_if_half_closed(poller, client_id);
         }
-        Ok(())
     }
 
-    /// Returns client connections.
-    pub fn connections(&self) -> impl '_ + Iterator<Item = &Connection> {
-        self.connections.values()
+    fn finish_reply(
+        &mut self,
+        poller: &mut Poll,
+        from: ClientId,
+        result: serde_json::Result<bool>,
+        count: usize,
+    ) -> ReplyStatus {
+        let written = result.as_ref().is_ok_and(|written| *written);
+        if result.is_ok() {
+            self.stats.sent_responses += count as u64;
+        }
+        if !self.finish_send(poller, from, result.map(|_| ())) {
+            return ReplyStatus::Dropped;
+        }
+        let status = match self.client(from) {
+            _ if !written => ReplyStatus::Held,
+            Some(c) if c.connection.has_pending_writes() => ReplyStatus::Queued,
+            _ => ReplyStatus::Sent,
+        };
+        self.release_in_flight(poller, from, count);
+        status
     }
 
-    fn handle_listener_event(&mut self, poller: &mut Poll) -> std::io::Result<()> {
-        loop {
-            match self.listener.accept() {
-                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
-                Err(e) => return Err(e),
-                Ok((stream, _addr)) => {
-                    let Some(connection) = self.handle_accepted(poller, stream) else {
-                        continue;
-                    };
-                    self.connections.insert(connection.token(), connection);
-                }
-            }
+    fn release_in_flight(&mut self, poller: &mut Poll, id: ClientId, count: usize) {
+        let max = self.max_in_flight_requests;
+        let Some(client) = self.client_mut(id) else {
+            return;
+        };
+        client.in_flight = client.in_flight.saturating_sub(count);
+        if client.read_paused && client.in_flight < max {
+            client.read_paused = false;
+            self.handle_client_event(poller, id, None);
         }
-        Ok(())
+        self.close_if_half_closed(poller, id);
     }
 
-    fn handle_accepted(&mut self, poller: &mut Poll, mut stream: TcpStream) -> Option<Connection> {
-        let token = self.next_token()?;
-        poller
-            .registry()
-            .register(&mut stream, token, Interest::READABLE)
-            .ok()?;
-        let connection = Connection::new(token, stream, ConnectionState::Connected);
-        Some(connection)
+    /// Closes the connection if the peer has closed its writing side and there is nothing left to send.
+    fn close_if_half_closed(&mut self, poller: &mut Poll, id: ClientId) {
+        let done = self.client(id).is_some_and(|c| {
+            c.peer_closed && c.in_flight == 0 && !c.connection.has_pending_writes()
+        });
+        if done {
+            self.remove_client(poller, id, DisconnectReason::PeerClosed);
+        }
     }
 
-    fn next_token(&mut self) -> Option<Token> {
-        if self.token_max.0 - self.token_min.0 == self.connections.len() {
-            return None;
+    /// Returns the duration until [`RpcServer::handle_timeout()`] should be called next.
+    ///
+    /// If no timeout is pending, this method returns `None`.
+    /// The returned value is intended to be passed to [`Poll::poll()`].
+    pub fn next_timeout(&self) -> Option<Duration> {
+        let deadline = [
+            self.next_idle_check,
+            self.next_stall_check,
+            self.next_read_resume,
+            self.next_drain_check,
+            self.accept_resume_at,
+        ]
+        .into_iter()
+        .flatten()
+        .min()?;
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Handles expired timeouts (e.g., closes idle connections).
+    pub fn handle_timeout(&mut self, poller: &mut Poll) {
+        let now = Instant::now();
+        self.handle_accept_backoff_timeout(poller, now);
+        self.handle_drain_timeout(poller, now);
+        self.handle_idle_timeout(poller, now);
+        self.handle_write_stall_timeout(poller, now);
+        self.handle_read_rate_timeout(poller, now);
+    }
+
+    fn handle_accept_backoff_timeout(&mut self, poller: &mut Poll, now: Instant) {
+        if self.accept_resume_at.is_none_or(|t| now < t) {
+            return;
+        }
+        if self.resume_accepting(poller).is_err() {
+            let _ = self.pause_accepting(poller);
+            self.accept_resume_at = Some(now + self.accept_backoff);
+            return;
+        }
+        if self.events_enabled {
+            self.events.push_back(ServerEvent::AcceptResumed);
         }
+    }
 
-        loop {
-            let token = self.next_token;
-            if self.next_token == self.token_max {
-                self.next_token.0 = self.token_min.0 + 1; // `+1` is to skip the server token
-            } else {
-                self.next_token.0 += 1;
+    fn suspend_accepting(&mut self, poller: &mut Poll) {
+        let _ = self.pause_accepting(poller);
+        self.accept_resume_at = Some(Instant::now() + self.accept_backoff);
+        if self.events_enabled {
+            self.events.push_back(ServerEvent::AcceptSuspended {
+                retry_after: self.accept_backoff,
+            });
+        }
+    }
+
+    fn handle_drain_timeout(&mut self, poller: &mut Poll, now: Instant) {
+        if self.next_drain_check.is_none_or(|t| now < t) {
+            return;
+        }
+
+        let expired_clients = self
+            .connections
+            .values()
+            .filter(|c| c.drain_deadline.is_some_and(|t| t <= now))
+            .map(|c| c.id)
+            .collect::<Vec<_>>();
+        for client in expired_clients {
+            self.remove_client(poller, client, DisconnectReason::DrainTimeout);
+        }
+        self.next_drain_check = self
+            .connections
+            .values()
+            .filter_map(|c| c.drain_deadline)
+            .min();
+    }
+
+    fn handle_idle_timeout(&mut self, poller: &mut Poll, now: Instant) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+        if self.next_idle_check.is_none_or(|t| now < t) {
+            return;
+        }
+
+        let idle_clients = self
+            .connections
+            .values()
+            .filter(|c| now.saturating_duration_since(c.last_activity) >= idle_timeout)
+            .map(|c| c.id)
+            .collect::<Vec<_>>();
+        for client in idle_clients {
+            self.remove_client(poller, client, DisconnectReason::IdleTimeout);
+        }
+        self.next_idle_check = self
+            .connections
+            .values()
+            .map(|c| c.last_activity + idle_timeout)
+            .min();
+    }
+
+    fn handle_read_rate_timeout(&mut self, poller: &mut Poll, now: Instant) {
+        if self.next_read_resume.is_none_or(|t| now < t) {
+            return;
+        }
+
+        let throttled_clients = self
+            .connections
+            .values()
+            .filter(|c| c.read_resume_at().is_some_and(|t| t <= now))
+            .map(|c| c.id)
+            .collect::<Vec<_>>();
+        for client in throttled_clients {
+            let Some(c) = self.client_mut(client) else {
+                continue;
+            };
+            if let Some(limiter) = &mut c.read_limiter {
+                limiter.resume_at = None;
             }
-            if !self.connections.contains_key(&token) {
-                return Some(token);
+            if !c.connection.is_reading_paused()
+                && c.connection.set_read_interest(poller, true).is_err()
+            {
+                self.remove_client(poller, client, DisconnectReason::Error);
+                continue;
             }
+            self.handle_client_event(poller, client, None);
         }
+        self.next_read_resume = self
+            .connections
+            .values()
+            .filter_map(|c| c.read_resume_at())
+            .min();
     }
-}
 
-/// Identifier of a client.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
-#[serde(from = "usize", into = "usize")]
-pub struct ClientId {
-    token: Token,
+    fn handle_write_stall_timeout(&mut self, poller: &mut Poll, now: Instant) {
+        let Some(timeout) = self.write_stall_timeout else {
+            return;
+        };
+        if self.next_stall_check.is_none_or(|t| now < t) {
+            return;
+        }
+
+        let stalled_clients = self
+            .connections
+            .values()
+            .filter(|c| {
+                c.connection
+                    .write_stalled_since()
+                    .is_some_and(|t| now.saturating_duration_since(t) >= timeout)
+            })
+            .map(|c| c.id)
+            .collect::<Vec<_>>();
+        for client in stalled_clients {
+            self.remove_client(poller, client, DisconnectReason::WriteStalled);
+        }
+        self.next_stall_check = self
+            .connections
+            .values()
+            .filter_map(|c| c.connection.write_stalled_since())
+            .map(|t| t + timeout)
+            .min();
+    }
+
+    /// Returns a snapshot of the statistics of this server.
+    pub fn stats(&self) -> ServerStats {
+        let mut stats = self.stats;
+        stats.active_connections = self.connections.len();
+        for client in self.connections.values() {
+            stats.received_bytes += client.connection.received_bytes();
+            stats.sent_bytes += client.connection.sent_bytes();
+        }
+        stats
+    }
+
+    /// Takes an event from the event queue.
+    ///
+    /// Events are only recorded if enabled via [`RpcServerBuilder::events()`].
+    pub fn try_recv_event(&mut self) -> Option<ServerEvent> {
+        self.events.pop_front()
+    }
+
+    /// Closes the connection with the specified client.
+    ///
+    /// Returns `false` if the client is not connected.
+    pub fn disconnect(&mut self, poller: &mut Poll, client: ClientId) -> bool {
+        self.remove_client(poller, client, DisconnectReason::Requested)
+    }
+
+    /// Closes the connection with the specified client after flushing the data queued on it.
+    ///
+    /// The server stops reading requests from the connection immediately, and closes it once
+    /// its write buffer becomes empty. If the buffer is not flushed within `timeout`,
+    /// the connection is closed with [`DisconnectReason::DrainTimeout`]
+    /// (this requires [`RpcServer::handle_timeout()`] to be called according to [`RpcServer::next_timeout()`]).
+    ///
+    /// Returns `false` if the client is not connected.
+    pub fn disconnect_gracefully(
+        &mut self,
+        poller: &mut Poll,
+        client: ClientId,
+        timeout: Duration,
+    ) -> bool {
+        let Some(c) = self.client_mut(client) else {
+            return false;
+        };
+        if !c.connection.has_pending_writes() {
+            return self.remove_client(poller, client, DisconnectReason::Requested);
+        }
+        let deadline = Instant::now() + timeout;
+        let deadline = c.drain_deadline.map_or(deadline, |t| t.min(deadline));
+        c.drain_deadline = Some(deadline);
+        c.connection.begin_shutdown();
+        if self.events_enabled {
+            self.events.push_back(ServerEvent::ShuttingDown { client });
+        }
+        self.next_drain_check = Some(self.next_drain_check.map_or(deadline, |t| t.min(deadline)));
+        true
+    }
+
+    /// Stops reading requests from the specified client without closing the connection
+    /// (see [`Connection::pause_reading()`]).
+    ///
+    /// Returns `false` if the client is not connected.
+    pub fn pause_reading(&mut self, poller: &mut Poll, client: ClientId) -> bool {
+        let Some(c) = self.client_mut(client) else {
+            return false;
+        };
+        if c.connection.pause_reading(poller).is_err() {
+            self.remove_client(poller, client, DisconnectReason::Error);
+            return false;
+        }
+        true
+    }
+
+    /// Resumes reading requests paused by [`RpcServer::pause_reading()`].
+    ///
+    /// Requests that have already been received are processed immediately.
+    /// Returns `false` if the client is not connected.
+    pub fn resume_reading(&mut self, poller: &mut Poll, client: ClientId) -> bool {
+        let Some(c) = self.client_mut(client) else {
+            return false;
+        };
+        if c.connection.resume_reading(poller).is_err() {
+            self.remove_client(poller, client, DisconnectReason::Error);
+            return false;
+        }
+        self.handle_client_event(poller, client, None);
+        self.client(client).is_some()
+    }
+
+    /// Returns the address of the specified client.
+    ///
+    /// If the client is no longer connected or is connected via a Unix domain socket,
+    /// this method returns `None`.
+    pub fn peer_addr(&self, client: ClientId) -> Option<SocketAddr> {
+        self.client(client).and_then(|c| c.peer_addr)
+    }
+
+    /// Returns the connection of the specified client.
+    pub fn connection(&self, client: ClientId) -> Option<&Connection> {
+        self.client(client).map(|c| &c.connection)
+    }
+
+    /// Attaches application data to the connection of the specified client (see [`Connection::set_user_data()`]).
+    ///
+    /// The data can be read via [`Connection::user_data()`] of [`RpcServer::connection()`].
+    /// Returns `false` if the client is no longer connected.
+    pub fn set_user_data<T: Any + Send>(&mut self, client: ClientId, data: T) -> bool {
+        let Some(c) = self.client_mut(client) else {
+            return false;
+        };
+        c.connection.set_user_data(data);
+        true
+    }
+
+    /// Overrides the buffer capacities of the connection of the specified client (see [`Connection::set_buf_capacities()`]).
+    ///
+    /// Returns `false` if the client is no longer connected.
+    pub fn set_buf_capacities(&mut self, client: ClientId, read: usize, write: usize) -> bool {
+        let Some(c) = self.client_mut(client) else {
+            return false;
+        };
+        c.connection.set_buf_capacities(read, write);
+        true
+    }
+
+    /// Returns a mutable reference to the application data attached to the connection of the specified client.
+    pub fn user_data_mut<T: Any>(&mut self, client: ClientId) -> Option<&mut T> {
+        self.client_mut(client)?.connection.user_data_mut()
+    }
+
+    /// Returns client connections.
+    pub fn connections(&self) -> impl '_ + Iterator<Item = &Connection> {
+        self.connections.values().map(|c| &c.connection)
+    }
+
+    /// Stops accepting new connections while keeping the existing connections served.
+    ///
+    /// The listening sockets remain bound, so incoming connections are held in the backlog until
+    /// [`RpcServer::resume_accepting()`] is called.
+    pub fn pause_accepting(&mut self, poller: &mut Poll) -> std::io::Result<()> {
+        self.accept_resume_at = None;
+        if !self.accepting {
+            return Ok(());
+        }
+        for listener in &mut self.listeners {
+            listener.deregister(poller)?;
+        }
+        self.accepting = false;
+        Ok(())
+    }
+
+    /// Resumes accepting new connections paused by [`RpcServer::pause_accepting()`].
+    pub fn resume_accepting(&mut self, poller: &mut Poll) -> std::io::Result<()> {
+        self.accept_resume_at = None;
+        if self.accepting {
+            return Ok(());
+        }
+        for (i, listener) in self.listeners.iter_mut().enumerate() {
+            let token = Token(self.token_min.0 + i);
+            listener.register(poller, token)?;
+        }
+        self.accepting = true;
+        Ok(())
+    }
+
+    /// Returns `true` if this server is accepting new connections.
+    pub fn is_accepting(&self) -> bool {
+        self.accepting && !self.is_shutdown()
+    }
+
+    /// Attempts to write the data queued on all connections immediately
+    /// instead of waiting for the next writable events.
+    ///
+    /// Connections that fail are closed with [`DisconnectReason::Error`].
+    pub fn flush(&mut self, poller: &mut Poll) {
+        let clients = self
+            .connections
+            .values()
+            .filter(|c| c.connection.has_pending_writes())
+            .map(|c| c.id)
+            .collect::<Vec<_>>();
+        for id in clients {
+            let client = self.client_mut(id).expect("unreachable");
+            let result = client.connection.flush(poller);
+            if !self.finish_send(poller, id, result) {
+                continue;
+            }
+            let client = self.client(id).expect("unreachable");
+            if client.drain_deadline.is_some() && !client.connection.has_pending_writes() {
+                self.remove_client(poller, id, DisconnectReason::Requested);
+            } else {
+                self.close_if_half_closed(poller, id);
+            }
+        }
+    }
+
+    /// Shuts down this server.
+    ///
+    /// This method stops accepting new connections, makes a best-effort attempt to flush
+    /// the responses queued on the existing connections, and then closes all of them
+    /// with [`DisconnectReason::Shutdown`].
+    /// Requests remaining in the receive queue are discarded.
+    pub fn shutdown(&mut self, poller: &mut Poll) {
+        for mut listener in self.listeners.drain(..) {
+            if self.accepting {
+                let _ = listener.deregister(poller);
+            }
+        }
+        let clients = self.connections.values().map(|c| c.id).collect::<Vec<_>>();
+        for client in clients {
+            if let Some(client) = self.client_mut(client) {
+                let _ = client.connection.flush_buf();
+            }
+            self.remove_client(poller, client, DisconnectReason::Shutdown);
+        }
+        self.requests.clear();
+    }
+
+    /// Shuts down this server, closing each connection after flushing the data queued on it.
+    ///
+    /// This method stops accepting new connections, discards the requests remaining in the receive queue,
+    /// and then applies [`RpcServer::disconnect_gracefully()`] to all connections.
+    /// [`RpcServer::handle_event()`] and [`RpcServer::handle_timeout()`] need to be called
+    /// until [`RpcServer::connections()`] becomes empty.
+    pub fn shutdown_gracefully(&mut self, poller: &mut Poll, timeout: Duration) {
+        for mut listener in self.listeners.drain(..) {
+            if self.accepting {
+                let _ = listener.deregister(poller);
+            }
+        }
+        self.requests.clear();
+        let clients = self.connections.values().map(|c| c.id).collect::<Vec<_>>();
+        for client in clients {
+            self.disconnect_gracefully(poller, client, timeout);
+        }
+    }
+
+    /// Returns `true` if [`RpcServer::shutdown()`] has been called.
+    pub fn is_shutdown(&self) -> bool {
+        self.listeners.is_empty()
+    }
+
+    /// Detaches the connection with the specified client from this server.
+    ///
+    /// The connection is deregistered from `poller` because its token belongs to the server's token range
+    /// and will be reused for new clients. To keep using the connection, register it again with
+    /// [`Connection::register()`] and a token outside of that range.
+    ///
+    /// Requests from the client that remain in the receive queue can no longer be replied to.
+    ///
+    /// Returns `None` if the client is not connected.
+    pub fn take_connection(&mut self, poller: &mut Poll, from: ClientId) -> Option<Connection> {
+        let mut client = self.detach_client(from, DisconnectReason::Detached)?;
+        client.connection.deregister(poller);
+        Some(client.connection)
+    }
+
+    fn remove_client(&mut self, poller: &mut Poll, id: ClientId, reason: DisconnectReason) -> bool {
+        let Some(mut client) = self.detach_client(id, reason) else {
+            return false;
+        };
+        client.connection.close(poller);
+        true
+    }
+
+    fn detach_client(
+        &mut self,
+        id: ClientId,
+        reason: DisconnectReason,
+    ) -> Option<ClientConnection> {
+        self.client(id)?;
+        let client = self.connections.remove(id.token)?;
+        if let Some(ip) = client.accept_ip {
+            if let Some(n) = self.connections_per_ip.get_mut(&ip) {
+                *n -= 1;
+                if *n == 0 {
+                    self.connections_per_ip.remove(&ip);
+                }
+            }
+        }
+        self.stats.record_closed(&client.connection);
+        if self.events_enabled {
+            self.events
+                .push_back(ServerEvent::Disconnected { client: id, reason });
+        }
+        Some(client)
+    }
+
+    fn finish_send(
+        &mut self,
+        poller: &mut Poll,
+        id: ClientId,
+        result: serde_json::Result<()>,
+    ) -> bool {
+        if result.is_err() {
+            self.remove_client(poller, id, DisconnectReason::Error);
+            return false;
+        }
+        let Some(client) = self.client_mut(id) else {
+            return false;
+        };
+        client.last_activity = Instant::now();
+        if client.connection.queued_bytes_len() > self.max_write_buf_size {
+            self.remove_client(poller, id, DisconnectReason::WriteBufferFull);
+            return false;
+        }
+        let client = self
+            .connections
+            .get_mut(id.token)
+            .filter(|c| c.id == id)
+            .expect("unreachable");
+        let high = client.connection.is_write_buffer_high();
+        if client.write_buf_high != high {
+            client.write_buf_high = high;
+            if self.events_enabled {
+                self.events.push_back(if high {
+                    ServerEvent::WriteBufferHigh { client: id }
+                } else {
+                    ServerEvent::WriteBufferLow { client: id }
+                });
+            }
+        }
+        let stall_deadline = self
+            .client(id)
+            .and_then(|c| c.connection.write_stalled_since())
+            .zip(self.write_stall_timeout)
+            .map(|(t, timeout)| t + timeout);
+        if let Some(deadline) = stall_deadline {
+            self.next_stall_check =
+                Some(self.next_stall_check.map_or(deadline, |t| t.min(deadline)));
+        }
+        true
+    }
+
+    fn client(&self, id: ClientId) -> Option<&ClientConnection> {
+        self.connections
+            .get(id.token)
+            .filter(|c| c.id.generation == id.generation)
+    }
+
+    fn client_mut(&mut self, id: ClientId) -> Option<&mut ClientConnection> {
+        self.connections
+            .get_mut(id.token)
+            .filter(|c| c.id.generation == id.generation)
+    }
+
+    fn handle_listener_event(&mut self, poller: &mut Poll, index: usize) -> std::io::Result<()> {
+        let mut accepted = 0;
+        while self.accepting {
+            let Some(listener) = self.listeners.get_mut(index) else {
+                return Ok(());
+            };
+            if accepted >= self.max_accepts_per_event {
+                // Re-arms the listener so that the remaining connections are accepted
+                // in a subsequent event.
+                listener.reregister(poller, Token(self.token_min.0 + index))?;
+                break;
+            }
+            match listener.accept() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if socket::is_resource_exhausted(&e) => {
+                    self.suspend_accepting(poller);
+                    break;
+                }
+                Err(e) => return Err(e),
+                Ok((stream, addr)) => {
+                    accepted += 1;
+                    if let (Some(filter), Some(peer_addr)) = (&mut self.accept_filter, addr) {
+                        if !(filter.0)(peer_addr) {
+                            self.reject_accepted(stream, addr, AcceptRejectReason::Filtered);
+                            continue;
+                        }
+                    }
+                    if self.connections.len() >= self.max_connections {
+                        self.reject_accepted(stream, addr, AcceptRejectReason::MaxConnections);
+                        continue;
+                    }
+                    if addr.is_some_and(|a| {
+                        self.connections_per_ip.get(&a.ip()).copied().unwrap_or(0)
+                            >= self.max_connections_per_ip
+                    }) {
+                        let reason = AcceptRejectReason::MaxConnectionsPerIp;
+                        self.reject_accepted(stream, addr, reason);
+                        continue;
+                    }
+                    if self.connections.next_token().is_none() {
+                        self.reject_accepted(stream, addr, AcceptRejectReason::NoTokens);
+                        continue;
+                    }
+                    let Some(client) = self.handle_accepted(poller, stream, addr) else {
+                        continue;
+                    };
+                    self.connections.insert(client);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reject_accepted(
+        &mut self,
+        stream: Stream,
+        peer_addr: Option<SocketAddr>,
+        reason: AcceptRejectReason,
+    ) {
+        match reason {
+            AcceptRejectReason::Filtered => {}
+            AcceptRejectReason::MaxConnections => {
+                reject(stream, SERVER_OVERLOADED, "Too many connections");
+            }
+            AcceptRejectReason::MaxConnectionsPerIp => {
+                let message = "Too many connections from the same IP address";
+                reject(stream, SERVER_OVERLOADED, message);
+            }
+            AcceptRejectReason::NoTokens => {
+                reject(stream, SERVER_OVERLOADED, "No available tokens");
+            }
+        }
+        self.stats.rejected_connections += 1;
+        if self.events_enabled {
+            self.events
+                .push_back(ServerEvent::AcceptRejected { peer_addr, reason });
+        }
+    }
+
+    fn handle_accepted(
+        &mut self,
+        poller: &mut Poll,
+        mut stream: Stream,
+        peer_addr: Option<SocketAddr>,
+    ) -> Option<ClientConnection> {
+        let token = self.connections.next_token()?;
+        poller
+            .registry()
+            .register(&mut stream, token, Interest::READABLE)
+            .ok()?;
+        if let Stream::Tcp(stream) = &stream {
+            // Socket options are applied on a best-effort basis.
+            let _ = socket::apply_socket_options(stream, &self.socket_options);
+        }
+        let mut connection = Connection::new(token, stream, ConnectionState::Connected);
+        connection.set_max_line_size(self.max_request_size);
+        connection.set_max_outgoing_line_size(self.max_response_size);
+        connection.set_max_retained_buf_size(self.max_retained_buf_size, self.buf_shrink_delay);
+        connection.set_write_watermarks(self.write_watermarks);
+        let (read, write) = self.initial_buf_capacities;
+        connection.set_buf_capacities(read, write);
+        let id = ClientId {
+            token,
+            generation: self.next_generation,
+        };
+        self.next_generation += 1;
+        self.stats.accepted_connections += 1;
+        let accept_ip = peer_addr.map(|a| a.ip());
+        if let Some(ip) = accept_ip {
+            *self.connections_per_ip.entry(ip).or_default() += 1;
+        }
+        let last_activity = Instant::now();
+        if let Some(idle_timeout) = self.idle_timeout {
+            self.next_idle_check
+                .get_or_insert(last_activity + idle_timeout);
+        }
+        Some(ClientConnection {
+            id,
+            last_activity,
+            pending_calls: HashSet::new(),
+            connection,
+            peer_addr,
+            batches: Vec::new(),
+            next_sequence: 0,
+            in_flight: 0,
+            read_paused: false,
+            read_limiter: self
+                .read_rate_limit
+                .map(|(rate, burst)| ReadRateLimiter::new(rate, burst, last_activity)),
+            write_buf_high: false,
+            peer_closed: false,
+            drain_deadline: None,
+            awaiting_proxy_header: self.proxy_protocol,
+            accept_ip,
+        })
+    }
+}
+
+/// Builder for [`RpcServer`].
+#[derive(Debug, Clone)]
+pub struct RpcServerBuilder {
+    listen_addrs: Vec<SocketAddr>,
+    share_port: bool,
+    #[cfg(unix)]
+    unix_listen_paths: Vec<PathBuf>,
+    token_min: Token,
+    token_max: Token,
+    listener_options: ListenerOptions,
+    socket_options: SocketOptions,
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    idle_timeout: Option<Duration>,
+    write_stall_timeout: Option<Duration>,
+    read_rate_limit: Option<(u64, u64)>,
+    max_request_size: usize,
+    max_response_size: usize,
+    max_write_buf_size: usize,
+    write_watermarks: Option<(usize, usize)>,
+    max_retained_buf_size: usize,
+    buf_shrink_delay: Duration,
+    initial_buf_capacities: (usize, usize),
+    max_in_flight_requests: usize,
+    max_accepts_per_event: usize,
+    accept_backoff: Duration,
+    proxy_protocol: bool,
+    methods: Option<HashSet<String>>,
+    parse_error_policy: ParseErrorPolicy,
+    events: bool,
+}
+
+impl RpcServerBuilder {
+    fn new(listen_addr: SocketAddr) -> Self {
+        Self {
+            listen_addrs: vec![listen_addr],
+            ..Self::without_listeners()
+        }
+    }
+
+    fn dual_stack(port: u16) -> Self {
+        Self {
+            share_port: true,
+            ..Self::new(SocketAddr::from(([0, 0, 0, 0], port)))
+                .add_listen_addr(SocketAddr::from(([0u16; 8], port)))
+                .only_v6(true)
+        }
+    }
+
+    fn without_listeners() -> Self {
+        Self {
+            listen_addrs: Vec::new(),
+            share_port: false,
+            #[cfg(unix)]
+            unix_listen_paths: Vec::new(),
+            token_min: Token(0),
+            token_max: Token(1024),
+            listener_options: ListenerOptions::default(),
+            socket_options: SocketOptions::default(),
+            max_connections: usize::MAX,
+            max_connections_per_ip: usize::MAX,
+            idle_timeout: None,
+            write_stall_timeout: None,
+            read_rate_limit: None,
+            max_request_size: usize::MAX,
+            max_response_size: usize::MAX,
+            max_write_buf_size: usize::MAX,
+            write_watermarks: None,
+            max_retained_buf_size: usize::MAX,
+            buf_shrink_delay: Duration::from_secs(1),
+            initial_buf_capacities: (READ_CHUNK_SIZE, 0),
+            max_in_flight_requests: usize::MAX,
+            max_accepts_per_event: usize::MAX,
+            accept_backoff: Duration::from_millis(100),
+            proxy_protocol: false,
+            methods: None,
+            parse_error_policy: ParseErrorPolicy::default(),
+            events: false,
+        }
+    }
+
+    /// Sets the range of `mio` tokens used by the server (the default is `Token(0)..=Token(1024)`).
+    ///
+    /// The first tokens of the range are assigned to the listening sockets (i.e., `token_min` if the server has only one listener),
+    /// and the remaining tokens are assigned to client connections.
+    pub fn token_range(mut self, token_min: Token, token_max: Token) -> Self {
+        self.token_min = token_min;
+        self.token_max = token_max;
+        self
+    }
+
+    /// Adds an extra address on which the server listens.
+    ///
+    /// Connections accepted on any of the addresses share the same request queue.
+    pub fn add_listen_addr(mut self, listen_addr: SocketAddr) -> Self {
+        self.listen_addrs.push(listen_addr);
+        self
+    }
+
+    /// Adds a Unix domain socket path on which the server listens.
+    ///
+    /// Starting the server fails if a file already exists at `path`.
+    #[cfg(unix)]
+    pub fn add_unix_listen_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.unix_listen_paths.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the backlog size of the listening socket (the default is `1024`).
+    ///
+    /// This option is ignored on non-Unix platforms.
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.listener_options.backlog = backlog.min(i32::MAX as u32) as i32;
+        self
+    }
+
+    /// Sets whether `SO_REUSEPORT` is enabled on the listening sockets (the default is `false`).
+    ///
+    /// On non-Unix platforms, [`RpcServerBuilder::start()`] fails if this option is enabled.
+    pub fn reuse_port(mut self, enabled: bool) -> Self {
+        self.listener_options.reuse_port = enabled;
+        self
+    }
+
+    /// Sets whether `IPV6_V6ONLY` is enabled on the IPv6 listening sockets (the default is the system default).
+    ///
+    /// On non-Unix platforms, [`RpcServerBuilder::start()`] fails if this option is set
+    /// and the server listens on an IPv6 address.
+    pub fn only_v6(mut self, enabled: bool) -> Self {
+        self.listener_options.only_v6 = Some(enabled);
+        self
+    }
+
+    /// Sets whether `TCP_NODELAY` is enabled on accepted connections (the default is `true`).
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.socket_options.nodelay = nodelay;
+        self
+    }
+
+    /// Enables TCP keepalive on accepted connections (the default is disabled).
+    ///
+    /// Probes are sent after the connection has been idle for `idle`, and then every `interval`,
+    /// and the connection is dropped by the OS after `count` unanswered probes.
+    /// Durations are rounded down to whole seconds (with a minimum of one second).
+    pub fn tcp_keepalive(mut self, idle: Duration, interval: Duration, count: u32) -> Self {
+        self.socket_options.keepalive = Some(TcpKeepalive {
+            idle,
+            interval,
+            count,
+        });
+        self
+    }
+
+    /// Sets the size of the kernel send buffer (`SO_SNDBUF`) of accepted connections (the default is chosen by the OS).
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.socket_options.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the kernel receive buffer (`SO_RCVBUF`) of accepted connections (the default is chosen by the OS).
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.socket_options.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the maximum number of concurrent client connections (the default is unlimited).
+    ///
+    /// See also: [`RpcServer::set_max_connections()`]
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Sets the maximum number of concurrent client connections from the same IP address (the default is unlimited).
+    ///
+    /// Connections accepted beyond this limit receive a [`SERVER_OVERLOADED`](crate::error_codes::SERVER_OVERLOADED)
+    /// error response and are then closed.
+    /// The limit is applied to the address of the TCP peer (i.e., the proxy if [`RpcServerBuilder::proxy_protocol()`] is enabled).
+    pub fn max_connections_per_ip(mut self, max: usize) -> Self {
+        self.max_connections_per_ip = max;
+        self
+    }
+
+    /// Sets the duration after which connections without any traffic are closed (the default is `None`).
+    ///
+    /// To make this option work, [`RpcServer::handle_timeout()`] needs to be called
+    /// according to [`RpcServer::next_timeout()`].
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the duration after which connections whose queued responses could not be written at all are closed
+    /// (the default is `None`).
+    ///
+    /// This detects clients that have stopped reading (see [`Connection::write_stalled_for()`]),
+    /// and such connections are closed with [`DisconnectReason::WriteStalled`].
+    /// To make this option work, [`RpcServer::handle_timeout()`] needs to be called
+    /// according to [`RpcServer::next_timeout()`].
+    pub fn write_stall_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_stall_timeout = timeout;
+        self
+    }
+
+    /// Limits the number of bytes read from each connection to `bytes_per_sec` on average,
+    /// allowing bursts of up to `burst_size` bytes (the default is unlimited).
+    ///
+    /// [`RpcServerBuilder::start()`] fails with [`std::io::ErrorKind::InvalidInput`] if `burst_size` is `0`.
+    /// Once a connection exceeds the limit, the server stops reading from it until enough time has passed,
+    /// so that a single client cannot monopolize the event loop.
+    /// To make this option work, [`RpcServer::handle_timeout()`] needs to be called
+    /// according to [`RpcServer::next_timeout()`].
+    pub fn read_rate_limit(mut self, bytes_per_sec: u64, burst_size: u64) -> Self {
+        self.read_rate_limit = Some((bytes_per_sec, burst_size));
+        self
+    }
+
+    /// Sets the maximum size in bytes of a single request line (the default is unlimited).
+    ///
+    /// If a client sends a larger line, the server replies with a
+    /// [`PAYLOAD_TOO_LARGE`](crate::error_codes::PAYLOAD_TOO_LARGE) error and closes the connection
+    /// with [`DisconnectReason::MessageTooLarge`].
+    pub fn max_request_size(mut self, size: usize) -> Self {
+        self.max_request_size = size;
+        self
+    }
+
+    /// Sets the maximum size in bytes of a single message sent to a client (the default is unlimited).
+    ///
+    /// This applies to responses as well as server-initiated requests and notifications.
+    /// Sending a larger message fails with a [`std::io::ErrorKind::InvalidInput`] error
+    /// without queueing it, and the connection is kept open
+    /// (e.g., an error response can be sent instead of the oversized one).
+    pub fn max_response_size(mut self, size: usize) -> Self {
+        self.max_response_size = size;
+        self
+    }
+
+    /// Sets the maximum number of bytes that can be queued for sending on each connection (the default is unlimited).
+    ///
+    /// If the limit is exceeded because the client is not reading responses,
+    /// the connection is closed with [`DisconnectReason::WriteBufferFull`].
+    pub fn max_write_buf_size(mut self, size: usize) -> Self {
+        self.max_write_buf_size = size;
+        self
+    }
+
+    /// Sets the low and high watermarks in bytes of the write buffer of each connection (the default is `None`).
+    ///
+    /// If events are enabled, [`ServerEvent::WriteBufferHigh`] is recorded when the data queued on a connection
+    /// reaches `high`, and [`ServerEvent::WriteBufferLow`] is recorded when it falls back to `low`.
+    /// This allows applications to stop producing data (e.g., notifications) for slow clients.
+    /// See also [`Connection::set_write_watermarks()`].
+    pub fn write_watermarks(mut self, low: usize, high: usize) -> Self {
+        self.write_watermarks = Some((low, high));
+        self
+    }
+
+    /// Sets the buffer capacity in bytes that each connection retains after its read or write buffer is drained
+    /// (the default is unlimited).
+    ///
+    /// Without this limit, a connection that has handled a large message keeps buffers of that size until it is closed.
+    /// A buffer is only shrunk once it has not needed more than `size` bytes for the delay set by
+    /// [`RpcServerBuilder::buf_shrink_delay()`], and the check is made when the buffer is drained.
+    /// See also [`Connection::allocated_buf_size()`].
+    pub fn max_retained_buf_size(mut self, size: usize) -> Self {
+        self.max_retained_buf_size = size;
+        self
+    }
+
+    /// Sets how long a buffer larger than [`RpcServerBuilder::max_retained_buf_size()`] must stay underused
+    /// before it is shrunk (the default is 1 second).
+    ///
+    /// This prevents connections that alternate between large and small messages from reallocating on every cycle.
+    /// [`Duration::ZERO`] shrinks buffers as soon as they are drained.
+    pub fn buf_shrink_delay(mut self, delay: Duration) -> Self {
+        self.buf_shrink_delay = delay;
+        self
+    }
+
+    /// Sets the initial capacities in bytes of the read and write buffers of each connection
+    /// (the defaults are 4096 bytes and 0 bytes respectively).
+    ///
+    /// Smaller capacities reduce the memory used by idle connections.
+    /// The capacities can be overridden per connection via [`RpcServer::set_buf_capacities()`].
+    /// See also [`Connection::set_buf_capacities()`].
+    pub fn initial_buf_capacities(mut self, read: usize, write: usize) -> Self {
+        self.initial_buf_capacities = (read, write);
+        self
+    }
+
+    /// Sets the maximum number of unanswered requests per connection (the default is unlimited).
+    ///
+    /// When a connection reaches this limit, the server stops reading from it until
+    /// some of the requests are replied to. Notifications are not counted.
+    pub fn max_in_flight_requests(mut self, max: usize) -> Self {
+        self.max_in_flight_requests = max;
+        self
+    }
+
+    /// Enables or disables strict request ordering (the default is `false`).
+    ///
+    /// If enabled, the server delivers the next request from a connection only after the previous one
+    /// has been replied to, so that each connection is served sequentially.
+    /// This is a shorthand for `max_in_flight_requests(1)` (or `max_in_flight_requests(usize::MAX)` if disabled),
+    /// so the requests included in a batch request are still delivered together.
+    pub fn strict_ordering(self, enabled: bool) -> Self {
+        self.max_in_flight_requests(if enabled { 1 } else { usize::MAX })
+    }
+
+    /// Sets the maximum number of connections accepted per listener event (the default is unlimited).
+    ///
+    /// Limiting this prevents a burst of incoming connections from starving request processing.
+    /// The remaining connections are accepted in subsequent events. `0` is treated as `1`.
+    pub fn max_accepts_per_event(mut self, max: usize) -> Self {
+        self.max_accepts_per_event = max;
+        self
+    }
+
+    /// Sets how long the server stops accepting connections when it runs out of file descriptors
+    /// or other resources needed to accept them (the default is 100 milliseconds).
+    ///
+    /// To resume accepting after the backoff, [`RpcServer::handle_timeout()`] needs to be called
+    /// according to [`RpcServer::next_timeout()`].
+    pub fn accept_backoff(mut self, backoff: Duration) -> Self {
+        self.accept_backoff = backoff;
+        self
+    }
+
+    /// Sets whether accepted connections start with a PROXY protocol (v1 or v2) header (the default is `false`).
+    ///
+    /// If enabled, the client address conveyed by the header is exposed via [`RpcServer::peer_addr()`]
+    /// and [`RequestContext::peer_addr`], and connections without a valid header are closed with
+    /// [`DisconnectReason::InvalidProxyHeader`].
+    /// Note that the accept filter is still applied to the address of the proxy.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Sets the names of the methods that the server handles (the default is `None`, meaning any method).
+    ///
+    /// Requests for methods not included in the set are automatically replied to with
+    /// an [`ErrorCode::METHOD_NOT_FOUND`] error (or silently dropped if they are notifications),
+    /// and are not delivered via [`RpcServer::try_recv()`].
+    pub fn methods<I, S>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.methods = Some(methods.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Sets the policy on how to handle client messages that cannot be parsed as requests
+    /// (the default is [`ParseErrorPolicy::Continue`]).
+    pub fn parse_error_policy(mut self, policy: ParseErrorPolicy) -> Self {
+        self.parse_error_policy = policy;
+        self
+    }
+
+    /// Sets whether the server records [`ServerEvent`]s (the default is `false`).
+    ///
+    /// If enabled, the recorded events need to be taken via [`RpcServer::try_recv_event()`].
+    pub fn events(mut self, enabled: bool) -> Self {
+        self.events = enabled;
+        self
+    }
+
+    /// Starts an [`RpcServer`] with the configured options.
+    pub fn start<REQ>(&self, poller: &mut Poll) -> std::io::Result<RpcServer<REQ>>
+    where
+        REQ: for<'de> Deserialize<'de>,
+    {
+        #[cfg(unix)]
+        let listener_count = self.listen_addrs.len() + self.unix_listen_paths.len();
+        #[cfg(not(unix))]
+        let listener_count = self.listen_addrs.len();
+        if self.token_max.0.saturating_sub(self.token_min.0) < listener_count - 1
+            || self.token_min > self.token_max
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Token range is too small",
+            ));
+        }
+
+        if self.read_rate_limit.is_some_and(|(_, burst)| burst == 0) {
+            // Reading could never start with an empty bucket.
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Read rate limit burst size must be positive",
+            ));
+        }
+
+        let mut listeners: Vec<Listener> = Vec::with_capacity(listener_count);
+        for &addr in &self.listen_addrs {
+            let mut addr = addr;
+            if let (true, 0, Some(first)) = (self.share_port, addr.port(), listeners.first()) {
+                addr.set_port(first.addr().map_or(0, |a| a.port()));
+            }
+            let listener = socket::bind_listener(addr, self.listener_options)?;
+            let addr = listener.local_addr()?;
+            listeners.push(Listener::Tcp { addr, listener });
+        }
+        #[cfg(unix)]
+        for path in &self.unix_listen_paths {
+            let listener = UnixListener::bind(path)?;
+            let path = path.clone();
+            listeners.push(Listener::Unix { path, listener });
+        }
+        for (i, listener) in listeners.iter_mut().enumerate() {
+            listener.register(poller, Token(self.token_min.0 + i))?;
+        }
+
+        Ok(RpcServer {
+            listen_addr: listeners.iter().find_map(Listener::addr),
+            listeners,
+            listener_count,
+            listener_options: self.listener_options,
+            accepting: true,
+            socket_options: self.socket_options,
+            token_min: self.token_min,
+            max_connections: self.max_connections,
+            max_connections_per_ip: self.max_connections_per_ip,
+            connections_per_ip: HashMap::new(),
+            accept_filter: None,
+            connections: TokenSlab::new(
+                Token(self.token_min.0.wrapping_add(listener_count)),
+                self.token_max.0 - self.token_min.0 - (listener_count - 1),
+            ),
+            next_generation: 0,
+            idle_timeout: self.idle_timeout,
+            write_stall_timeout: self.write_stall_timeout,
+            max_request_size: self.max_request_size,
+            max_response_size: self.max_response_size,
+            max_write_buf_size: self.max_write_buf_size,
+            write_watermarks: self.write_watermarks,
+            max_retained_buf_size: self.max_retained_buf_size,
+            buf_shrink_delay: self.buf_shrink_delay,
+            initial_buf_capacities: self.initial_buf_capacities,
+            max_in_flight_requests: self.max_in_flight_requests,
+            max_accepts_per_event: self.max_accepts_per_event.max(1),
+            read_rate_limit: self.read_rate_limit,
+            methods: self.methods.clone(),
+            parse_error_policy: self.parse_error_policy,
+            next_idle_check: None,
+            next_stall_check: None,
+            next_read_resume: None,
+            next_drain_check: None,
+            accept_backoff: self.accept_backoff,
+            accept_resume_at: None,
+            proxy_protocol: self.proxy_protocol,
+            events_enabled: self.events,
+            events: VecDeque::new(),
+            stats: ServerStats::default(),
+            requests: VecDeque::new(),
+            responses: VecDeque::new(),
+            next_request_id: 0,
+            _request: PhantomData,
+        })
+    }
+}
+
+/// JSON-RPC message received by [`RpcServer`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Incoming<REQ> {
+    /// Request that expects a response.
+    Call(REQ),
+
+    /// Notification to which the server must not reply.
+    Notification(REQ),
+}
+
+impl<REQ> Incoming<REQ> {
+    fn new(request: REQ, is_call: bool) -> Self {
+        if is_call {
+            Self::Call(request)
+        } else {
+            Self::Notification(request)
+        }
+    }
+
+    /// Returns `true` if this message is a notification.
+    pub fn is_notification(&self) -> bool {
+        matches!(self, Self::Notification(_))
+    }
+
+    /// Returns a reference to the received request.
+    pub fn request(&self) -> &REQ {
+        match self {
+            Self::Call(request) | Self::Notification(request) => request,
+        }
+    }
+
+    /// Converts this message into the received request.
+    pub fn into_request(self) -> REQ {
+        match self {
+            Self::Call(request) | Self::Notification(request) => request,
+        }
+    }
+}
+
+/// Returns a METHOD_NOT_FOUND response if the method of the probed request is not included in `methods`.
+fn check_method(probe: &RequestProbe, methods: Option<&HashSet<String>>) -> Option<ResponseObject> {
+    let methods = methods?;
+    let method = probe.method().unwrap_or_default();
+    if methods.contains(method) {
+        return None;
+    }
+    Some(ResponseObject::Err {
+        jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+        error: ErrorObject {
+            code: ErrorCode::METHOD_NOT_FOUND,
+            message: format!("Method not found: {method}"),
+            data: None,
+        },
+        id: probe.id().cloned(),
+    })
+}
+
+/// Metadata attached to a request received by [`RpcServer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestContext {
+    /// Client that sent the request.
+    pub client: ClientId,
+
+    /// Address of the client (`None` for Unix domain socket clients).
+    pub peer_addr: Option<SocketAddr>,
+
+    /// Time at which the request was read from the connection.
+    pub received_at: Instant,
+
+    /// Sequence number of the request within the connection, starting from `0`.
+    ///
+    /// Each request in a batch request has its own sequence number.
+    pub sequence: u64,
+}
+
+/// Statistics of [`RpcServer`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ServerStats {
+    /// Number of currently active client connections.
+    pub active_connections: usize,
+
+    /// Total number of accepted client connections.
+    pub accepted_connections: u64,
+
+    /// Total number of closed client connections.
+    pub closed_connections: u64,
+
+    /// Total number of connections rejected at accept time.
+    pub rejected_connections: u64,
+
+    /// Total number of received requests and notifications.
+    pub received_requests: u64,
+
+    /// Total number of responses sent via [`RpcServer::reply()`].
+    pub sent_responses: u64,
+
+    /// Total number of bytes received from clients.
+    pub received_bytes: u64,
+
+    /// Total number of bytes sent to clients.
+    pub sent_bytes: u64,
+}
+
+impl ServerStats {
+    fn record_closed(&mut self, connection: &Connection) {
+        self.closed_connections += 1;
+        self.received_bytes += connection.received_bytes();
+        self.sent_bytes += connection.sent_bytes();
+    }
+}
+
+/// Event reported by [`RpcServer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// A client connection entered [`ConnectionState::ShuttingDown`]
+    /// (i.e., it will be closed once the queued data has been flushed).
+    ShuttingDown {
+        /// Client whose connection is shutting down.
+        client: ClientId,
+    },
+
+    /// A client connection has been closed.
+    Disconnected {
+        /// Client whose connection has been closed.
+        client: ClientId,
+
+        /// Reason for the disconnection.
+        reason: DisconnectReason,
+    },
+
+    /// An incoming connection was accepted and then immediately closed.
+    AcceptRejected {
+        /// Address of the rejected peer (`None` for Unix domain socket clients).
+        peer_addr: Option<SocketAddr>,
+
+        /// Reason for the rejection.
+        reason: AcceptRejectReason,
+    },
+
+    /// The server temporarily stopped accepting connections because it ran out of
+    /// file descriptors or other resources (e.g., `EMFILE`).
+    AcceptSuspended {
+        /// Duration after which the server retries accepting.
+        retry_after: Duration,
+    },
+
+    /// The server resumed accepting connections after [`ServerEvent::AcceptSuspended`].
+    AcceptResumed,
+
+    /// All data queued on a client connection has been written to the socket.
+    ///
+    /// This event is recorded when the write buffer becomes empty after data had to be queued
+    /// (e.g., after a reply returned [`ReplyStatus::Queued`]).
+    Flushed {
+        /// Client whose write buffer has been flushed.
+        client: ClientId,
+    },
+
+    /// The data queued on a client connection reached the high watermark set by [`RpcServerBuilder::write_watermarks()`].
+    WriteBufferHigh {
+        /// Client whose write buffer is high.
+        client: ClientId,
+    },
+
+    /// The data queued on a client connection fell to the low watermark after [`ServerEvent::WriteBufferHigh`].
+    WriteBufferLow {
+        /// Client whose write buffer is low.
+        client: ClientId,
+    },
+}
+
+/// Reason for [`ServerEvent::AcceptRejected`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AcceptRejectReason {
+    /// The accept filter set via [`RpcServer::set_accept_filter()`] rejected the peer.
+    Filtered,
+
+    /// The number of connections reached the maximum.
+    ///
+    /// The peer receives a [`SERVER_OVERLOADED`](crate::error_codes::SERVER_OVERLOADED) error response.
+    MaxConnections,
+
+    /// The number of connections from the peer's IP address reached the maximum.
+    ///
+    /// The peer receives a [`SERVER_OVERLOADED`](crate::error_codes::SERVER_OVERLOADED) error response.
+    MaxConnectionsPerIp,
+
+    /// No token was available in the token range of the server.
+    ///
+    /// The peer receives a [`SERVER_OVERLOADED`](crate::error_codes::SERVER_OVERLOADED) error response.
+    NoTokens,
+}
+
+/// Delivery status of a response returned by [`RpcServer::reply()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReplyStatus {
+    /// The response has been completely written to the socket.
+    Sent,
+
+    /// The response has been queued in the write buffer and will be written when the socket becomes writable.
+    ///
+    /// If events are enabled, [`ServerEvent::Flushed`] is recorded once the write buffer has been flushed.
+    Queued,
+
+    /// The response is held until all responses of the batch request it belongs to are available.
+    Held,
+
+    /// The response has been dropped because the client is not connected.
+    Dropped,
+}
+
+impl ReplyStatus {
+    /// Returns `true` if the response has been dropped.
+    pub fn is_dropped(self) -> bool {
+        self == Self::Dropped
+    }
+}
+
+/// Policy on how to handle client messages that cannot be parsed as requests.
+///
+/// In either case, an error response is sent to the client first.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParseErrorPolicy {
+    /// Keeps the connection open and continues reading subsequent messages.
+    #[default]
+    Continue,
+
+    /// Closes the connection.
+    Close,
+}
+
+/// Reason why a client connection has been closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    /// The peer closed the connection.
+    ///
+    /// If the peer only closed its writing side, the connection is closed after
+    /// the in-flight requests have been replied to and the responses have been flushed.
+    PeerClosed,
+
+    /// An I/O error occurred on the connection.
+    Error,
+
+    /// The connection had no traffic for longer than the idle timeout.
+    IdleTimeout,
+
+    /// [`RpcServer::disconnect()`] was called.
+    Requested,
+
+    /// The client sent a message that could not be parsed as a request,
+    /// and the [`ParseErrorPolicy::Close`] policy was in effect.
+    InvalidMessage,
+
+    /// The client sent a message larger than the maximum request size.
+    MessageTooLarge,
+
+    /// The amount of data queued for sending exceeded the write buffer limit
+    /// (i.e., the client is not reading responses).
+    WriteBufferFull,
+
+    /// [`RpcServerBuilder::proxy_protocol()`] was enabled, but the client did not send
+    /// a valid PROXY protocol header.
+    InvalidProxyHeader,
+
+    /// The connection was being closed gracefully, but the queued data could not be flushed
+    /// before the deadline.
+    DrainTimeout,
+
+    /// The connection was detached from the server via [`RpcServer::take_connection()`].
+    Detached,
+
+    /// Queued data could not be written for longer than the write stall timeout
+    /// (i.e., the client stopped reading).
+    WriteStalled,
+
+    /// [`RpcServer::shutdown()`] was called.
+    Shutdown,
+}
+
+#[derive(Debug)]
+enum Listener {
+    Tcp {
+        addr: SocketAddr,
+        listener: TcpListener,
+    },
+    #[cfg(unix)]
+    Unix {
+        path: PathBuf,
+        listener: UnixListener,
+    },
+}
+
+impl Listener {
+    fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp { addr, .. } => Some(*addr),
+            #[cfg(unix)]
+            Self::Unix { .. } => None,
+        }
+    }
+
+    fn accept(&self) -> std::io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Self::Tcp { listener, .. } => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Stream::Tcp(stream), Some(addr)))
+            }
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => {
+                let (stream, _) = listener.accept()?;
+                Ok((Stream::Unix(stream), None))
+            }
+        }
+    }
+
+    fn register(&mut self, poller: &mut Poll, token: Token) -> std::io::Result<()> {
+        let registry = poller.registry();
+        match self {
+            Self::Tcp { listener, .. } => registry.register(listener, token, Interest::READABLE),
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => registry.register(listener, token, Interest::READABLE),
+        }
+    }
+
+    fn reregister(&mut self, poller: &mut Poll, token: Token) -> std::io::Result<()> {
+        let registry = poller.registry();
+        match self {
+            Self::Tcp { listener, .. } => registry.reregister(listener, token, Interest::READABLE),
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => registry.reregister(listener, token, Interest::READABLE),
+        }
+    }
+
+    fn deregister(&mut self, poller: &mut Poll) -> std::io::Result<()> {
+        let registry = poller.registry();
+        match self {
+            Self::Tcp { listener, .. } => registry.deregister(listener),
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => registry.deregister(listener),
+        }
+    }
+}
+
+struct AcceptFilter(Box<dyn Send + FnMut(SocketAddr) -> bool>);
+
+impl std::fmt::Debug for AcceptFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("AcceptFilter").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+struct ClientConnection {
+    id: ClientId,
+    last_activity: Instant,
+    pending_calls: HashSet<RequestId>,
+    connection: Connection,
+    peer_addr: Option<SocketAddr>,
+    batches: Vec<Batch>,
+    next_sequence: u64,
+    in_flight: usize,
+    read_paused: bool,
+    read_limiter: Option<ReadRateLimiter>,
+    write_buf_high: bool,
+    peer_closed: bool,
+    drain_deadline: Option<Instant>,
+    awaiting_proxy_header: bool,
+    accept_ip: Option<IpAddr>,
+}
+
+impl ClientConnection {
+    fn read_resume_at(&self) -> Option<Instant> {
+        self.read_limiter.as_ref()?.resume_at
+    }
+
+    /// Returns `Ok(false)` if the response is held until the other responses of its batch are available.
+    fn reply_batch_member<T: Serialize>(
+        &mut self,
+        poller: &mut Poll,
+        response: &T,
+    ) -> serde_json::Result<bool> {
+        let response = serde_json::to_value(response)?;
+        let id = response
+            .get("id")
+            .and_then(|id| serde_json::from_value::<RequestId>(id.clone()).ok());
+        let Some((i, j)) = id.as_ref().and_then(|id| {
+            self.batches.iter().enumerate().find_map(|(i, b)| {
+                let j = b.pending_ids.iter().position(|x| x == id)?;
+                Some((i, j))
+            })
+        }) else {
+            return self.connection.send(poller, &response).map(|()| true);
+        };
+
+        // Only one entry is removed, as a batch may contain requests with duplicate IDs.
+        let batch = &mut self.batches[i];
+        batch.pending_ids.swap_remove(j);
+        batch.responses.push(response);
+        if !batch.pending_ids.is_empty() {
+            return Ok(false);
+        }
+
+        let batch = self.batches.swap_remove(i);
+        self.connection
+            .send(poller, &batch.responses)
+            .map(|()| true)
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct OkResponse<'a, T> {
+    pub jsonrpc: jsonlrpc::JsonRpcVersion,
+    pub id: &'a RequestId,
+    pub result: &'a T,
+}
+
+/// Token bucket that limits the number of bytes read from a connection.
+#[derive(Debug)]
+struct ReadRateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated_at: Instant,
+    counted_bytes: u64,
+    resume_at: Option<Instant>,
+}
+
+impl ReadRateLimiter {
+    fn new(rate: u64, burst: u64, now: Instant) -> Self {
+        Self {
+            rate: rate.max(1) as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated_at: now,
+            counted_bytes: 0,
+            resume_at: None,
+        }
+    }
+
+    /// Consumes the bytes received since the last call and returns the time to resume reading if the bucket is exhausted.
+    fn throttle(&mut self, received_bytes: u64, now: Instant) -> Option<Instant> {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.tokens -= (received_bytes - self.counted_bytes) as f64;
+        self.updated_at = now;
+        self.counted_bytes = received_bytes;
+        if self.tokens > 0.0 {
+            return None;
+        }
+        let resume_at = now + Duration::from_secs_f64(-self.tokens / self.rate);
+        self.resume_at = Some(resume_at);
+        Some(resume_at)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Batch {
+    pending_ids: Vec<RequestId>,
+    responses: Vec<serde_json::Value>,
+}
+
+fn reject(mut stream: Stream, code: ErrorCode, message: &str) {
+    let response = ResponseObject::Err {
+        jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+        error: ErrorObject {
+            code,
+            message: message.to_owned(),
+            data: None,
+        },
+        id: None,
+    };
+    let mut bytes = serde_json::to_vec(&response).expect("unreachable");
+    bytes.push(b'\n');
+    let _ = stream.write(&bytes);
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+fn invalid_request_response(value: &serde_json::Value, e: &serde_json::Error) -> ResponseObject {
+    if let Ok(request) = serde_json::from_value::<RequestObject>(value.clone()) {
+        error_response(ErrorCode::INVALID_PARAMS, e, request.id)
+    } else {
+        error_response(ErrorCode::INVALID_REQUEST, e, None)
+    }
+}
+
+fn error_response(code: ErrorCode, e: &serde_json::Error, id: Option<RequestId>) -> ResponseObject {
+    ResponseObject::Err {
+        jsonrpc: jsonlrpc::JsonRpcVersion::V2,
+        error: ErrorObject {
+            code,
+            message: e.to_string(),
+            data: None,
+        },
+        id,
+    }
+}
+
+/// Returns an error if `result` failed because the message exceeds the outgoing size limit
+/// (in which case nothing has been queued and the connection is kept open).
+fn check_message_size<T>(result: &serde_json::Result<T>) -> std::io::Result<()> {
+    match result {
+        Err(e) if is_message_too_large_error(e) => Err(message_too_large_error().into()),
+        _ => Ok(()),
+    }
+}
+
+/// Identifier of a client.
+///
+/// In addition to the `mio` token of the connection, this identifier includes a generation number
+/// that is unique for each accepted connection.
+/// Therefore, even if the token is reused by a newer connection after the client disconnects,
+/// [`RpcServer`] never delivers messages addressed to a stale identifier to the new connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(from = "(usize, u64)", into = "(usize, u64)")]
+pub struct ClientId {
+    token: Token,
+    generation: u64,
+}
+
+impl ClientId {
+    /// Returns the `mio` token of the connection associated with this client.
+    pub fn token(self) -> Token {
+        self.token
+    }
+
+    /// Returns the generation number of this client.
+    pub fn generation(self) -> u64 {
+        self.generation
+    }
 }
 
-impl From<usize> for ClientId {
-    fn from(value: usize) -> Self {
+impl From<(usize, u64)> for ClientId {
+    fn from((token, generation): (usize, u64)) -> Self {
         Self {
-            token: Token(value),
+            token: Token(token),
+            generation,
         }
     }
 }
 
-impl From<ClientId> for usize {
+impl From<ClientId> for (usize, u64) {
     fn from(value: ClientId) -> Self {
-        value.token.0
+        (value.token.0, value.generation)
     }
 }
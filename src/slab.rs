@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+
+use mio::Token;
+
+/// Token-indexed storage that provides O(1) token allocation and lookup.
+///
+/// Freed tokens are reused in FIFO order to delay the reuse of recently freed tokens as long as possible.
+#[derive(Debug)]
+pub(crate) struct TokenSlab<T> {
+    first_token: usize,
+    capacity: usize,
+    entries: Vec<Option<T>>,
+    free: VecDeque<usize>,
+    len: usize,
+}
+
+impl<T> TokenSlab<T> {
+    pub fn new(first_token: Token, capacity: usize) -> Self {
+        Self {
+            first_token: first_token.0,
+            capacity,
+            entries: Vec::new(),
+            free: VecDeque::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn next_token(&self) -> Option<Token> {
+        let i = if let Some(&i) = self.free.front() {
+            i
+        } else if self.entries.len() < self.capacity {
+            self.entries.len()
+        } else {
+            return None;
+        };
+        Some(Token(self.first_token + i))
+    }
+
+    /// Inserts `value` at the token returned by [`TokenSlab::next_token()`].
+    pub fn insert(&mut self, value: T) -> Option<Token> {
+        let token = self.next_token()?;
+        if self.free.pop_front().is_none() {
+            self.entries.push(None);
+        }
+        self.entries[token.0 - self.first_token] = Some(value);
+        self.len += 1;
+        Some(token)
+    }
+
+    pub fn get(&self, token: Token) -> Option<&T> {
+        let i = token.0.checked_sub(self.first_token)?;
+        self.entries.get(i)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, token: Token) -> Option<&mut T> {
+        let i = token.0.checked_sub(self.first_token)?;
+        self.entries.get_mut(i)?.as_mut()
+    }
+
+    pub fn remove(&mut self, token: Token) -> Option<T> {
+        let i = token.0.checked_sub(self.first_token)?;
+        let value = self.entries.get_mut(i)?.take()?;
+        if i < self.capacity {
+            self.free.push_back(i);
+        } else {
+            self.truncate_vacant_tail();
+        }
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// Changes the maximum number of entries.
+    ///
+    /// Existing entries beyond the new capacity are kept until they are removed.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.truncate_vacant_tail();
+
+        let end = self.entries.len().min(capacity);
+        let mut is_free = vec![false; end];
+        self.free.retain(|&i| i < end);
+        for &i in &self.free {
+            is_free[i] = true;
+        }
+        for (i, entry) in self.entries[..end].iter().enumerate() {
+            if entry.is_none() && !is_free[i] {
+                self.free.push_back(i);
+            }
+        }
+    }
+
+    fn truncate_vacant_tail(&mut self) {
+        while self.entries.len() > self.capacity && matches!(self.entries.last(), Some(None)) {
+            self.entries.pop();
+        }
+    }
+
+    pub fn values(&self) -> impl '_ + Iterator<Item = &T> {
+        self.entries.iter().filter_map(|e| e.as_ref())
+    }
+}
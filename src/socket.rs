@@ -0,0 +1,299 @@
+use std::{net::SocketAddr, time::Duration};
+
+use mio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ListenerOptions {
+    pub backlog: i32,
+    pub reuse_port: bool,
+    pub only_v6: Option<bool>,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        Self {
+            backlog: 1024,
+            reuse_port: false,
+            only_v6: None,
+        }
+    }
+}
+
+/// Options applied to accepted and dialed TCP sockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SocketOptions {
+    pub nodelay: bool,
+    pub keepalive: Option<TcpKeepalive>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TcpKeepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub count: u32,
+}
+
+#[cfg(unix)]
+pub(crate) fn apply_socket_options(
+    stream: &TcpStream,
+    options: &SocketOptions,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    stream.set_nodelay(options.nodelay)?;
+    let fd = stream.as_raw_fd();
+    if let Some(keepalive) = options.keepalive {
+        set_socket_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        set_keepalive_params(fd, keepalive)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        let size = libc::c_int::try_from(size).unwrap_or(libc::c_int::MAX);
+        set_socket_option(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        let size = libc::c_int::try_from(size).unwrap_or(libc::c_int::MAX);
+        set_socket_option(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, size)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn apply_socket_options(
+    stream: &TcpStream,
+    options: &SocketOptions,
+) -> std::io::Result<()> {
+    stream.set_nodelay(options.nodelay)?;
+    if options.keepalive.is_some()
+        || options.send_buffer_size.is_some()
+        || options.recv_buffer_size.is_some()
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "TCP keepalive and buffer size options are not supported on this platform",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_vendor = "apple"))]
+fn set_keepalive_params(fd: libc::c_int, keepalive: TcpKeepalive) -> std::io::Result<()> {
+    #[cfg(target_vendor = "apple")]
+    const TCP_KEEPIDLE: libc::c_int = libc::TCP_KEEPALIVE;
+    #[cfg(not(target_vendor = "apple"))]
+    const TCP_KEEPIDLE: libc::c_int = libc::TCP_KEEPIDLE;
+
+    let secs = |d: Duration| libc::c_int::try_from(d.as_secs().max(1)).unwrap_or(libc::c_int::MAX);
+    let count = libc::c_int::try_from(keepalive.count).unwrap_or(libc::c_int::MAX);
+    set_socket_option(fd, libc::IPPROTO_TCP, TCP_KEEPIDLE, secs(keepalive.idle))?;
+    set_socket_option(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPINTVL,
+        secs(keepalive.interval),
+    )?;
+    set_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, count)
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "linux", target_os = "android", target_vendor = "apple"))
+))]
+fn set_keepalive_params(_fd: libc::c_int, _keepalive: TcpKeepalive) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "TCP keepalive parameters are not supported on this platform",
+    ))
+}
+
+#[cfg(unix)]
+pub(crate) fn bind_listener(
+    addr: SocketAddr,
+    options: ListenerOptions,
+) -> std::io::Result<TcpListener> {
+    use std::os::fd::AsRawFd;
+
+    let listener = std::net::TcpListener::from(new_socket(addr)?);
+    let fd = listener.as_raw_fd();
+
+    set_socket_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    if options.reuse_port {
+        set_socket_option(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+    }
+    if let (Some(only_v6), true) = (options.only_v6, addr.is_ipv6()) {
+        let value = libc::c_int::from(only_v6);
+        set_socket_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, value)?;
+    }
+
+    let (sockaddr, len) = to_sockaddr(addr);
+    // SAFETY: `sockaddr` is a valid socket address of `len` bytes.
+    check(unsafe { libc::bind(fd, &sockaddr as *const _ as *const libc::sockaddr, len) })?;
+    // SAFETY: `fd` is a valid socket.
+    check(unsafe { libc::listen(fd, options.backlog) })?;
+
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener))
+}
+
+/// Starts connecting to `addr` in a non-blocking manner from a socket bound to `local_addr`.
+#[cfg(unix)]
+pub(crate) fn connect_from(local_addr: SocketAddr, addr: SocketAddr) -> std::io::Result<TcpStream> {
+    use std::os::fd::AsRawFd;
+
+    let stream = std::net::TcpStream::from(new_socket(addr)?);
+    let fd = stream.as_raw_fd();
+    stream.set_nonblocking(true)?;
+
+    let (sockaddr, len) = to_sockaddr(local_addr);
+    // SAFETY: `sockaddr` is a valid socket address of `len` bytes.
+    check(unsafe { libc::bind(fd, &sockaddr as *const _ as *const libc::sockaddr, len) })?;
+
+    let (sockaddr, len) = to_sockaddr(addr);
+    // SAFETY: `sockaddr` is a valid socket address of `len` bytes.
+    let result =
+        check(unsafe { libc::connect(fd, &sockaddr as *const _ as *const libc::sockaddr, len) });
+    match result {
+        Err(e) if e.raw_os_error() != Some(libc::EINPROGRESS) => return Err(e),
+        _ => {}
+    }
+    Ok(TcpStream::from_std(stream))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn connect_from(
+    _local_addr: SocketAddr,
+    _addr: SocketAddr,
+) -> std::io::Result<TcpStream> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Binding client sockets is not supported on this platform",
+    ))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn bind_listener(
+    addr: SocketAddr,
+    options: ListenerOptions,
+) -> std::io::Result<TcpListener> {
+    if options.reuse_port {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not supported on this platform",
+        ));
+    }
+    if options.only_v6.is_some() && addr.is_ipv6() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "IPV6_V6ONLY is not supported on this platform",
+        ));
+    }
+    TcpListener::bind(addr)
+}
+
+/// Returns `true` if `error` indicates that the process or system ran out of resources
+/// (e.g., file descriptors) needed to accept a connection.
+#[cfg(unix)]
+pub(crate) fn is_resource_exhausted(error: &std::io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    )
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_resource_exhausted(_error: &std::io::Error) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn new_socket(addr: SocketAddr) -> std::io::Result<std::os::fd::OwnedFd> {
+    use std::os::fd::FromRawFd;
+
+    let domain = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    // SAFETY: `socket()` has no memory safety preconditions.
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // SAFETY: `fd` is a newly created socket owned by no one else.
+    let owned = unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) };
+    // SAFETY: `fd` is a valid file descriptor.
+    check(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+    Ok(owned)
+}
+
+#[cfg(unix)]
+fn set_socket_option(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    // SAFETY: `value` is a valid `c_int` that outlives the call.
+    check(unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    })
+}
+
+#[cfg(unix)]
+fn check(result: libc::c_int) -> std::io::Result<()> {
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: All-zero bytes are a valid `sockaddr_storage`.
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            // SAFETY: `sockaddr_storage` is large enough and suitably aligned for `sockaddr_in`.
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr = libc::in_addr {
+                s_addr: u32::from_ne_bytes(addr.ip().octets()),
+            };
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            // SAFETY: `sockaddr_storage` is large enough and suitably aligned for `sockaddr_in6`.
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr = libc::in6_addr {
+                s6_addr: addr.ip().octets(),
+            };
+            sin6.sin6_scope_id = addr.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
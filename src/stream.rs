@@ -0,0 +1,154 @@
+use std::{
+    io::{ErrorKind, IoSlice, Read, Write},
+    net::Shutdown,
+};
+
+use mio::{event::Source, net::TcpStream, Interest, Registry, Token};
+
+#[cfg(unix)]
+use mio::net::UnixStream;
+
+/// Stream underlying a [`Connection`](crate::Connection).
+#[derive(Debug)]
+pub enum Stream {
+    /// TCP stream.
+    Tcp(TcpStream),
+
+    /// Unix domain socket stream.
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Returns the TCP stream if this is a TCP stream.
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Tcp(s) => Some(s),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+        }
+    }
+
+    /// Returns the Unix domain socket stream if this is a Unix domain socket stream.
+    #[cfg(unix)]
+    pub fn as_unix(&self) -> Option<&UnixStream> {
+        match self {
+            Self::Tcp(_) => None,
+            Self::Unix(s) => Some(s),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.shutdown(how),
+            #[cfg(unix)]
+            Self::Unix(s) => s.shutdown(how),
+        }
+    }
+
+    pub(crate) fn take_error(&self) -> std::io::Result<Option<std::io::Error>> {
+        match self {
+            Self::Tcp(s) => s.take_error(),
+            #[cfg(unix)]
+            Self::Unix(s) => s.take_error(),
+        }
+    }
+
+    pub(crate) fn is_connected(&self) -> std::io::Result<bool> {
+        let result = match self {
+            Self::Tcp(s) => s.peer_addr().map(|_| ()),
+            #[cfg(unix)]
+            Self::Unix(s) => s.peer_addr().map(|_| ()),
+        };
+        match result {
+            Err(e) if e.kind() == ErrorKind::NotConnected => Ok(false),
+            Err(e) => Err(e),
+            Ok(()) => Ok(true),
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Self {
+        Self::Unix(stream)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Self::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Self::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.write_vectored(bufs),
+            #[cfg(unix)]
+            Self::Unix(s) => s.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Self::Unix(s) => s.flush(),
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.register(registry, token, interests),
+            #[cfg(unix)]
+            Self::Unix(s) => s.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.reregister(registry, token, interests),
+            #[cfg(unix)]
+            Self::Unix(s) => s.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.deregister(registry),
+            #[cfg(unix)]
+            Self::Unix(s) => s.deregister(registry),
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use std::io::ErrorKind;
+
+use mio::Poll;
+
+use crate::connection::Connection;
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HEADER_LEN: usize = 16 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Client-side state of a WebSocket connection.
+#[derive(Debug)]
+pub(crate) struct WebSocketSession {
+    key: String,
+    established: bool,
+    backlog: Vec<u8>,
+    fragments: Vec<u8>,
+    rng_state: u64,
+}
+
+impl WebSocketSession {
+    pub(crate) fn new(seed: u64) -> Self {
+        let mut this = Self {
+            key: String::new(),
+            established: false,
+            backlog: Vec::new(),
+            fragments: Vec::new(),
+            rng_state: seed | 1,
+        };
+        let mut nonce = [0; 16];
+        nonce[..8].copy_from_slice(&this.next_random().to_be_bytes());
+        nonce[8..].copy_from_slice(&this.next_random().to_be_bytes());
+        this.key = base64_encode(&nonce);
+        this
+    }
+
+    /// Builds the opening handshake request.
+    pub(crate) fn handshake_request(&self, path: &str, host: &str) -> Vec<u8> {
+        format!(
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            self.key
+        )
+        .into_bytes()
+    }
+
+    /// Sends `payload` as a text frame, or holds it until the opening handshake completes.
+    pub(crate) fn send_text(
+        &mut self,
+        connection: &mut Connection,
+        poller: &mut Poll,
+        payload: &[u8],
+    ) -> serde_json::Result<()> {
+        let frame = self.encode_frame(OPCODE_TEXT, payload);
+        if self.established {
+            connection.send_bytes(poller, &frame)
+        } else {
+            self.backlog.extend_from_slice(&frame);
+            Ok(())
+        }
+    }
+
+    /// Reads the next data message.
+    ///
+    /// Returns `Ok(None)` if a frame that does not complete a data message (e.g., a ping) has been handled.
+    /// Messages larger than `max_message_size` are rejected before they are completely received.
+    pub(crate) fn read_message(
+        &mut self,
+        connection: &mut Connection,
+        poller: &mut Poll,
+        max_message_size: usize,
+    ) -> serde_json::Result<Option<Vec<u8>>> {
+        if !self.established {
+            let accept = accept_key(&self.key);
+            connection.read_prefix(|buf| parse_handshake_response(buf, &accept))?;
+            self.established = true;
+            if !self.backlog.is_empty() {
+                connection.send_bytes(poller, &std::mem::take(&mut self.backlog))?;
+            }
+            return Ok(None);
+        }
+
+        let Frame {
+            fin,
+            opcode,
+            payload,
+        } = connection.read_prefix(|buf| parse_frame(buf, max_message_size))?;
+        match opcode {
+            OPCODE_TEXT | OPCODE_BINARY if fin && self.fragments.is_empty() => Ok(Some(payload)),
+            OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                if payload.len() > max_message_size - self.fragments.len() {
+                    return Err(serde_json::Error::io(too_large_message_error()));
+                }
+                self.fragments.extend_from_slice(&payload);
+                Ok(fin.then(|| std::mem::take(&mut self.fragments)))
+            }
+            OPCODE_PING => {
+                let frame = self.encode_frame(OPCODE_PONG, &payload);
+                connection.send_bytes(poller, &frame)?;
+                Ok(None)
+            }
+            OPCODE_PONG => Ok(None),
+            OPCODE_CLOSE => {
+                // Echoes the status code (if any) as required by the closing handshake.
+                let frame = self.encode_frame(OPCODE_CLOSE, &payload[..payload.len().min(2)]);
+                let _ = connection.send_bytes(poller, &frame);
+                Err(serde_json::Error::io(std::io::Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "WebSocket connection closed by the server",
+                )))
+            }
+            _ => Err(serde_json::Error::io(invalid_data_error(
+                "Unknown WebSocket opcode",
+            ))),
+        }
+    }
+
+    fn encode_frame(&mut self, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            n if n < 126 => frame.push(0x80 | n as u8),
+            n if n <= usize::from(u16::MAX) => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        let mask = (self.next_random() as u32).to_be_bytes();
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        frame
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 7;
+        self.rng_state ^= self.rng_state << 17;
+        self.rng_state
+    }
+}
+
+fn parse_handshake_response(buf: &[u8], accept: &str) -> std::io::Result<Option<(usize, ())>> {
+    let Some(header_end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        if buf.len() > MAX_HEADER_LEN {
+            return Err(invalid_data_error("Too large WebSocket handshake response"));
+        }
+        return Ok(None);
+    };
+    let header = std::str::from_utf8(&buf[..header_end])
+        .map_err(|_| invalid_data_error("Non UTF-8 WebSocket handshake response"))?;
+
+    let mut lines = header.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    if status_line.split(' ').nth(1) != Some("101") {
+        return Err(invalid_data_error("WebSocket handshake rejected"));
+    }
+    let accepted = lines
+        .filter_map(|line| line.split_once(':'))
+        .any(|(name, value)| {
+            name.eq_ignore_ascii_case("sec-websocket-accept") && value.trim() == accept
+        });
+    if !accepted {
+        return Err(invalid_data_error("Invalid Sec-WebSocket-Accept header"));
+    }
+    Ok(Some((header_end + 4, ())))
+}
+
+#[derive(Debug)]
+pub(crate) struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+pub(crate) fn parse_frame(
+    buf: &[u8],
+    max_payload_size: usize,
+) -> std::io::Result<Option<(usize, Frame)>> {
+    let [b0, b1, ..] = *buf else {
+        return Ok(None);
+    };
+    let fin = b0 & 0x80 != 0;
+    let opcode = b0 & 0x0F;
+    let masked = b1 & 0x80 != 0;
+    let (len, mut offset) = match b1 & 0x7F {
+        126 => {
+            let Some(bytes) = buf.get(2..4) else {
+                return Ok(None);
+            };
+            (u64::from(u16::from_be_bytes([bytes[0], bytes[1]])), 4)
+        }
+        127 => {
+            let Some(bytes) = buf.get(2..10) else {
+                return Ok(None);
+            };
+            (
+                u64::from_be_bytes(bytes.try_into().expect("unreachable")),
+                10,
+            )
+        }
+        n => (u64::from(n), 2),
+    };
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= max_payload_size)
+        .ok_or_else(too_large_message_error)?;
+
+    let mask = if masked {
+        let Some(mask) = buf.get(offset..offset + 4) else {
+            return Ok(None);
+        };
+        offset += 4;
+        Some([mask[0], mask[1], mask[2], mask[3]])
+    } else {
+        None
+    };
+    let Some(payload) = offset.checked_add(len).and_then(|end| buf.get(offset..end)) else {
+        return Ok(None);
+    };
+    let payload = match mask {
+        Some(mask) => payload
+            .iter()
+            .zip(mask.iter().cycle())
+            .map(|(b, m)| b ^ m)
+            .collect(),
+        None => payload.to_vec(),
+    };
+    Ok(Some((
+        offset + len,
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+    )))
+}
+
+/// Returns the expected `Sec-WebSocket-Accept` value for `key`.
+pub(crate) fn accept_key(key: &str) -> String {
+    base64_encode(&sha1(format!("{key}{ACCEPT_GUID}").as_bytes()))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().expect("unreachable"));
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &w) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(w);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (chunk, h) in digest.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(TABLE[(n >> (18 - i * 6)) as usize & 0x3F]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn too_large_message_error() -> std::io::Error {
+    invalid_data_error("Too large WebSocket message")
+}
+
+fn invalid_data_error(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}